@@ -33,8 +33,85 @@ pub struct HandlerConfig {
     pub jwt_virtual_connection_key: Vec<u8>,
     #[serde(deserialize_with = "deserializer::string_to_number")]
     pub jwt_exp_in_hours: i64,
+    /// "http" to get nTor certificates from the authentication server, or the path of a static
+    /// certificates file or directory, to run without one. Default to "http"
+    #[serde(default = "default_certificate_provider")]
+    pub certificate_provider: String,
     pub auth_access_token: String,
-    pub auth_get_certificate_url: String,
+    /// Certificate endpoints of the authentication servers, comma separated, in order of preference
+    #[serde(rename = "auth_get_certificate_url", deserialize_with = "deserializer::string_to_vec")]
+    pub auth_get_certificate_urls: Vec<String>,
+    /// Default to 2000 ms
+    #[serde(default = "default_auth_connect_timeout_ms", deserialize_with = "deserializer::string_to_number")]
+    pub auth_connect_timeout_ms: u64,
+    /// Bounds each attempt, from sending the request to reading the response. Default to 5000 ms
+    #[serde(default = "default_auth_read_timeout_ms", deserialize_with = "deserializer::string_to_number")]
+    pub auth_read_timeout_ms: u64,
+    /// Retries of a failed request (connection error, timeout or 5xx), each on the next server.
+    /// Default to 2
+    #[serde(default = "default_auth_max_retries", deserialize_with = "deserializer::string_to_number")]
+    pub auth_max_retries: u32,
+    /// Base of the jittered exponential backoff between retries. Default to 100 ms
+    #[serde(default = "default_auth_retry_backoff_ms", deserialize_with = "deserializer::string_to_number")]
+    pub auth_retry_backoff_ms: u64,
+    /// Consecutive failures after which an authentication server is skipped. Default to 5
+    #[serde(default = "default_auth_circuit_failure_threshold", deserialize_with = "deserializer::string_to_number")]
+    pub auth_circuit_failure_threshold: u32,
+    /// How long a failing authentication server is skipped before it is probed again. Default to
+    /// 30 seconds
+    #[serde(default = "default_auth_circuit_open_secs", deserialize_with = "deserializer::string_to_number")]
+    pub auth_circuit_open_secs: u64,
+    /// How long an nTor certificate from the authentication server is served from cache. Default to
+    /// 300 seconds
+    #[serde(default = "default_auth_cert_cache_ttl_secs", deserialize_with = "deserializer::string_to_number")]
+    pub auth_cert_cache_ttl_secs: u64,
+    /// How long a 4xx from the authentication server is served from cache. Default to 30 seconds
+    #[serde(default = "default_auth_cert_cache_negative_ttl_secs", deserialize_with = "deserializer::string_to_number")]
+    pub auth_cert_cache_negative_ttl_secs: u64,
+    /// How long an expired certificate is still served while it is refreshed in the background.
+    /// Default to 60 seconds
+    #[serde(default = "default_auth_cert_cache_stale_secs", deserialize_with = "deserializer::string_to_number")]
+    pub auth_cert_cache_stale_secs: u64,
+    /// Maximum number of backends whose certificate is cached; the least recently used is evicted
+    /// beyond it
+    #[serde(default = "default_auth_cert_cache_max_entries", deserialize_with = "deserializer::string_to_number")]
+    pub auth_cert_cache_max_entries: usize,
+    /// Only accept https `backend_url`s. Default to true
+    #[serde(default = "default_true", deserialize_with = "deserializer::string_to_bool")]
+    pub backend_require_https: bool,
+    /// Hosts allowed as `backend_url`, comma separated, e.g. "api.example.com,*.example.org".
+    /// Empty allows any host not denied. Default to empty
+    #[serde(default, deserialize_with = "deserializer::string_to_vec")]
+    pub backend_allowed_domains: Vec<String>,
+    /// Hosts refused as `backend_url`, with the same patterns as `backend_allowed_domains`.
+    /// Default to empty
+    #[serde(default, deserialize_with = "deserializer::string_to_vec")]
+    pub backend_denied_domains: Vec<String>,
+    /// Ports allowed in `backend_url`, comma separated. Empty allows any port. Default to empty
+    #[serde(default, deserialize_with = "deserializer::string_to_vec_number")]
+    pub backend_allowed_ports: Vec<u16>,
+    /// Refuse backends resolving to loopback, private, link-local or other non-public addresses.
+    /// Default to true
+    #[serde(default = "default_true", deserialize_with = "deserializer::string_to_bool")]
+    pub backend_block_private_ips: bool,
+    /// Maximum number of DNS answers cached for upstream hosts. Default to 1024
+    #[serde(default = "default_dns_cache_size", deserialize_with = "deserializer::string_to_number")]
+    pub dns_cache_size: usize,
+    /// Caps how long a DNS answer is cached; records with a shorter TTL expire sooner. Default to
+    /// 300 seconds
+    #[serde(default = "default_dns_max_ttl_secs", deserialize_with = "deserializer::string_to_number")]
+    pub dns_max_ttl_secs: u64,
+    /// "memory", "sealed" (the session is encrypted into `int_fp_jwt`), or the path of the file
+    /// persisting `int_fp_jwt` sessions across restarts. Default to "memory"
+    #[serde(default = "default_session_store")]
+    pub session_store: String,
+    /// Default to 100000
+    #[serde(default = "default_session_store_max_entries", deserialize_with = "deserializer::string_to_number")]
+    pub session_store_max_entries: usize,
+    /// 32 bytes key sealing sessions, shared by all FP instances. Required when `session_store` is
+    /// "sealed"
+    #[serde(default, deserialize_with = "string_to_optional_u8_32")]
+    pub session_seal_key: Option<[u8; 32]>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -48,6 +125,18 @@ pub struct ProxyConfig {
     pub cors_allow_credentials: bool,
     #[serde(deserialize_with = "deserializer::string_to_vec")]
     pub cors_allow_origins: Vec<String>,
+    /// Maximum request body size in bytes, for paths without a limit of their own. Default to
+    /// 10 MiB
+    #[serde(default = "default_max_request_body_size", deserialize_with = "deserializer::string_to_number")]
+    pub max_request_body_size: usize,
+    /// Maximum request body size in bytes for `/init-tunnel`, which only carries a public key.
+    /// Default to 4 KiB
+    #[serde(default = "default_init_tunnel_max_body_size", deserialize_with = "deserializer::string_to_number")]
+    pub init_tunnel_max_body_size: usize,
+    /// Adds a `Server-Timing` header with the duration of each request phase to responses.
+    /// Default to false, as it discloses the time spent in each phase
+    #[serde(default, deserialize_with = "deserializer::string_to_bool")]
+    pub server_timing_enabled: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,4 +145,83 @@ pub struct InfluxDBConfig {
     pub influxdb_org: String,
     pub influxdb_bucket: String,
     pub influxdb_auth_token: String,
-}
\ No newline at end of file
+}
+
+fn string_to_optional_u8_32<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserializer::string_to_u8_32(deserializer).map(Some)
+}
+
+fn default_certificate_provider() -> String {
+    "http".to_string()
+}
+
+fn default_auth_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_auth_read_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_auth_max_retries() -> u32 {
+    2
+}
+
+fn default_auth_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_auth_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_auth_circuit_open_secs() -> u64 {
+    30
+}
+
+fn default_auth_cert_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_auth_cert_cache_negative_ttl_secs() -> u64 {
+    30
+}
+
+fn default_auth_cert_cache_stale_secs() -> u64 {
+    60
+}
+
+fn default_auth_cert_cache_max_entries() -> usize {
+    10000
+}
+
+fn default_max_request_body_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_init_tunnel_max_body_size() -> usize {
+    4096
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_dns_cache_size() -> usize {
+    1024
+}
+
+fn default_dns_max_ttl_secs() -> u64 {
+    300
+}
+
+fn default_session_store() -> String {
+    "memory".to_string()
+}
+
+fn default_session_store_max_entries() -> usize {
+    100000
+}
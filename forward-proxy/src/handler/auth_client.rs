@@ -0,0 +1,242 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use pingora_router::error::Layer8Error;
+use rand::Rng;
+use reqwest::{Client, Response};
+use tracing::{error, warn};
+use url::Url;
+
+use crate::config::HandlerConfig;
+use crate::handler::consts::LogTypes;
+
+/// `AuthClient` calls the Layer8 authentication servers.
+///
+/// - One pooled `reqwest::Client` is shared by all requests, so connections are reused.
+/// - Every attempt is bounded by the connect and read timeouts.
+/// - A failed attempt (connection error, timeout or 5xx) is retried on the next server, after a
+///   jittered exponential backoff, up to `max_retries` times. Only GETs are sent, so retrying is
+///   always safe.
+/// - Each server has a circuit breaker. After `failure_threshold` consecutive failures the server
+///   is skipped for `open_for`, then a single probe decides whether it is used again. When every
+///   circuit is open, requests fail fast with `Layer8Error::AuthServerCircuitOpen`.
+///
+/// Servers are tried in the configured order, so the first one is preferred while it is healthy.
+/// Cloning is cheap; clones share connections and circuit breakers.
+#[derive(Clone)]
+pub struct AuthClient {
+    client: Client,
+    servers: Arc<[AuthServer]>,
+    access_token: String,
+    read_timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+struct AuthServer {
+    /// Certificate endpoint, the backend URL is added to its query as `backend_url`
+    url: Url,
+    breaker: CircuitBreaker,
+}
+
+impl AuthClient {
+    pub fn new(config: &HandlerConfig) -> io::Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_millis(config.auth_connect_timeout_ms))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .map_err(io::Error::other)?;
+
+        let servers = config.auth_get_certificate_urls
+            .iter()
+            .map(|url| {
+                Ok(AuthServer {
+                    url: certificate_endpoint(url)?,
+                    breaker: CircuitBreaker::new(
+                        config.auth_circuit_failure_threshold,
+                        Duration::from_secs(config.auth_circuit_open_secs),
+                    ),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(AuthClient {
+            client,
+            servers,
+            access_token: config.auth_access_token.clone(),
+            read_timeout: Duration::from_millis(config.auth_read_timeout_ms),
+            max_retries: config.auth_max_retries,
+            retry_backoff: Duration::from_millis(config.auth_retry_backoff_ms),
+        })
+    }
+
+    /// Requests the nTor certificate of `backend_url`.
+    ///
+    /// The response is returned as soon as a server answers with anything but a 5xx; a 4xx is
+    /// the server's answer about the backend, so it is neither retried nor counted as a failure.
+    pub async fn get_certificate(
+        &self,
+        backend_url: &str,
+        correlation_id: &str,
+    ) -> Result<Response, Layer8Error> {
+        let mut last_error = None;
+        // index of the first server to try, moved past a server once it failed
+        let mut next = 0;
+
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(self.backoff(attempt)).await;
+            }
+
+            let Some(index) = (0..self.servers.len())
+                .map(|offset| (next + offset) % self.servers.len())
+                .find(|&index| self.servers[index].breaker.try_acquire())
+            else {
+                break;
+            };
+            let server = &self.servers[index];
+            next = index + 1;
+
+            let mut request_url = server.url.clone();
+            request_url.query_pairs_mut().append_pair("backend_url", backend_url);
+            let result = self.client.get(request_url.as_str())
+                .header("Authorization", &self.access_token)
+                .timeout(self.read_timeout)
+                .send()
+                .await;
+
+            let err = match result {
+                Ok(res) if !res.status().is_server_error() => {
+                    server.breaker.on_success();
+                    return Ok(res);
+                }
+                // connected but layer8 itself failed
+                Ok(res) => Layer8Error::AuthServerUnavailable(format!(
+                    "Failed to get public key from layer8, status code: {}",
+                    res.status().as_u16()
+                )),
+                // unable to connect, or timed out
+                Err(e) => Layer8Error::AuthServerUnavailable(format!("Failed to connect to layer8: {}", e)),
+            };
+
+            warn!(
+                %correlation_id,
+                log_type=LogTypes::AUTHENTICATION_SERVER,
+                "Attempt {} to get ntor certificate for {request_url} failed: {err}",
+                attempt + 1
+            );
+            if server.breaker.on_failure() {
+                error!(
+                    %correlation_id,
+                    log_type=LogTypes::AUTHENTICATION_SERVER,
+                    "Circuit opened for authentication server {}",
+                    server.url
+                );
+            }
+            last_error = Some(err);
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            warn!(
+                %correlation_id,
+                log_type=LogTypes::AUTHENTICATION_SERVER,
+                "All authentication server circuits are open"
+            );
+            Layer8Error::AuthServerCircuitOpen("Layer8 is unavailable, retry later".to_string())
+        }))
+    }
+
+    /// Full jitter: a random delay up to `retry_backoff * 2^(attempt - 1)`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self.retry_backoff.saturating_mul(1 << (attempt - 1).min(16));
+        max.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Parses a configured certificate endpoint. The `backend_url` parameter it may end with, as in
+/// `.../client-cert?backend_url=`, is dropped; it is added with the backend URL of each request.
+fn certificate_endpoint(url: &str) -> io::Result<Url> {
+    let mut endpoint = Url::parse(url).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid authentication server URL \"{}\": {}", url, e),
+        )
+    })?;
+
+    let pairs: Vec<(String, String)> = endpoint
+        .query_pairs()
+        .filter(|(name, _)| name != "backend_url")
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    endpoint.set_query(None);
+    if !pairs.is_empty() {
+        endpoint.query_pairs_mut().extend_pairs(pairs);
+    }
+
+    Ok(endpoint)
+}
+
+/// Consecutive failures of a server, and whether it is skipped.
+struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    state: Mutex<CircuitState>,
+}
+
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A single probe is in flight. If it never completes (its request was dropped), another one
+    /// is let through after `open_for`.
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether the server may be called now. Lets a single probe through once the circuit has
+    /// been open for `open_for`.
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let probe_due = match *state {
+            CircuitState::Closed { .. } => return true,
+            CircuitState::Open { until } => now >= until,
+            CircuitState::HalfOpen { since } => now >= since + self.open_for,
+        };
+
+        if probe_due {
+            *state = CircuitState::HalfOpen { since: now };
+        }
+        probe_due
+    }
+
+    fn on_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed { failures: 0 };
+    }
+
+    /// Returns true if this failure opened the circuit.
+    fn on_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            CircuitState::Closed { failures } => failures + 1,
+            CircuitState::HalfOpen { .. } => self.failure_threshold,
+            // a request started before the circuit opened
+            CircuitState::Open { .. } => return false,
+        };
+
+        *state = if failures >= self.failure_threshold {
+            CircuitState::Open { until: Instant::now() + self.open_for }
+        } else {
+            CircuitState::Closed { failures }
+        };
+        failures >= self.failure_threshold
+    }
+}
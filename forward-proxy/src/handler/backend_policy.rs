@@ -0,0 +1,232 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use pingora_router::error::Layer8Error;
+use url::{Host, Url};
+
+use crate::config::HandlerConfig;
+
+/// `BackendPolicy` decides which backends the FP opens tunnels to, so `backend_url` can't be used
+/// to reach the FP's own network (SSRF).
+///
+/// `check_url` runs before resolution:
+/// - the scheme must be https when `require_https` is set;
+/// - the host must match `allowed_domains` when not empty, and must not match `denied_domains`.
+///   A pattern is an exact host, or `*.example.com` for any subdomain of `example.com`;
+/// - the port must be in `allowed_ports` when not empty.
+///
+/// `check_addresses` runs after resolution: when `block_private_ips` is set, every address must be
+/// public. IP literals in `backend_url` go through the same check.
+///
+/// Any rejection is a `Layer8Error::BackendNotAllowed`.
+pub struct BackendPolicy {
+    require_https: bool,
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+    allowed_ports: Vec<u16>,
+    block_private_ips: bool,
+}
+
+impl BackendPolicy {
+    pub fn new(config: &HandlerConfig) -> Self {
+        let patterns = |domains: &[String]| domains.iter().map(|domain| domain.to_lowercase()).collect::<Vec<_>>();
+
+        BackendPolicy {
+            require_https: config.backend_require_https,
+            allowed_domains: patterns(&config.backend_allowed_domains),
+            denied_domains: patterns(&config.backend_denied_domains),
+            allowed_ports: config.backend_allowed_ports.clone(),
+            block_private_ips: config.backend_block_private_ips,
+        }
+    }
+
+    pub fn check_url(&self, url: &Url) -> Result<(), Layer8Error> {
+        if self.require_https && url.scheme() != "https" {
+            return Err(Layer8Error::BackendNotAllowed(format!("Scheme {} is not allowed, use https", url.scheme())));
+        }
+
+        let host = match url.host() {
+            None => return Err(Layer8Error::BadRequest("backend_url has no host".to_string())),
+            Some(Host::Domain(domain)) => domain.trim_end_matches('.').to_lowercase(),
+            Some(Host::Ipv4(ip)) => {
+                self.check_ip(IpAddr::V4(ip))?;
+                ip.to_string()
+            }
+            Some(Host::Ipv6(ip)) => {
+                self.check_ip(IpAddr::V6(ip))?;
+                ip.to_string()
+            }
+        };
+
+        if self.denied_domains.iter().any(|pattern| matches_domain(pattern, &host)) {
+            return Err(Layer8Error::BackendNotAllowed(format!("Host {} is denied", host)));
+        }
+        if !self.allowed_domains.is_empty()
+            && !self.allowed_domains.iter().any(|pattern| matches_domain(pattern, &host))
+        {
+            return Err(Layer8Error::BackendNotAllowed(format!("Host {} is not allowed", host)));
+        }
+
+        let port = url.port_or_known_default().unwrap_or_default();
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&port) {
+            return Err(Layer8Error::BackendNotAllowed(format!("Port {} is not allowed", port)));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects the backend if any of its resolved addresses is not public, so a domain can't
+    /// mix a public address with an internal one.
+    pub fn check_addresses(&self, addresses: &[SocketAddr]) -> Result<(), Layer8Error> {
+        addresses.iter().try_for_each(|addr| self.check_ip(addr.ip()))
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), Layer8Error> {
+        if self.block_private_ips && !is_public(ip) {
+            return Err(Layer8Error::BackendNotAllowed(format!("Address {} is not public", ip)));
+        }
+        Ok(())
+    }
+}
+
+/// `*.example.com` matches the subdomains of `example.com`, not `example.com` itself.
+fn matches_domain(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .strip_suffix(parent)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Whether `ip` is reachable on the public internet: not loopback, private, link-local, shared
+/// (CGNAT), multicast, unspecified or reserved for documentation.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let shared = a == 100 && (b & 0b1100_0000) == 64; // 100.64.0.0/10
+
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || shared
+        || a == 0)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses reach the IPv4 address they embed
+    let embedded = match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some((high, low)),
+        [0x2002, high, low, ..] => Some((high, low)),
+        _ => None,
+    };
+    if let Some((high, low)) = embedded {
+        return is_public_v4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+    }
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_domains: &[&str], denied_domains: &[&str]) -> BackendPolicy {
+        let patterns = |domains: &[&str]| domains.iter().map(|domain| domain.to_string()).collect();
+        BackendPolicy {
+            require_https: true,
+            allowed_domains: patterns(allowed_domains),
+            denied_domains: patterns(denied_domains),
+            allowed_ports: vec![],
+            block_private_ips: true,
+        }
+    }
+
+    fn check_url(policy: &BackendPolicy, url: &str) -> Result<(), Layer8Error> {
+        policy.check_url(&Url::parse(url).unwrap())
+    }
+
+    fn is_public_ip(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_checked_as_ipv4() {
+        assert!(!is_public_ip("::ffff:127.0.0.1"));
+        assert!(!is_public_ip("::ffff:10.0.0.1"));
+        assert!(!is_public_ip("::ffff:169.254.169.254"));
+        assert!(is_public_ip("::ffff:93.184.216.34"));
+
+        assert!(check_url(&policy(&[], &[]), "https://[::ffff:192.168.1.1]/").is_err());
+    }
+
+    #[test]
+    fn nat64_addresses_are_checked_as_the_embedded_ipv4() {
+        assert!(!is_public_ip("64:ff9b::7f00:1")); // 127.0.0.1
+        assert!(!is_public_ip("64:ff9b::a00:1")); // 10.0.0.1
+        assert!(!is_public_ip("64:ff9b::a9fe:a9fe")); // 169.254.169.254
+        assert!(is_public_ip("64:ff9b::5db8:d822")); // 93.184.216.34
+    }
+
+    #[test]
+    fn sixtofour_addresses_are_checked_as_the_embedded_ipv4() {
+        assert!(!is_public_ip("2002:7f00:1::1")); // 127.0.0.1
+        assert!(!is_public_ip("2002:c0a8:101::")); // 192.168.1.1
+        assert!(!is_public_ip("2002:6440:1::1")); // 100.64.0.1
+        assert!(is_public_ip("2002:5db8:d822::1")); // 93.184.216.34
+    }
+
+    #[test]
+    fn internal_ipv6_addresses_are_not_public() {
+        assert!(!is_public_ip("::1"));
+        assert!(!is_public_ip("::"));
+        assert!(!is_public_ip("fd00::1"));
+        assert!(!is_public_ip("fe80::1"));
+        assert!(is_public_ip("2606:2800:220:1:248:1893:25c8:1946"));
+    }
+
+    #[test]
+    fn the_allowlist_accepts_exact_hosts_and_subdomains_only() {
+        let policy = policy(&["api.example.com", "*.example.org"], &[]);
+
+        assert!(check_url(&policy, "https://api.example.com/").is_ok());
+        assert!(check_url(&policy, "https://API.Example.com./").is_ok());
+        assert!(check_url(&policy, "https://v1.api.example.org/").is_ok());
+
+        assert!(check_url(&policy, "https://example.com/").is_err());
+        assert!(check_url(&policy, "https://example.org/").is_err());
+        assert!(check_url(&policy, "https://evilexample.org/").is_err());
+        assert!(check_url(&policy, "https://api.example.com.evil.net/").is_err());
+    }
+
+    #[test]
+    fn the_denylist_wins_over_the_allowlist() {
+        let policy = policy(&["*.example.com"], &["admin.example.com"]);
+
+        assert!(check_url(&policy, "https://api.example.com/").is_ok());
+        assert!(matches!(
+            check_url(&policy, "https://admin.example.com/"),
+            Err(Layer8Error::BackendNotAllowed(_))
+        ));
+
+        let policy = BackendPolicy { allowed_domains: vec![], ..policy };
+        assert!(check_url(&policy, "https://other.net/").is_ok());
+        assert!(check_url(&policy, "https://admin.example.com/").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use futures::future::{BoxFuture, Shared};
+use lru::LruCache;
+use pingora_router::error::Layer8Error;
+use tracing::debug;
+
+use crate::handler::AuthServerCertificate;
+use crate::handler::cert_provider::normalize;
+use crate::handler::consts::LogTypes;
+
+type Lookup = Result<AuthServerCertificate, Layer8Error>;
+
+/// A request to the authentication server, awaited by every caller asking for the same backend.
+type InFlight = Shared<BoxFuture<'static, Lookup>>;
+
+#[derive(Clone)]
+struct CacheEntry {
+    lookup: Lookup,
+    fetched_at: Instant,
+    /// Once the entry can't be served anymore, even stale
+    expires_at: Instant,
+}
+
+/// `CertificateCache` keeps the nTor certificates fetched from the authentication server,
+/// by backend URL.
+///
+/// - A certificate is served from the cache for `ttl`. For `stale_ttl` after that, it is still
+///   served while a single background request refreshes it.
+/// - A rejection by the authentication server (`Layer8Error::BadRequest`, from a 4xx) is cached
+///   for `negative_ttl`. Other failures are not cached, the next lookup retries.
+/// - Concurrent lookups of the same backend share one request to the authentication server.
+///
+/// Backend URLs are normalized as by the certificate provider, see `cert_provider::normalize`.
+/// At most `max_entries` backends are kept, the least recently used is evicted beyond it, and an
+/// entry is dropped once it can't be served anymore.
+pub struct CertificateCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    stale_ttl: Duration,
+    entries: Arc<Mutex<LruCache<String, CacheEntry>>>,
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+}
+
+impl CertificateCache {
+    pub fn new(ttl: Duration, negative_ttl: Duration, stale_ttl: Duration, max_entries: usize) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN);
+
+        CertificateCache {
+            ttl,
+            negative_ttl,
+            stale_ttl,
+            entries: Arc::new(Mutex::new(LruCache::new(max_entries))),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the certificate of `backend_url`, calling `fetch` to request it from the
+    /// authentication server when it is not cached and no request is already in flight.
+    pub async fn get<F>(&self, backend_url: &str, fetch: F) -> Lookup
+    where
+        F: FnOnce() -> BoxFuture<'static, Lookup>,
+    {
+        let key = normalize(backend_url);
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(&key).cloned() {
+                Some(entry) if entry.expires_at <= Instant::now() => {
+                    entries.pop(&key);
+                    None
+                }
+                entry => entry,
+            }
+        };
+
+        if let Some(entry) = cached {
+            let age = entry.fetched_at.elapsed();
+            let ttl = match entry.lookup {
+                Ok(_) => self.ttl,
+                Err(_) => self.negative_ttl,
+            };
+
+            if age < ttl {
+                debug!(log_type = LogTypes::AUTHENTICATION_SERVER, backend_url, "Certificate cache hit");
+                return entry.lookup;
+            }
+
+            if entry.lookup.is_ok() && age < ttl + self.stale_ttl {
+                debug!(log_type = LogTypes::AUTHENTICATION_SERVER, backend_url, "Certificate cache stale, revalidating");
+                tokio::spawn(self.in_flight(key, fetch));
+                return entry.lookup;
+            }
+        }
+
+        self.in_flight(key, fetch).await
+    }
+
+    /// The request in flight for the normalized `key`, started with `fetch` if there is none.
+    /// It stores its result in the cache and removes itself from `in_flight` once done.
+    fn in_flight<F>(&self, key: String, fetch: F) -> InFlight
+    where
+        F: FnOnce() -> BoxFuture<'static, Lookup>,
+    {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(request) = in_flight.get(&key) {
+            return request.clone();
+        }
+
+        let request = {
+            let key = key.clone();
+            let entries = self.entries.clone();
+            let pending = self.in_flight.clone();
+            let fetch = fetch();
+            let (ttl, negative_ttl) = (self.ttl + self.stale_ttl, self.negative_ttl);
+
+            async move {
+                let lookup = fetch.await;
+                let ttl = match &lookup {
+                    Ok(_) => Some(ttl),
+                    Err(Layer8Error::BadRequest(_)) => Some(negative_ttl),
+                    Err(_) => None,
+                };
+
+                if let Some(ttl) = ttl {
+                    let now = Instant::now();
+                    let mut entries = entries.lock().unwrap();
+                    // make room with expired entries before evicting a live one
+                    while entries.peek_lru().is_some_and(|(_, entry)| entry.expires_at <= now) {
+                        entries.pop_lru();
+                    }
+                    entries.put(key.clone(), CacheEntry {
+                        lookup: lookup.clone(),
+                        fetched_at: now,
+                        expires_at: now + ttl,
+                    });
+                }
+                pending.lock().unwrap().remove(&key);
+                lookup
+            }
+            .boxed()
+            .shared()
+        };
+
+        in_flight.insert(key, request.clone());
+        request
+    }
+}
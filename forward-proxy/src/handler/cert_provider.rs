@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pingora_router::error::Layer8Error;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::config::HandlerConfig;
+use crate::handler::{AuthServerCertificate, NTorServerCertificate};
+use crate::handler::auth_client::AuthClient;
+use crate::handler::consts::LogTypes;
+
+/// `CertificateProvider` gives the nTor certificate and `client_id` registered for a backend.
+///
+/// Lookups go through the `CertificateCache`, so providers don't cache themselves.
+#[async_trait]
+pub trait CertificateProvider: Send + Sync {
+    /// A backend that is not registered is a `Layer8Error::BadRequest`.
+    async fn get_certificate(
+        &self,
+        backend_url: &str,
+        correlation_id: &str,
+    ) -> Result<AuthServerCertificate, Layer8Error>;
+}
+
+/// Builds the provider configured by `certificate_provider`: "http" for the Layer8 authentication
+/// server, or the path of a static certificates file or directory, see `StaticCertificateProvider`.
+pub fn new_certificate_provider(config: &HandlerConfig) -> io::Result<Arc<dyn CertificateProvider>> {
+    match config.certificate_provider.as_str() {
+        "http" => {
+            let auth_client = AuthClient::new(config)?;
+            Ok(Arc::new(HttpCertificateProvider { auth_client }))
+        }
+        path => Ok(Arc::new(StaticCertificateProvider::open(path)?)),
+    }
+}
+
+/// `HttpCertificateProvider` requests certificates from the Layer8 authentication server.
+pub struct HttpCertificateProvider {
+    auth_client: AuthClient,
+}
+
+#[async_trait]
+impl CertificateProvider for HttpCertificateProvider {
+    async fn get_certificate(
+        &self,
+        backend_url: &str,
+        correlation_id: &str,
+    ) -> Result<AuthServerCertificate, Layer8Error> {
+        // connection errors, timeouts and 5xx are retried by the client
+        let res = self.auth_client.get_certificate(backend_url, correlation_id).await?;
+
+        // connected but request failed: a 4xx means the backend is unknown to layer8
+        if !res.status().is_success() {
+            let detail = format!("Failed to get public key from layer8, status code: {}", res.status().as_u16());
+            error!(
+                %correlation_id,
+                log_type=LogTypes::AUTHENTICATION_SERVER,
+                "Failed to get ntor certificate for {backend_url}: {detail}"
+            );
+            return Err(Layer8Error::BadRequest(detail));
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct AuthServerResponse {
+            pub cert: String,
+            pub client_id: String,
+        }
+
+        let auth_res: AuthServerResponse = res.json().await.map_err(|err| {
+            error!(
+                %correlation_id,
+                log_type=LogTypes::AUTHENTICATION_SERVER,
+                "Failed to parse authentication server response: {:?}",
+                err
+            );
+            Layer8Error::AuthServerUnavailable("Invalid response from layer8".to_string())
+        })?;
+
+        let pub_key = utils::cert::extract_x509_pem(auth_res.cert.clone())
+            .map_err(|e| {
+                error!(
+                    %correlation_id,
+                    log_type=LogTypes::AUTHENTICATION_SERVER,
+                    "Failed to parse x509 certificate: {:?}",
+                    e
+                );
+                Layer8Error::Internal("Invalid ntor certificate".to_string())
+            })?;
+
+        debug!(%correlation_id, "AuthenticationServer response: {:?}", auth_res);
+        info!(
+            %correlation_id,
+            log_type=LogTypes::AUTHENTICATION_SERVER,
+            "Obtained ntor credentials for backend_url: {}",
+            backend_url
+        );
+
+        Ok(AuthServerCertificate {
+            client_id: auth_res.client_id,
+            certificate: NTorServerCertificate {
+                server_id: backend_url.to_string(), // todo I still prefer taking the server_id value from certificate's subject
+                public_key: pub_key,
+            },
+        })
+    }
+}
+
+/// An entry of a static certificates file.
+#[derive(Deserialize)]
+struct StaticCertificate {
+    client_id: String,
+    /// The PEM certificate, or the path of a PEM file relative to the certificates file
+    cert: String,
+}
+
+/// `StaticCertificateProvider` serves certificates from local files, so the FP runs without an
+/// authentication server (local development, CI).
+///
+/// A certificates file is a JSON object mapping backend URLs to their `client_id` and `cert`:
+/// ```json
+/// {
+///   "http://localhost:6193": { "client_id": "local-dev-client", "cert": "rp_cert.pem" }
+/// }
+/// ```
+/// Given a directory, every `.json` file in it is loaded. Certificates are parsed once, when the
+/// provider is opened, so a bad entry fails at startup.
+pub struct StaticCertificateProvider {
+    certificates: HashMap<String, AuthServerCertificate>,
+}
+
+impl StaticCertificateProvider {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut files = Vec::new();
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let file = entry?.path();
+                if file.extension().is_some_and(|ext| ext == "json") {
+                    files.push(file);
+                }
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+
+        let mut certificates = HashMap::new();
+        for file in files {
+            let entries: HashMap<String, StaticCertificate> = serde_json::from_slice(&fs::read(&file)?)?;
+            let dir = file.parent().unwrap_or(Path::new("."));
+
+            for (backend_url, entry) in entries {
+                let pem = if entry.cert.trim_start().starts_with("-----BEGIN") {
+                    entry.cert
+                } else {
+                    fs::read_to_string(dir.join(&entry.cert))?
+                };
+                let public_key = utils::cert::extract_x509_pem(pem).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid certificate for {} in {}: {}", backend_url, file.display(), err),
+                    )
+                })?;
+
+                let backend_url = normalize(&backend_url);
+                certificates.insert(backend_url.clone(), AuthServerCertificate {
+                    client_id: entry.client_id,
+                    certificate: NTorServerCertificate {
+                        server_id: backend_url,
+                        public_key,
+                    },
+                });
+            }
+        }
+
+        info!(
+            log_type=LogTypes::AUTHENTICATION_SERVER,
+            "Loaded {} static ntor certificates from {}",
+            certificates.len(),
+            path.display()
+        );
+        Ok(StaticCertificateProvider { certificates })
+    }
+}
+
+#[async_trait]
+impl CertificateProvider for StaticCertificateProvider {
+    async fn get_certificate(
+        &self,
+        backend_url: &str,
+        correlation_id: &str,
+    ) -> Result<AuthServerCertificate, Layer8Error> {
+        match self.certificates.get(&normalize(backend_url)) {
+            Some(certificate) => {
+                let mut certificate = certificate.clone();
+                // same as the authentication server, the certificate is issued for the URL asked
+                certificate.certificate.server_id = backend_url.to_string();
+                Ok(certificate)
+            }
+            None => {
+                error!(
+                    %correlation_id,
+                    log_type=LogTypes::AUTHENTICATION_SERVER,
+                    "No static ntor certificate for backend_url: {}",
+                    backend_url
+                );
+                Err(Layer8Error::BadRequest(format!("Backend {} is not registered", backend_url)))
+            }
+        }
+    }
+}
+
+/// `http://localhost:6193/` and `HTTP://LocalHost:6193` are the same backend: the trailing slash is
+/// dropped, and the scheme and host, which are case insensitive, are lowercased.
+pub(crate) fn normalize(backend_url: &str) -> String {
+    match url::Url::parse(backend_url) {
+        Ok(url) => url.as_str().trim_end_matches('/').to_string(),
+        Err(_) => backend_url.trim_end_matches('/').to_string(),
+    }
+}
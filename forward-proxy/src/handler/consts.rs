@@ -5,21 +5,8 @@ impl HeaderKeys {
     pub const INT_RP_JWT: &'static str = "int_rp_jwt";
     pub const INT_FP_JWT: &'static str = "int_fp_jwt";
     pub const FP_RP_JWT: &'static str = "fp_rp_jwt";
-}
-
-pub struct CtxKeys;
-
-impl CtxKeys {
-    pub const NTOR_SERVER_ID: &'static str = "ntor_server_id";
-    pub const NTOR_STATIC_PUBLIC_KEY: &'static str = "ntor_static_public_key";
-    pub const UPSTREAM_ADDRESS: &'static str = "upstream_address";
-    pub const UPSTREAM_SNI: &'static str = "upstream_sni";
-    #[allow(dead_code)]
-    pub const INT_RP_JWT: &'static str = "int_rp_jwt";
-    #[allow(dead_code)]
-    pub const INT_FP_JWT: &'static str = "int_fp_jwt";
-    pub const FP_RP_JWT: &'static str = "fp_rp_jwt";
-    pub const BACKEND_AUTH_CLIENT_ID: &'static str = "backend_auth_client_id";
+    /// Set by the RP when the interceptor has to rekey its nTor session
+    pub const REKEY: &'static str = "x-layer8-rekey";
 }
 
 pub struct LogTypes;
@@ -30,15 +17,18 @@ impl LogTypes {
     pub const UPSTREAM_CONNECT: &'static str = "UPSTREAM_CONNECT";
     pub const HANDLE_CLIENT_REQUEST: &'static str = "HANDLE_CLIENT_REQUEST";
     pub const HANDLE_UPSTREAM_RESPONSE: &'static str = "HANDLE_UPSTREAM_RESPONSE";
+    #[allow(dead_code)]
     pub const HEALTHCHECK: &'static str = "HEALTHCHECK";
     pub const INFLUXDB: &'static str = "INFLUXDB";
     pub const AUTHENTICATION_SERVER: &'static str = "AUTHENTICATION_SERVER";
+    pub const SESSION_STORE: &'static str = "SESSION_STORE";
 }
 
 pub struct RequestPaths;
 
 impl RequestPaths {
     pub const PROXY: &'static str = "/proxy";
+    pub const REKEY: &'static str = "/rekey";
     pub const INIT_TUNNEL: &'static str = "/init-tunnel";
     pub const HEALTHCHECK: &'static str = "/healthcheck";
 }
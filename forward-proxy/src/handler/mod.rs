@@ -1,239 +1,365 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use pingora::http::StatusCode;
-use reqwest::Client;
+use pingora::http::{RequestHeader, StatusCode};
+use futures::FutureExt;
+use jsonwebtoken::errors::ErrorKind;
 use pingora_router::{
    ctx::{Layer8Context, Layer8ContextTrait},
-   handler::{APIHandlerResponse, DefaultHandlerTrait, RequestBodyTrait, ResponseBodyTrait}
+   error::Layer8Error,
+   extract::{IntoErrorResponse, Query, WithHeaders},
+   handler::{APIHandlerResponse, DefaultHandlerTrait, RequestBodyTrait, ResponseBodyTrait},
+   timing::Phases,
 };
-use serde::Deserialize;
-use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
 
 use crate::handler::types::{
-   response::{ErrorResponse, FpHealthcheckError, FpHealthcheckSuccess, InitTunnelResponseFromRP, InitTunnelResponseToINT},
-   request::InitTunnelRequest
+   response::{FpHealthcheckError, FpHealthcheckSuccess, InitTunnelResponseFromRP, InitTunnelResponseToINT},
+   request::{HealthcheckQuery, InitTunnelRequest}
 };
-use utils::{self, jwt::JWTClaims};
+use url::Url;
+use utils::{self, dns::DnsResolver, jwt::JWTClaims};
 use crate::config::HandlerConfig;
-use crate::handler::consts::LogTypes;
+use crate::handler::consts::{HeaderKeys, LogTypes};
+use crate::handler::backend_policy::BackendPolicy;
+use crate::handler::cert_cache::CertificateCache;
+use crate::handler::cert_provider::CertificateProvider;
+use crate::handler::session_store::Sessions;
 
 pub mod types;
 pub mod consts;
+pub mod session_store;
+pub mod cert_cache;
+pub mod auth_client;
+pub mod cert_provider;
+pub mod backend_policy;
 
 pub struct ForwardHandler {
     pub config: HandlerConfig,
-    jwts_storage: Arc<Mutex<HashMap<String, IntFPSession>>>, // int_fp_jwt -> IntFPSession
+    sessions: Sessions, // int_fp_jwt -> IntFPSession
+    certificate_provider: Arc<dyn CertificateProvider>,
+    certificates: CertificateCache, // backend_url -> AuthServerCertificate
+    resolver: DnsResolver,
+    backend_policy: BackendPolicy,
 }
 
 impl DefaultHandlerTrait for ForwardHandler {}
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct NTorServerCertificate {
     server_id: String,
     public_key: Vec<u8>,
 }
 
-#[derive(Clone, Debug, Default)]
+/// nTor certificate of a backend and its `client_id`, as registered on the authentication server.
+#[derive(Clone, Debug)]
+pub struct AuthServerCertificate {
+    client_id: String,
+    certificate: NTorServerCertificate,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct IntFPSession {
     pub client_id: String,
     pub rp_base_url: String,
     pub fp_rp_jwt: String,
 }
 
+/// Binds sealed sessions to `int_fp_jwt`, see `utils::seal::seal`.
+const SEALED_SESSION_AAD: &[u8] = b"int_fp_session";
+
+/// `client_id` of the backend, as registered on the authentication server. Set when its
+/// certificate is fetched for `/init-tunnel`, or from the session verified for `/proxy`.
+#[derive(Clone, Debug)]
+pub struct BackendClientId(pub String);
+
+/// Reverse proxy to connect to, resolved by the route's `request_filter` hook and consumed by
+/// `upstream_peer`.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTarget {
+    /// Socket addresses of the RP, tried in order until a connection succeeds.
+    pub addresses: Vec<SocketAddr>,
+    pub sni: String,
+}
+
 impl ForwardHandler {
-    pub fn new(config: HandlerConfig) -> Self {
+    pub fn new(
+        config: HandlerConfig,
+        sessions: Sessions,
+        certificate_provider: Arc<dyn CertificateProvider>,
+        resolver: DnsResolver,
+    ) -> Self {
+        let certificates = CertificateCache::new(
+            Duration::from_secs(config.auth_cert_cache_ttl_secs),
+            Duration::from_secs(config.auth_cert_cache_negative_ttl_secs),
+            Duration::from_secs(config.auth_cert_cache_stale_secs),
+            config.auth_cert_cache_max_entries,
+        );
+
+        let backend_policy = BackendPolicy::new(&config);
+
         ForwardHandler {
             config,
-            jwts_storage: Arc::new(Mutex::new(HashMap::new())),
+            sessions,
+            certificate_provider,
+            certificates,
+            resolver,
+            backend_policy,
         }
     }
 
+    /// Returns the nTor certificate of `backend_url`, from the certificate cache or the
+    /// certificate provider, and saves the backend's `client_id` to ctx.
     async fn get_public_key(
         &self,
         backend_url: String,
         ctx: &mut Layer8Context,
-    ) -> Result<NTorServerCertificate, APIHandlerResponse>
+    ) -> Result<NTorServerCertificate, Layer8Error>
     {
-        let correlation_id = ctx.get_correlation_id();
-        let client = Client::new();
+        let fetch = {
+            let provider = self.certificate_provider.clone();
+            let backend_url = backend_url.clone();
+            let correlation_id = ctx.get_correlation_id();
 
-        let request_path = format!(
-            "{}{}",
-            self.config.auth_get_certificate_url,
-            backend_url
-        );
-        let res = client.get(&request_path)
-            .header("Authorization", self.config.auth_access_token.clone())
-            .send()
-            .await
-            // unable to connect
-            .map_err(|e| {
-                let response_body = ErrorResponse {
-                    error: format!("Failed to connect to layer8: {}", e)
-                };
+            move || async move { provider.get_certificate(&backend_url, &correlation_id).await }.boxed()
+        };
 
-                APIHandlerResponse {
-                    status: StatusCode::INTERNAL_SERVER_ERROR,
-                    cookies: None,
-                    body: Some(response_body.to_bytes()),
-                }
-            })?;
+        match self.certificates.get(&backend_url, fetch).await {
+            Ok(auth_certificate) => {
+                // save `client_id` to ctx for later use
+                ctx.extensions_mut().insert(BackendClientId(auth_certificate.client_id));
 
-        // connected but request failed
-        if !res.status().is_success() {
-            let response_body = ErrorResponse {
-                error: format!("Failed to get public key from layer8, status code: {}", res.status().as_u16()),
-            };
-            error!(
-                %correlation_id,
-                log_type=LogTypes::AUTHENTICATION_SERVER,
-                "Failed to get ntor certificate for {request_path}: {response_body:?}"
-            );
+                // the spellings of a backend URL share a cache entry, the certificate is issued for
+                // the one asked, as by the authentication server
+                let mut certificate = auth_certificate.certificate;
+                certificate.server_id = backend_url;
+                Ok(certificate)
+            }
+            Err(err) => {
+                ctx.insert_response_header("Connection", "close"); // Ensure connection closes???
+                Err(err)
+            }
+        }
+    }
 
-            ctx.insert_response_header("Connection", "close"); // Ensure connection closes???
+    /// Verify `int_fp_jwt` and return its session: looked up in the store, or unsealed from the token
+    pub fn verify_int_fp_jwt(
+        &self,
+        token: &str,
+    ) -> Result<IntFPSession, String> {
+        match utils::jwt::verify_jwt_token(token, &self.config.jwt_virtual_connection_key) {
+            Ok(data) => match &self.sessions {
+                Sessions::Stored(store) => match store.get(token) {
+                    None => Err("token not found!".to_string()),
+                    Some(session) => Ok(session)
+                },
+                Sessions::Sealed(key) => match data.claims.sealed {
+                    None => Err("token carries no session!".to_string()),
+                    Some(sealed) => utils::seal::open(key, SEALED_SESSION_AAD, &sealed)
+                        .map_err(|err| err.to_string()),
+                },
+            },
+            Err(err) => {
+                // the session can't be used anymore
+                if let (Sessions::Stored(store), ErrorKind::ExpiredSignature) = (&self.sessions, err.kind()) {
+                    store.remove(token);
+                }
+                Err(err.to_string())
+            }
+        }
+    }
 
-            Err(APIHandlerResponse {
-                status: StatusCode::BAD_REQUEST,
-                cookies: None,
-                body: Some(response_body.to_bytes()),
-            })
-        } else {
-            #[derive(Deserialize, Debug)]
-            struct AuthServerResponse {
-                pub cert: String,
-                pub client_id: String,
+    /// Resolve the RP to connect to from the `backend_url` param.
+    pub async fn handle_init_tunnel_upstream(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        let result = match ctx.param("backend_url").map(|url| utils::validate_url(url)) {
+            None => Err(Layer8Error::BadRequest("backend_url is a required param".to_string())),
+            Some(None) => Err(Layer8Error::BadRequest("Invalid backend_url".to_string())),
+            Some(Some(url)) => self.resolve_upstream(&url, &ctx.get_correlation_id()).await,
+        };
+
+        match result {
+            Ok(upstream) => {
+                ctx.extensions_mut().insert(upstream);
+                APIHandlerResponse::new(StatusCode::OK, None)
             }
+            Err(err) => err.into_error_response(ctx),
+        }
+    }
+
+    /// Verify `int_fp_jwt` and resolve the RP of the session it belongs to.
+    pub async fn handle_proxy_upstream(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        let correlation_id = ctx.get_correlation_id();
 
-            let auth_res: AuthServerResponse = res.json().await.map_err(|err| {
+        let result = match ctx.get_request_header().get(HeaderKeys::INT_FP_JWT) {
+            None => Err(Layer8Error::BadRequest(format!("Missing {} header", HeaderKeys::INT_FP_JWT))),
+            Some(int_fp_jwt) => self.verify_int_fp_jwt(int_fp_jwt).map_err(|err| {
                 error!(
                     %correlation_id,
-                    log_type=LogTypes::AUTHENTICATION_SERVER,
-                    "Failed to parse authentication server response: {:?}",
-                    err
+                    log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                    "Error verifying int_fp_jwt: {}", err
                 );
-                APIHandlerResponse {
-                    status: StatusCode::INTERNAL_SERVER_ERROR,
-                    cookies: None,
-                    body: None,
-                }
-            })?;
-
-            // save `client_id` to ctx for later use
-            ctx.set(consts::CtxKeys::BACKEND_AUTH_CLIENT_ID.to_string(), auth_res.client_id.clone());
-
-            let pub_key = utils::cert::extract_x509_pem(auth_res.cert.clone())
-                .map_err(|e| {
-                    error!(
-                        %correlation_id,
-                        log_type=LogTypes::AUTHENTICATION_SERVER,
-                        "Failed to parse x509 certificate: {:?}",
-                        e
-                    );
-                    APIHandlerResponse {
-                        status: StatusCode::INTERNAL_SERVER_ERROR,
-                        cookies: None,
-                        body: None,
+                Layer8Error::InvalidSession(format!("Invalid {}: {}", HeaderKeys::INT_FP_JWT, err))
+            }),
+        };
+
+        let error = match result {
+            Err(err) => err,
+            Ok(session) => {
+                debug!(%correlation_id, "IntFPSession: {:?}", session);
+                ctx.extensions_mut().insert(BackendClientId(session.client_id.clone()));
+
+                let upstream = match utils::validate_url(&session.rp_base_url) {
+                    None => Err(Layer8Error::BadRequest("Invalid backend_url".to_string())),
+                    Some(url) => self.resolve_upstream(&url, &correlation_id).await,
+                };
+
+                match upstream {
+                    Err(err) => err,
+                    Ok(upstream) => {
+                        ctx.extensions_mut().insert(upstream);
+                        ctx.extensions_mut().insert(session);
+                        return APIHandlerResponse::new(StatusCode::OK, None);
                     }
-                })?;
+                }
+            }
+        };
+
+        error.into_error_response(ctx)
+    }
+
+    /// Resolve the socket addresses of the RP at `url`, in the order to try them, if the backend
+    /// policy allows it.
+    async fn resolve_upstream(&self, url: &Url, correlation_id: &str) -> Result<UpstreamTarget, Layer8Error> {
+        let check_policy = |result: Result<(), Layer8Error>| {
+            result.inspect_err(|err| {
+                error!(
+                    %correlation_id,
+                    log_type = LogTypes::UPSTREAM_CONNECT,
+                    "Backend {} rejected: {}", url, err
+                );
+            })
+        };
+
+        check_policy(self.backend_policy.check_url(url))?;
 
-            debug!(%correlation_id, "AuthenticationServer response: {:?}", auth_res);
-            info!(
+        let addresses = self.resolver.resolve(url).await.map_err(|err| {
+            error!(
                 %correlation_id,
-                log_type=LogTypes::AUTHENTICATION_SERVER,
-                "Obtained ntor credentials for backend_url: {}",
-                backend_url
+                log_type = LogTypes::UPSTREAM_CONNECT,
+                "Failed to resolve {}: {}", url, err
             );
+            Layer8Error::UpstreamUnavailable(format!("Failed to resolve {}", url.host_str().unwrap_or_default()))
+        })?;
 
-            Ok(NTorServerCertificate {
-                server_id: backend_url, // todo I still prefer taking the server_id value from certificate's subject
-                public_key: pub_key,
-            })
-        }
+        check_policy(self.backend_policy.check_addresses(&addresses))?;
+
+        Ok(UpstreamTarget {
+            addresses,
+            sni: url.domain().unwrap_or_default().to_string(),
+        })
     }
 
-    /// Verify `int_fp_jwt` and return `fp_rp_jwt`
-    pub fn verify_int_fp_jwt(
+    /// Replace `int_fp_jwt` with the session's `fp_rp_jwt` in the request sent to the RP.
+    pub fn handle_proxy_upstream_request(
         &self,
-        token: &str,
-    ) -> Result<IntFPSession, String> {
-        match utils::jwt::verify_jwt_token(token, &self.config.jwt_virtual_connection_key) {
-            Ok(_claims) => {
-                // todo check claims if needed
-                match {
-                    let jwts = self.jwts_storage.lock().unwrap();
-                    jwts.get(token).cloned()
-                } {
-                    None => Err("token not found!".to_string()),
-                    Some(session) => Ok(session)
-                }
+        ctx: &mut Layer8Context,
+        upstream_request: &mut RequestHeader,
+    ) -> pingora::Result<()> {
+        match ctx.extensions().get::<IntFPSession>() {
+            // the session was verified from `int_fp_jwt` in `handle_proxy_upstream`
+            Some(int_fp_session) => {
+                upstream_request
+                    .insert_header(HeaderKeys::FP_RP_JWT, int_fp_session.fp_rp_jwt.clone())
+                    .unwrap_or_default();
+                upstream_request.remove_header(HeaderKeys::INT_FP_JWT);
+                Ok(())
+            }
+            None => {
+                error!(
+                    correlation_id = ctx.get_correlation_id(),
+                    log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                    "Missing verified {} session",
+                    HeaderKeys::INT_FP_JWT
+                );
+                Err(Layer8Error::Internal(format!("Missing verified {} session", HeaderKeys::INT_FP_JWT)).abort(ctx))
             }
-            Err(err) => Err(err.to_string())
         }
     }
 
     /// Validate request body and get ntor certificate for the given backend URL.
     pub async fn handle_init_tunnel_request(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
         // validate request body
-        let received_body = match ForwardHandler::parse_request_body::<
-            InitTunnelRequest,
-            ErrorResponse
-        >(&ctx.get_request_body())
-        {
+        let received_body = match InitTunnelRequest::from_bytes(ctx.get_request_body()) {
             Ok(res) => res.to_bytes(),
-            Err(Some(e)) => {
-                return APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    cookies: None,
-                    body: Some(e.to_bytes()),
-                };
-            }
-            Err(None) => {
-                return APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    cookies: None,
-                    body: None,
-                };
+            Err(e) => {
+                return Layer8Error::BadRequest(format!("Invalid request body: {}", e))
+                    .into_error_response(ctx);
             }
         };
 
         // get public key to initialize encrypted tunnel
         {
-            // it's safe to use unwrap here because this param was already checked in `request_filter`
-            let backend_url = ctx.param("backend_url").unwrap_or(&"".to_string()).to_string();
+            let backend_url = match backend_url_param(ctx) {
+                Ok(url) => url,
+                Err(err) => return err.into_error_response(ctx),
+            };
+
+            ctx.timings_mut().start(Phases::AUTH_SERVER);
+            let server_certificate = self.get_public_key(backend_url, ctx).await;
+            ctx.timings_mut().stop(Phases::AUTH_SERVER);
 
-            let server_certificate = match self.get_public_key(backend_url.to_string(), ctx).await {
+            let server_certificate = match server_certificate {
                 Ok(cert) => cert,
-                Err(err) => return err
+                Err(err) => return err.into_error_response(ctx),
             };
             debug!("Server certificate: {:?}", server_certificate);
 
-            ctx.set(
-                consts::CtxKeys::NTOR_SERVER_ID.to_string(),
-                server_certificate.server_id,
-            );
-            ctx.set(
-                consts::CtxKeys::NTOR_STATIC_PUBLIC_KEY.to_string(),
-                hex::encode(server_certificate.public_key),
-            );
+            ctx.extensions_mut().insert(server_certificate);
         }
 
         APIHandlerResponse {
             status: StatusCode::OK,
-            cookies: None,
+            cookies: Vec::new(),
             body: Some(received_body),
         }
     }
 
     pub fn handle_init_tunnel_response(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
-        let ntor_server_id = ctx.get(&consts::CtxKeys::NTOR_SERVER_ID.to_string()).unwrap_or(&"".to_string()).clone();
-        let ntor_static_public_key = hex::decode(
-            ctx.get(&consts::CtxKeys::NTOR_STATIC_PUBLIC_KEY.to_string()).clone().unwrap_or(&"".to_string())
-        ).unwrap_or_default();
+        let server_certificate = match ctx.extensions_mut().remove::<NTorServerCertificate>() {
+            Some(cert) => cert,
+            None => {
+                error!(
+                    correlation_id=ctx.get_correlation_id(),
+                    log_type=LogTypes::HANDLE_UPSTREAM_RESPONSE,
+                    "Missing nTor server certificate in context"
+                );
+                return Layer8Error::Internal("Missing nTor server certificate".to_string())
+                    .into_error_response(ctx);
+            }
+        };
+
+        // set with the certificate, by `handle_init_tunnel_request`
+        let client_id = match ctx.extensions().get::<BackendClientId>() {
+            Some(BackendClientId(client_id)) => client_id.clone(),
+            None => {
+                error!(
+                    correlation_id=ctx.get_correlation_id(),
+                    log_type=LogTypes::HANDLE_UPSTREAM_RESPONSE,
+                    "Missing backend client id in context"
+                );
+                return Layer8Error::Internal("Missing backend client id".to_string())
+                    .into_error_response(ctx);
+            }
+        };
 
-        let response_body = ctx.get_response_body();
+        let backend_url = match backend_url_param(ctx) {
+            Ok(url) => url,
+            Err(err) => return err.into_error_response(ctx),
+        };
 
-        match utils::bytes_to_json::<InitTunnelResponseFromRP>(response_body) {
+        match InitTunnelResponseFromRP::from_bytes(ctx.get_response_body()).map(|res| *res) {
             Err(e) => {
                 error!(
                     correlation_id=ctx.get_correlation_id(),
@@ -241,72 +367,274 @@ impl ForwardHandler {
                     "Error parsing RP response: {:?}",
                     e
                 );
-                APIHandlerResponse {
-                    status: StatusCode::INTERNAL_SERVER_ERROR,
-                    cookies: None,
-                    body: None,
-                }
+                Layer8Error::UpstreamUnavailable("Invalid response from the reverse proxy".to_string())
+                    .into_error_response(ctx)
             }
             Ok(res_from_rp) => {
-                let int_fp_jwt = {
-                    let mut claims = JWTClaims::new(Some(self.config.jwt_exp_in_hours));
-                    claims.uuid = Some(utils::new_uuid());
-                    utils::jwt::create_jwt_token(claims, &self.config.jwt_virtual_connection_key)
-                };
-
                 let int_fp_session = IntFPSession {
-                    client_id: ctx.get(&consts::CtxKeys::BACKEND_AUTH_CLIENT_ID.to_string()).unwrap_or(&"".to_string()).to_string(),
-                    rp_base_url: ctx.param("backend_url").unwrap_or(&"".to_string()).to_string(),
+                    client_id,
+                    rp_base_url: backend_url,
                     fp_rp_jwt: res_from_rp.fp_rp_jwt,
                 };
 
-                let mut jwts = self.jwts_storage.lock().unwrap();
-                jwts.insert(int_fp_jwt.clone(), int_fp_session);
+                let mut claims = JWTClaims::new(Some(self.config.jwt_exp_in_hours));
+                claims.uuid = Some(utils::new_uuid());
+
+                let int_fp_jwt = match &self.sessions {
+                    Sessions::Stored(store) => {
+                        let int_fp_jwt = utils::jwt::create_jwt_token(claims, &self.config.jwt_virtual_connection_key);
+
+                        // the session lives as long as the token
+                        let ttl = Duration::from_secs(self.config.jwt_exp_in_hours.max(0) as u64 * 3600);
+                        store.insert(int_fp_jwt.clone(), int_fp_session, ttl);
+                        debug!(
+                            correlation_id=ctx.get_correlation_id(),
+                            log_type=LogTypes::SESSION_STORE,
+                            "Saved int_fp_jwt session, {} sessions stored",
+                            store.len()
+                        );
+                        int_fp_jwt
+                    }
+                    Sessions::Sealed(key) => {
+                        claims.sealed = Some(utils::seal::seal(key, SEALED_SESSION_AAD, &int_fp_session));
+                        utils::jwt::create_jwt_token(claims, &self.config.jwt_virtual_connection_key)
+                    }
+                };
 
                 let res_to_int = InitTunnelResponseToINT {
                     ephemeral_public_key: res_from_rp.public_key,
                     t_b_hash: res_from_rp.t_b_hash,
                     int_rp_jwt: res_from_rp.int_rp_jwt,
                     int_fp_jwt,
-                    ntor_static_public_key,
-                    ntor_server_id,
+                    ntor_static_public_key: server_certificate.public_key,
+                    ntor_server_id: server_certificate.server_id,
                 };
 
                 APIHandlerResponse {
                     status: StatusCode::OK,
-                    cookies: None,
+                    cookies: Vec::new(),
                     body: Some(res_to_int.to_bytes()),
                 }
             }
         }
     }
 
-    pub fn handle_healthcheck(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
-        if let Some(error) = ctx.param("error") {
-            if error == "true" {
-                let response_bytes = FpHealthcheckError {
-                    fp_healthcheck_error: "this is placeholder for a custom error".to_string()
-                }.to_bytes();
-
-                ctx.insert_response_header("x-fp-healthcheck-error", "response-header-error");
-                return APIHandlerResponse {
-                    status: StatusCode::IM_A_TEAPOT,
-                    cookies: None,
-                    body: Some(response_bytes),
-                };
-            }
+    /// Answers `?error=true` with `FpHealthcheckError`, see its `IntoErrorResponse`.
+    pub async fn handle_healthcheck(
+        self: Arc<Self>,
+        Query(query): Query<HealthcheckQuery>,
+    ) -> Result<WithHeaders<FpHealthcheckSuccess>, FpHealthcheckError> {
+        if query.error.as_deref() == Some("true") {
+            return Err(FpHealthcheckError {
+                fp_healthcheck_error: "this is placeholder for a custom error".to_string()
+            });
         }
 
-        let response_bytes = FpHealthcheckSuccess {
+        let response = FpHealthcheckSuccess {
             fp_healthcheck_success: "this is placeholder for a custom body".to_string(),
-        }.to_bytes();
+        };
 
-        ctx.insert_response_header("x-fp-healthcheck-success", "response-header-success");
+        Ok(WithHeaders::new(response).header("x-fp-healthcheck-success", "response-header-success"))
+    }
+}
 
-        APIHandlerResponse {
-            status: StatusCode::OK,
-            cookies: None,
-            body: Some(response_bytes),
+/// The `backend_url` param, a `Layer8Error::BadRequest` when missing or empty.
+fn backend_url_param(ctx: &Layer8Context) -> Result<String, Layer8Error> {
+    match ctx.param("backend_url") {
+        Some(url) if !url.is_empty() => Ok(url.clone()),
+        _ => Err(Layer8Error::BadRequest("backend_url is a required param".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use futures::executor::block_on;
+    use pingora::http::Method;
+    use pingora_router::extract::typed;
+    use pingora_router::handler::APIHandler;
+    use pingora_router::hooks::ProxyHooks;
+    use pingora_router::router::Router;
+    use pingora_router::testing;
+    use super::*;
+    use crate::handler::cert_provider::new_certificate_provider;
+    use crate::handler::consts::RequestPaths;
+    use crate::handler::session_store::new_sessions;
+
+    fn config(vars: &[(&str, &str)]) -> HandlerConfig {
+        let mut env: HashMap<&str, &str> = HashMap::from([
+            ("JWT_VIRTUAL_CONNECTION_KEY", "secret"),
+            ("JWT_EXP_IN_HOURS", "24"),
+            ("AUTH_ACCESS_TOKEN", ""),
+            ("AUTH_GET_CERTIFICATE_URL", "http://localhost:5001/sp-pub-key"),
+        ]);
+        env.extend(vars.iter().copied());
+        envy::from_iter(env.into_iter().map(|(key, value)| (key.to_string(), value.to_string()))).unwrap()
+    }
+
+    fn handler(vars: &[(&str, &str)]) -> Arc<ForwardHandler> {
+        let config = config(vars);
+        let sessions = new_sessions(&config).unwrap();
+        let certificate_provider = new_certificate_provider(&config).unwrap();
+        let resolver = DnsResolver::from_system_conf(config.dns_cache_size, Duration::from_secs(60)).unwrap();
+        Arc::new(ForwardHandler::new(config, sessions, certificate_provider, resolver))
+    }
+
+    /// The healthcheck and the `request_filter` hooks of the proxied routes, as in `main`.
+    fn router(vars: &[(&str, &str)]) -> Router<Arc<ForwardHandler>> {
+        let handle_init_tunnel_upstream: APIHandler<Arc<ForwardHandler>> =
+            Box::new(|h, ctx| async move { h.handle_init_tunnel_upstream(ctx).await }.boxed());
+        let handle_proxy_upstream: APIHandler<Arc<ForwardHandler>> =
+            Box::new(|h, ctx| async move { h.handle_proxy_upstream(ctx).await }.boxed());
+
+        let mut router = Router::new(handler(vars));
+        router.get(RequestPaths::HEALTHCHECK.to_string(), Box::new([typed(ForwardHandler::handle_healthcheck)]));
+        router.proxy(
+            Method::POST,
+            RequestPaths::INIT_TUNNEL.to_string(),
+            ProxyHooks::new().request_filter(handle_init_tunnel_upstream),
+        );
+        router.proxy(
+            Method::POST,
+            RequestPaths::PROXY.to_string(),
+            ProxyHooks::new().request_filter(handle_proxy_upstream),
+        );
+        router
+    }
+
+    /// Runs the route's `request_filter` hook, as `ForwardProxy::request_filter` does.
+    fn request_filter(router: &Router<Arc<ForwardHandler>>, ctx: &mut Layer8Context) -> Option<APIHandlerResponse> {
+        ctx.set_correlation_id();
+        block_on(router.request_filter(ctx))
+    }
+
+    fn body(response: &APIHandlerResponse) -> serde_json::Value {
+        serde_json::from_slice(response.body.as_deref().unwrap_or_default()).unwrap()
+    }
+
+    #[test]
+    fn sealed_sessions_require_a_seal_key() {
+        assert!(matches!(new_sessions(&config(&[])), Ok(Sessions::Stored(_))));
+
+        let err = new_sessions(&config(&[("SESSION_STORE", "sealed")])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let sessions = new_sessions(&config(&[
+            ("SESSION_STORE", "sealed"),
+            ("SESSION_SEAL_KEY", "this is 32-byte FPSessionSealKey"),
+        ]));
+        assert!(matches!(sessions, Ok(Sessions::Sealed(key)) if &key == b"this is 32-byte FPSessionSealKey"));
+    }
+
+    #[test]
+    fn healthcheck_answers_with_its_headers() {
+        let router = router(&[]);
+
+        let mut ctx = Layer8Context::builder().method(Method::GET).path(RequestPaths::HEALTHCHECK).build();
+        let response = block_on(testing::dispatch(&router, &mut ctx));
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(body(&response)["fp_healthcheck_success"].is_string());
+        assert_eq!(
+            ctx.get_response_header().get("x-fp-healthcheck-success"),
+            Some("response-header-success")
+        );
+
+        let mut ctx = Layer8Context::builder()
+            .method(Method::GET)
+            .path(&format!("{}?error=true", RequestPaths::HEALTHCHECK))
+            .build();
+        let response = block_on(testing::dispatch(&router, &mut ctx));
+        assert_eq!(response.status, StatusCode::IM_A_TEAPOT);
+        assert!(body(&response)["fp_healthcheck_error"].is_string());
+        assert_eq!(
+            ctx.get_response_header().get("x-fp-healthcheck-error"),
+            Some("response-header-error")
+        );
+    }
+
+    #[test]
+    fn init_tunnel_refuses_a_backend_out_of_policy() {
+        let router = router(&[("BACKEND_REQUIRE_HTTPS", "false")]);
+
+        let cases = [
+            ("", StatusCode::BAD_REQUEST),
+            ("?backend_url=", StatusCode::BAD_REQUEST),
+            ("?backend_url=http://127.0.0.1:3000", StatusCode::FORBIDDEN),
+            ("?backend_url=http://[::ffff:10.0.0.1]", StatusCode::FORBIDDEN),
+        ];
+        for (query, status) in cases {
+            let mut ctx = Layer8Context::builder()
+                .method(Method::POST)
+                .path(&format!("{}{}", RequestPaths::INIT_TUNNEL, query))
+                .build();
+            let response = request_filter(&router, &mut ctx).expect(query);
+            assert_eq!(response.status, status, "{}", query);
+            assert!(!ctx.extensions().contains::<UpstreamTarget>());
+        }
+    }
+
+    #[test]
+    fn proxy_refuses_an_unknown_session() {
+        let router = router(&[]);
+
+        let token = utils::jwt::create_jwt_token(JWTClaims::new(Some(1)), b"secret");
+        let forged = utils::jwt::create_jwt_token(JWTClaims::new(Some(1)), b"forged");
+        let cases = [
+            (None, StatusCode::BAD_REQUEST),
+            (Some(forged.as_str()), StatusCode::UNAUTHORIZED),
+            // signed, but no session was opened with it
+            (Some(token.as_str()), StatusCode::UNAUTHORIZED),
+        ];
+        for (int_fp_jwt, status) in cases {
+            let mut builder = Layer8Context::builder().method(Method::POST).path(RequestPaths::PROXY);
+            if let Some(int_fp_jwt) = int_fp_jwt {
+                builder = builder.header(HeaderKeys::INT_FP_JWT, int_fp_jwt);
+            }
+            let mut ctx = builder.build();
+
+            let response = request_filter(&router, &mut ctx).unwrap();
+            assert_eq!(response.status, status);
+            assert!(!ctx.extensions().contains::<IntFPSession>());
         }
     }
+
+    #[test]
+    fn init_tunnel_sessions_carry_the_backend_client_id() {
+        let handler = handler(&[]);
+        let ctx = |client_id: Option<&str>| {
+            let mut ctx = Layer8Context::builder()
+                .method(Method::POST)
+                .path("/init-tunnel?backend_url=https://backend.example")
+                .extension(NTorServerCertificate {
+                    server_id: "https://backend.example".to_string(),
+                    public_key: vec![1; 32],
+                })
+                .build();
+            if let Some(client_id) = client_id {
+                ctx.extensions_mut().insert(BackendClientId(client_id.to_string()));
+            }
+            ctx.set_response_body(
+                InitTunnelResponseFromRP {
+                    public_key: vec![2; 32],
+                    t_b_hash: vec![3; 32],
+                    int_rp_jwt: "jwt1".to_string(),
+                    fp_rp_jwt: "jwt2".to_string(),
+                }
+                .to_bytes(),
+            );
+            ctx
+        };
+
+        let response = handler.handle_init_tunnel_response(&mut ctx(None));
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = handler.handle_init_tunnel_response(&mut ctx(Some("client-1")));
+        assert_eq!(response.status, StatusCode::OK);
+        let int_fp_jwt = body(&response)["jwt2"].as_str().unwrap().to_string();
+        let session = handler.verify_int_fp_jwt(&int_fp_jwt).unwrap();
+        assert_eq!(session.client_id, "client-1");
+        assert_eq!(session.rp_base_url, "https://backend.example");
+        assert_eq!(session.fp_rp_jwt, "jwt2");
+    }
 }
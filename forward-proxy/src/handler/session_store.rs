@@ -0,0 +1,371 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::config::HandlerConfig;
+use crate::handler::IntFPSession;
+use crate::handler::consts::LogTypes;
+
+/// The log of a `FileSessionStore` is compacted once it holds this many records, and twice as
+/// many as there are sessions.
+const COMPACT_MIN_RECORDS: usize = 1024;
+
+/// `SessionStore` keeps the `IntFPSession` of each `int_fp_jwt` until the token expires.
+///
+/// Implementations are shared by all requests, so they take care of their own locking.
+/// Expired sessions are never returned, and are evicted over time.
+pub trait SessionStore: Send + Sync {
+    fn get(&self, int_fp_jwt: &str) -> Option<IntFPSession>;
+    /// Stores a session for `ttl`. When the store is full, the session expiring first is evicted.
+    fn insert(&self, int_fp_jwt: String, session: IntFPSession, ttl: Duration);
+    fn remove(&self, int_fp_jwt: &str) -> Option<IntFPSession>;
+    fn len(&self) -> usize;
+}
+
+/// `Sessions` is where the forward proxy keeps the `IntFPSession` of each `int_fp_jwt`.
+pub enum Sessions {
+    /// Looked up by `int_fp_jwt` in a store of this FP instance.
+    Stored(Box<dyn SessionStore>),
+    /// Sealed inside the `int_fp_jwt` with this key, see `utils::seal`. Any FP instance configured
+    /// with the same key can open it, so instances are interchangeable and survive restarts.
+    Sealed([u8; 32]),
+}
+
+/// Builds the sessions configured by `session_store`: "memory", "sealed", or the path of the file
+/// persisting sessions across restarts.
+pub fn new_sessions(config: &HandlerConfig) -> io::Result<Sessions> {
+    let max_entries = config.session_store_max_entries;
+    match config.session_store.as_str() {
+        "memory" => Ok(Sessions::Stored(Box::new(MemorySessionStore::new(max_entries)))),
+        "sealed" => match config.session_seal_key {
+            Some(key) => Ok(Sessions::Sealed(key)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "session_seal_key is required when session_store is \"sealed\"",
+            )),
+        },
+        path => Ok(Sessions::Stored(Box::new(FileSessionStore::open(path, max_entries)?))),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SessionEntry {
+    session: IntFPSession,
+    expires_at: SystemTime,
+}
+
+impl SessionEntry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Sessions by key, with expiry and a maximum number of entries.
+#[derive(Debug)]
+struct SessionMap {
+    entries: HashMap<String, SessionEntry>,
+    /// The keys of `entries` by expiry, so the first to expire are found without a scan
+    expiries: BTreeSet<(SystemTime, String)>,
+    max_entries: usize,
+}
+
+impl SessionMap {
+    fn new(max_entries: usize) -> Self {
+        SessionMap {
+            entries: HashMap::new(),
+            expiries: BTreeSet::new(),
+            max_entries,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<IntFPSession> {
+        let now = SystemTime::now();
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get(key).map(|entry| entry.session.clone())
+    }
+
+    fn insert(&mut self, key: String, entry: SessionEntry) {
+        self.evict_expired(SystemTime::now());
+        self.remove(&key);
+
+        while self.entries.len() >= self.max_entries {
+            let Some((_, first_to_expire)) = self.expiries.pop_first() else {
+                break;
+            };
+            self.entries.remove(&first_to_expire);
+        }
+
+        self.expiries.insert((entry.expires_at, key.clone()));
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<SessionEntry> {
+        let entry = self.entries.remove(key)?;
+        self.expiries.remove(&(entry.expires_at, key.to_string()));
+        Some(entry)
+    }
+
+    fn evict_expired(&mut self, now: SystemTime) {
+        while self.expiries.first().is_some_and(|(expires_at, _)| *expires_at <= now) {
+            let Some((_, key)) = self.expiries.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// `MemorySessionStore` keeps sessions in memory only; they are lost on restart.
+pub struct MemorySessionStore {
+    sessions: Mutex<SessionMap>,
+}
+
+impl MemorySessionStore {
+    pub fn new(max_entries: usize) -> Self {
+        MemorySessionStore {
+            sessions: Mutex::new(SessionMap::new(max_entries)),
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn get(&self, int_fp_jwt: &str) -> Option<IntFPSession> {
+        self.sessions.lock().unwrap().get(int_fp_jwt)
+    }
+
+    fn insert(&self, int_fp_jwt: String, session: IntFPSession, ttl: Duration) {
+        let entry = SessionEntry {
+            session,
+            expires_at: SystemTime::now() + ttl,
+        };
+        self.sessions.lock().unwrap().insert(int_fp_jwt, entry);
+    }
+
+    fn remove(&self, int_fp_jwt: &str) -> Option<IntFPSession> {
+        self.sessions.lock().unwrap().remove(int_fp_jwt).map(|entry| entry.session)
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.lock().unwrap().entries.len()
+    }
+}
+
+/// A change of a `FileSessionStore`, one JSON line of its log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord {
+    Insert { key: String, entry: SessionEntry },
+    Remove { key: String },
+}
+
+/// Work of the thread writing the log of a `FileSessionStore`.
+enum LogCommand {
+    Append(Vec<u8>),
+    /// Replaces the log with these records
+    Compact(Vec<u8>),
+}
+
+/// `FileSessionStore` keeps sessions in memory and appends every change to a log file, so tunnels
+/// survive a restart of the forward proxy.
+///
+/// The log is written by a thread of its own, in the order of the changes, so requests never wait
+/// on disk I/O; a failed write is logged and the session is still served from memory. It is
+/// replayed on start, then compacted: on start, and whenever it holds twice as many records as
+/// there are sessions.
+///
+/// The file is only readable by its owner. Sessions are keyed by the SHA-256 of their
+/// `int_fp_jwt`, so the tokens clients present are not on disk.
+pub struct FileSessionStore {
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    sessions: SessionMap,
+    /// Records in the log, since it was last compacted
+    records: usize,
+    log: Sender<LogCommand>,
+}
+
+impl FileSessionStore {
+    /// Loads the sessions of `path`, dropping the expired ones. A missing file is an empty store.
+    pub fn open(path: impl AsRef<Path>, max_entries: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut sessions = SessionMap::new(max_entries);
+
+        match fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<HashMap<String, SessionEntry>>(&bytes) {
+                // the JSON map written by earlier versions, keyed by the tokens themselves
+                Ok(entries) => {
+                    for (int_fp_jwt, entry) in entries {
+                        sessions.insert(token_key(&int_fp_jwt), entry);
+                    }
+                }
+                Err(_) => load_log(&bytes, &mut sessions),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        sessions.evict_expired(SystemTime::now());
+
+        // start from a compacted log
+        replace_file(&path, &snapshot(&sessions))?;
+        let file = open_private(&path, true)?;
+
+        let (log, commands) = mpsc::channel();
+        thread::Builder::new()
+            .name("fp-session-log".to_string())
+            .spawn(move || write_log(path, file, commands))?;
+
+        Ok(FileSessionStore {
+            state: Mutex::new(FileState {
+                records: sessions.entries.len(),
+                sessions,
+                log,
+            }),
+        })
+    }
+}
+
+impl FileState {
+    fn append(&mut self, record: &LogRecord) {
+        let mut line = serde_json::to_vec(record).unwrap_or_default();
+        line.push(b'\n');
+        // the writer only stops with the store
+        let _ = self.log.send(LogCommand::Append(line));
+        self.records += 1;
+
+        if self.records >= COMPACT_MIN_RECORDS.max(2 * self.sessions.entries.len()) {
+            let _ = self.log.send(LogCommand::Compact(snapshot(&self.sessions)));
+            self.records = self.sessions.entries.len();
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn get(&self, int_fp_jwt: &str) -> Option<IntFPSession> {
+        // an expired session found here is dropped from the log when it is compacted
+        self.state.lock().unwrap().sessions.get(&token_key(int_fp_jwt))
+    }
+
+    fn insert(&self, int_fp_jwt: String, session: IntFPSession, ttl: Duration) {
+        let key = token_key(&int_fp_jwt);
+        let entry = SessionEntry {
+            session,
+            expires_at: SystemTime::now() + ttl,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.sessions.insert(key.clone(), entry.clone());
+        state.append(&LogRecord::Insert { key, entry });
+    }
+
+    fn remove(&self, int_fp_jwt: &str) -> Option<IntFPSession> {
+        let key = token_key(int_fp_jwt);
+
+        let mut state = self.state.lock().unwrap();
+        let entry = state.sessions.remove(&key)?;
+        state.append(&LogRecord::Remove { key });
+        Some(entry.session)
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().sessions.entries.len()
+    }
+}
+
+/// Key of a session in a `FileSessionStore`
+fn token_key(int_fp_jwt: &str) -> String {
+    hex::encode(Sha256::digest(int_fp_jwt.as_bytes()))
+}
+
+/// Replays the records of a log. A record cut short by a crash is skipped.
+fn load_log(bytes: &[u8], sessions: &mut SessionMap) {
+    for line in bytes.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+        match serde_json::from_slice(line) {
+            Ok(LogRecord::Insert { key, entry }) => sessions.insert(key, entry),
+            Ok(LogRecord::Remove { key }) => {
+                sessions.remove(&key);
+            }
+            Err(err) => error!(
+                log_type = LogTypes::SESSION_STORE,
+                "Skipped invalid session log record: {}",
+                err
+            ),
+        }
+    }
+}
+
+/// The records of a log holding `sessions`
+fn snapshot(sessions: &SessionMap) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (key, entry) in &sessions.entries {
+        let record = LogRecord::Insert {
+            key: key.clone(),
+            entry: entry.clone(),
+        };
+        if serde_json::to_writer(&mut bytes, &record).is_ok() {
+            bytes.push(b'\n');
+        }
+    }
+    bytes
+}
+
+/// Runs on the log thread until the store is dropped.
+fn write_log(path: PathBuf, mut file: File, commands: Receiver<LogCommand>) {
+    for command in commands {
+        let result = match command {
+            LogCommand::Append(line) => file.write_all(&line),
+            LogCommand::Compact(records) => replace_file(&path, &records)
+                .and_then(|_| open_private(&path, true))
+                .map(|compacted| file = compacted),
+        };
+
+        if let Err(err) = result {
+            error!(
+                log_type = LogTypes::SESSION_STORE,
+                "Failed to write sessions to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Replaces the file atomically: written next to it, then renamed.
+fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    open_private(&tmp_path, false)?.write_all(bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Opens a file only its owner can read, to append to it or to truncate it.
+fn open_private(path: &Path, append: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(append).write(true).truncate(!append);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+
+    // `mode` only applies to a new file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
@@ -7,3 +7,9 @@ pub struct InitTunnelRequest {
 }
 
 impl RequestBodyTrait for InitTunnelRequest {}
+
+#[derive(Deserialize, Debug)]
+pub struct HealthcheckQuery {
+    /// `true` answers with `FpHealthcheckError`
+    pub(crate) error: Option<String>,
+}
@@ -1,19 +1,8 @@
+use pingora::http::StatusCode;
 use serde::{Deserialize, Serialize};
-use pingora_router::handler::ResponseBodyTrait;
-use serde_json::Error;
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ErrorResponse {
-    pub(crate) error: String,
-}
-
-impl ResponseBodyTrait for ErrorResponse {
-    fn from_json_err(err: Error) -> Option<Self> {
-        Some(ErrorResponse {
-            error: err.to_string()
-        })
-    }
-}
+use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
+use pingora_router::extract::IntoErrorResponse;
+use pingora_router::handler::{APIHandlerResponse, ResponseBodyTrait};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitTunnelResponseFromRP { // this struct should match ReverseProxy's Response
@@ -56,3 +45,10 @@ pub struct FpHealthcheckError {
 }
 
 impl ResponseBodyTrait for FpHealthcheckError {}
+
+impl IntoErrorResponse for FpHealthcheckError {
+    fn into_error_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        ctx.insert_response_header("x-fp-healthcheck-error", "response-header-error");
+        APIHandlerResponse::new(StatusCode::IM_A_TEAPOT, Some(self.to_bytes()))
+    }
+}
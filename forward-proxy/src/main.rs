@@ -4,9 +4,21 @@ mod config;
 mod statistics;
 
 use crate::handler::ForwardHandler;
+use crate::handler::consts::RequestPaths;
+use crate::handler::cert_provider::new_certificate_provider;
+use crate::handler::session_store::new_sessions;
+use futures::FutureExt;
 use proxy::ForwardProxy;
+use pingora::http::Method;
 use pingora::prelude::*;
+use pingora_router::extract::typed;
+use pingora_router::handler::APIHandler;
+use pingora_router::hooks::{ProxyHooks, ResponseBodyHook, UpstreamRequestHook};
+use pingora_router::router::Router;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use utils::dns::DnsResolver;
 use crate::config::FPConfig;
 use tracing::{info, debug};
 use crate::statistics::Statistics;
@@ -44,11 +56,82 @@ fn main() {
     })).expect("Failed to create server");
     server.bootstrap();
 
-    let fp_handler = ForwardHandler::new(config.handler_config);
+    let sessions = new_sessions(&config.handler_config).expect("Failed to open session store");
+    let certificate_provider = new_certificate_provider(&config.handler_config)
+        .expect("Failed to build certificate provider");
+    let resolver = DnsResolver::from_system_conf(
+        config.handler_config.dns_cache_size,
+        Duration::from_secs(config.handler_config.dns_max_ttl_secs),
+    ).expect("Failed to read the system DNS configuration");
+    let fp_handler = Arc::new(ForwardHandler::new(
+        config.handler_config,
+        sessions,
+        certificate_provider,
+        resolver,
+    ));
+
+    let handle_healthcheck: APIHandler<Arc<ForwardHandler>> = typed(ForwardHandler::handle_healthcheck);
+
+    // the hooks of proxied routes hand the resolved RP and the session over to `upstream_peer` and
+    // the later hooks through the context, so they work on the context itself
+
+    let handle_init_tunnel_upstream: APIHandler<Arc<ForwardHandler>> =
+        Box::new(|h, ctx| async move { h.handle_init_tunnel_upstream(ctx).await }.boxed());
+
+    let handle_init_tunnel_request: APIHandler<Arc<ForwardHandler>> =
+        Box::new(|h, ctx| async move { h.handle_init_tunnel_request(ctx).await }.boxed());
+
+    let handle_init_tunnel_response: ResponseBodyHook<Arc<ForwardHandler>> =
+        Box::new(|h, ctx| h.handle_init_tunnel_response(ctx));
+
+    let handle_proxy_upstream: APIHandler<Arc<ForwardHandler>> =
+        Box::new(|h, ctx| async move { h.handle_proxy_upstream(ctx).await }.boxed());
+
+    let handle_proxy_upstream_request: UpstreamRequestHook<Arc<ForwardHandler>> =
+        Box::new(|h, ctx, upstream_request| h.handle_proxy_upstream_request(ctx, upstream_request));
+
+    // `/rekey` reaches the RP of the session the same way as `/proxy`
+    let handle_rekey_upstream: APIHandler<Arc<ForwardHandler>> =
+        Box::new(|h, ctx| async move { h.handle_proxy_upstream(ctx).await }.boxed());
+
+    let handle_rekey_upstream_request: UpstreamRequestHook<Arc<ForwardHandler>> =
+        Box::new(|h, ctx, upstream_request| h.handle_proxy_upstream_request(ctx, upstream_request));
+
+    let mut router: Router<Arc<ForwardHandler>> = Router::new(fp_handler);
+    router.get(RequestPaths::HEALTHCHECK.to_string(), Box::new([handle_healthcheck]));
+    router.proxy(
+        Method::POST,
+        RequestPaths::INIT_TUNNEL.to_string(),
+        ProxyHooks::new()
+            .request_filter(handle_init_tunnel_upstream)
+            .request_body_filter(handle_init_tunnel_request)
+            .response_body_filter(handle_init_tunnel_response),
+    );
+    router.proxy(
+        Method::POST,
+        RequestPaths::PROXY.to_string(),
+        ProxyHooks::new()
+            .request_filter(handle_proxy_upstream)
+            .upstream_request_filter(handle_proxy_upstream_request),
+    );
+    router.proxy(
+        Method::POST,
+        RequestPaths::REKEY.to_string(),
+        ProxyHooks::new()
+            .request_filter(handle_rekey_upstream)
+            .upstream_request_filter(handle_rekey_upstream_request),
+    );
+
+    router.max_body_size(config.tls_config.max_request_body_size);
+    router.route_max_body_size(
+        Method::POST,
+        RequestPaths::INIT_TUNNEL,
+        config.tls_config.init_tunnel_max_body_size,
+    );
 
     let mut proxy = http_proxy_service(
         &server.configuration,
-        ForwardProxy::new(config.tls_config, fp_handler),
+        ForwardProxy::new(config.tls_config, router),
     );
 
     proxy.add_tcp(&format!("{}:{}", config.listen_address, config.listen_port));
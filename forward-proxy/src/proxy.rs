@@ -1,7 +1,6 @@
 use crate::config::ProxyConfig;
-use crate::handler::ForwardHandler;
-use crate::handler::consts::{CtxKeys, HeaderKeys, LogTypes, RequestPaths};
-use crate::handler::types::response::ErrorResponse;
+use crate::handler::{BackendClientId, UpstreamTarget};
+use crate::handler::consts::{HeaderKeys, LogTypes, RequestPaths};
 use crate::statistics::Statistics;
 use async_trait::async_trait;
 use boring::x509::X509;
@@ -11,33 +10,42 @@ use pingora::OrErr;
 use pingora::http::{RequestHeader, ResponseHeader, StatusCode};
 use pingora::listeners::tls::TLS_CONF_ERR;
 use pingora::prelude::{HttpPeer, ProxyHttp, Session};
+use pingora::protocols::Digest;
+use pingora::proxy::FailToProxy;
 use pingora::upstreams::peer::PeerOptions;
 use pingora::utils::tls::CertKey;
-use pingora_error::ErrorType;
+use pingora_error::{ErrorSource, ErrorType};
 use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
-use pingora_router::handler::ResponseBodyTrait;
+use pingora_router::error::Layer8Error;
+use pingora_router::extract::IntoErrorResponse;
+use pingora_router::handler::APIHandlerResponse;
+use pingora_router::router::Router;
+use pingora_router::timing::Phases;
 use reqwest::header::TRANSFER_ENCODING;
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::{debug, error, info};
 
-pub struct ForwardProxy {
+pub struct ForwardProxy<T> {
     config: ProxyConfig,
-    handler: ForwardHandler,
+    router: Router<T>,
 }
 
-impl ForwardProxy {
-    pub fn new(tls_config: ProxyConfig, handler: ForwardHandler) -> Self {
+impl<T> ForwardProxy<T> {
+    pub fn new(tls_config: ProxyConfig, router: Router<T>) -> Self {
         ForwardProxy {
             config: tls_config,
-            handler,
+            router,
         }
     }
 
     pub fn set_response_header(&self, ctx: &Layer8Context, response: &mut ResponseHeader) -> pingora::Result<()> {
         response.insert_header("Access-Control-Allow-Credentials", self.config.cors_allow_credentials.to_string())?;
-        response.insert_header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS")?;
+        if let Some(allow) = self.router.allow(&ctx.path()) {
+            response.insert_header("Access-Control-Allow-Methods", allow)?;
+        }
         response.insert_header("Access-Control-Max-Age", "86400")?;
+        response.insert_header("Access-Control-Expose-Headers", HeaderKeys::REKEY)?;
 
         if let Some(origin) = ctx.request.header.get("origin") {
             if self
@@ -50,14 +58,75 @@ impl ForwardProxy {
             }
         }
 
+        if let Some(req_headers) = ctx.request.header.get("Access-Control-Request-Headers") {
+            response.insert_header("Access-Control-Allow-Headers", req_headers.to_string())?;
+        }
+
+        if self.config.server_timing_enabled {
+            response.insert_header("Server-Timing", ctx.timings().server_timing())?;
+        }
+
+        Ok(())
+    }
+
+    /// Maximum request body size in bytes for the request's route.
+    fn body_limit(&self, ctx: &Layer8Context) -> Option<usize> {
+        self.router.body_limit(&ctx.method(), &ctx.path())
+    }
+
+    /// Answers the request with a response from the router, without proxying it.
+    async fn respond(
+        &self,
+        session: &mut Session,
+        ctx: &mut Layer8Context,
+        response: APIHandlerResponse,
+    ) -> pingora::Result<()> {
+        ctx.response.status = response.status;
+
+        let mut header = ResponseHeader::build(response.status, None)?;
+        ctx.get_response_header().write_to_response(&mut header)?;
+        self.set_response_header(ctx, &mut header)?;
+        for cookie in response.cookies.iter() {
+            header.append_header("Set-Cookie", cookie)?;
+        }
+
+        let response_bytes = Bytes::from(response.body.unwrap_or_default());
+        if !response_bytes.is_empty() {
+            header.insert_header("Content-Length", response_bytes.len().to_string())?;
+            ctx.set_response_body(response_bytes.clone());
+        }
+
+        if !response.status.is_success() {
+            session.set_keepalive(None);
+        }
+        session.write_response_header_ref(&header).await?;
+
+        // Write the response body to the session after setting headers
+        session.write_response_body(Some(response_bytes), true).await?;
+        ctx.timings_mut().finish();
         Ok(())
     }
+
+    /// The error to answer a failed request with: the one recorded by `Layer8Error::abort` or a
+    /// handler, else one derived from the pingora error's status `code`.
+    fn failure(&self, ctx: &Layer8Context, code: u16) -> Layer8Error {
+        if let Some(error) = ctx.extensions().get::<Layer8Error>() {
+            return error.clone();
+        }
+
+        match code {
+            413 => Layer8Error::PayloadTooLarge(self.body_limit(ctx).unwrap_or_default()),
+            502..=504 => Layer8Error::UpstreamUnavailable("Failed to reach the reverse proxy".to_string()),
+            400..=499 => Layer8Error::BadRequest("Invalid request".to_string()),
+            _ => Layer8Error::Internal("Failed to proxy the request".to_string()),
+        }
+    }
 }
 
 /// To see the order of execution and how the request is processed, refer to the documentation
 /// see https://github.com/cloudflare/pingora/blob/main/docs/user_guide/phase.md
 #[async_trait]
-impl ProxyHttp for ForwardProxy {
+impl<T: Sync> ProxyHttp for ForwardProxy<T> {
     type CTX = Layer8Context;
 
     fn new_ctx(&self) -> Self::CTX {
@@ -84,58 +153,28 @@ impl ProxyHttp for ForwardProxy {
 
         let correlation_id = ctx.get_correlation_id();
 
-        let addrs = ctx
-            .get(&CtxKeys::UPSTREAM_ADDRESS.to_string())
-            .unwrap_or(&"".to_string())
-            .clone();
-        let sni = ctx
-            .get(&CtxKeys::UPSTREAM_SNI.to_string())
-            .unwrap_or(&"".to_string())
-            .clone();
+        // spans every connection attempt, until `connected_to_upstream`
+        ctx.timings_mut().start(Phases::UPSTREAM_CONNECT);
+
+        let upstream = ctx.extensions().get::<UpstreamTarget>().cloned().unwrap_or_default();
         info!(
             %correlation_id,
             log_type = LogTypes::UPSTREAM_CONNECT,
-            addresses = addrs,
-            sni = sni
+            addresses = ?upstream.addresses,
+            sni = upstream.sni
         );
 
-        // HttpPeer cannot connect to upstream without a valid socket(IP:PORT) address.
-        // A dns name can resolve to multiple socket addresses.
-        // We will try to connect to each address until one succeeds.
-        let mut address_list: Vec<&str> = addrs.split(',').collect();
-        let upstream_sni = sni.to_string(); // clone for move into closure
-        let mut opt_peer = None;
-        for addr in address_list.clone() {
-            match std::panic::catch_unwind(|| HttpPeer::new(addr, self.config.enable_tls, upstream_sni.clone()))
-            {
-                Ok(p) => {
-                    info!(
-                        %correlation_id,
-                        log_type = LogTypes::UPSTREAM_CONNECT,
-                        "Created HttpPeer for addr: {}", addr
-                    );
-                    opt_peer = Some(p);
-                    break;
-                }
-                Err(err) => {
-                    error!(
-                        %correlation_id,
-                        log_type = LogTypes::UPSTREAM_CONNECT,
-                        "Panic occurred while creating HttpPeer for addr: {}, error: {:?}",
-                        addr,
-                        err
-                    );
-                    address_list.retain(|&x| x != addr);
-                    ctx.set(
-                        CtxKeys::UPSTREAM_ADDRESS.to_string(),
-                        address_list.join(","),
-                    );
-                }
+        // A dns name can resolve to multiple socket addresses, alternating IPv6 and IPv4.
+        // We connect to the first one; `fail_to_connect` drops it and retries with the next one.
+        let mut peer = match upstream.addresses.first() {
+            Some(addr) => {
+                info!(
+                    %correlation_id,
+                    log_type = LogTypes::UPSTREAM_CONNECT,
+                    "Created HttpPeer for addr: {}", addr
+                );
+                HttpPeer::new(*addr, self.config.enable_tls, upstream.sni.clone())
             }
-        }
-
-        let mut peer = match opt_peer {
-            Some(p) => p,
             None => {
                 error!(
                     %correlation_id,
@@ -176,6 +215,24 @@ impl ProxyHttp for ForwardProxy {
         Ok(Box::new(peer))
     }
 
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        _reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.timings_mut().stop(Phases::UPSTREAM_CONNECT);
+        ctx.timings_mut().start(Phases::UPSTREAM);
+        Ok(())
+    }
+
     async fn request_filter(
         &self,
         session: &mut Session,
@@ -197,146 +254,26 @@ impl ProxyHttp for ForwardProxy {
             user_agent = ctx.request.header.get("User-Agent"),
         );
 
-        match session.req_header().method {
-            pingora::http::Method::OPTIONS => {
-                // Handle CORS preflight request
-                ctx.response.status = StatusCode::NO_CONTENT;
-                let mut header = ResponseHeader::build(StatusCode::NO_CONTENT, None)?;
-                if let Some(req_headers) = session
-                    .req_header()
-                    .headers
-                    .get("Access-Control-Request-Headers")
-                {
-                    header.insert_header("Access-Control-Allow-Headers", req_headers)?;
-                }
-                self.set_response_header(ctx, &mut header)?;
-
-                session.write_response_header_ref(&header).await?;
-                session.set_keepalive(None);
-                return Ok(true);
-            }
-            _ => {}
-        }
-
-        let mut error_response_bytes: Vec<u8> = vec![];
-        match (
-            session.req_header().uri.path(),
-            session.req_header().method.as_str(),
-        ) {
-            (RequestPaths::HEALTHCHECK, "GET") => {
-                let handler_response = self.handler.handle_healthcheck(ctx);
-                let mut header = ResponseHeader::build(handler_response.status, None)?;
-                let response_headers = header.headers.clone();
-                for (key, val) in response_headers.iter() {
-                    header
-                        .insert_header(key.clone(), val.clone())
-                        .map_err(|e| {
-                            error!(
-                                %correlation_id,
-                                log_type = LogTypes::HEALTHCHECK,
-                                "Cannot add request header {}:{:?}, err: {:?}",
-                                key.clone(),
-                                val.clone(),
-                                e
-                            )
-                        })
-                        .unwrap_or_default();
-                }
-
-                let mut response_bytes = vec![];
-                if let Some(body_bytes) = handler_response.body {
-                    header
-                        .insert_header("Content-length", &body_bytes.len().to_string())
-                        .unwrap_or_default();
-                    response_bytes = body_bytes;
-                };
-
-                session.write_response_header_ref(&header).await?;
-
-                // Write the response body to the session after setting headers
-                session
-                    .write_response_body(Some(Bytes::from(response_bytes)), true)
-                    .await?;
-
-                return Ok(true);
-            }
-            (RequestPaths::INIT_TUNNEL, "POST") => {
-                if let Some(url) = ctx.param("backend_url") {
-                    if let Some(url) = utils::validate_url(url) {
-                        let socket_addr = utils::get_socket_addrs(&url);
-                        ctx.set(CtxKeys::UPSTREAM_ADDRESS.to_string(), socket_addr);
-                        ctx.set(
-                            CtxKeys::UPSTREAM_SNI.to_string(),
-                            url.domain().unwrap_or_default().to_string(),
-                        );
-                    } else {
-                        error_response_bytes = ErrorResponse {
-                            error: "Invalid backend_url".to_string(),
-                        }.to_bytes();
-                    }
-                } else {
-                    error_response_bytes = ErrorResponse {
-                        error: "backend_url is a required param".to_string(),
-                    }.to_bytes();
-                }
-            }
-            (RequestPaths::PROXY, "POST") => {
-                error_response_bytes = match ctx.get_request_header().get(HeaderKeys::INT_FP_JWT) {
-                    None => ErrorResponse {
-                        error: "Missing int_fp_jwt header".to_string(),
-                    }.to_bytes(),
-                    Some(int_fp_jwt) => match self.handler.verify_int_fp_jwt(int_fp_jwt.as_str()) {
-                        Ok(session) => {
-                            debug!(%correlation_id, "IntFPSession: {:?}", session);
-                            ctx.set(CtxKeys::FP_RP_JWT.to_string(), session.fp_rp_jwt);
-                            ctx.set(
-                                CtxKeys::BACKEND_AUTH_CLIENT_ID.to_string(),
-                                session.client_id,
-                            );
-
-                            if let Some(url) = utils::validate_url(&session.rp_base_url) {
-                                let socket_addr = utils::get_socket_addrs(&url);
-                                ctx.set(CtxKeys::UPSTREAM_ADDRESS.to_string(), socket_addr);
-                                ctx.set(
-                                    CtxKeys::UPSTREAM_SNI.to_string(),
-                                    url.domain().unwrap_or_default().to_string(),
-                                );
-                                vec![]
-                            } else {
-                                ErrorResponse {
-                                    error: "Invalid backend_url".to_string(),
-                                }.to_bytes()
-                            }
-                        }
-                        Err(err) => {
-                            error!(
-                                %correlation_id,
-                                log_type = LogTypes::HANDLE_CLIENT_REQUEST,
-                                "Error verifying int_fp_jwt: {}", err
-                            );
-                            ErrorResponse { error: err }.to_bytes()
-                        }
-                    },
-                }
-            }
-            _ => {
-                ctx.response.status = StatusCode::NOT_FOUND;
-                let header = ResponseHeader::build(StatusCode::NOT_FOUND, None)?;
-                session.write_response_header_ref(&header).await?;
-                session.set_keepalive(None);
-                return Ok(true);
-            }
+        // CORS preflights are answered by the router too, with the path's allowed methods
+        if let Some(handler_response) = self.router.request_filter(ctx).await {
+            self.respond(session, ctx, handler_response).await?;
+            return Ok(true);
         }
 
-        if error_response_bytes.len() > 0 {
-            ctx.response.status = StatusCode::BAD_REQUEST;
-            ctx.set_response_body(error_response_bytes.clone());
-            let header = ResponseHeader::build(StatusCode::BAD_REQUEST, None)?;
-            session.write_response_header_ref(&header).await?;
-            session
-                .write_response_body(Some(Bytes::from(error_response_bytes)), true)
-                .await?;
-            session.set_keepalive(None);
+        // reject an oversized body upfront when its length is declared, `request_body_filter`
+        // enforces the limit on the streamed chunks otherwise
+        let limit = self.body_limit(ctx);
+        let content_length = ctx.request_content_length();
+        if content_length.zip(limit).is_some_and(|(length, limit)| length > limit) {
+            info!(
+                %correlation_id,
+                log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                "Request body too large, content-length: {:?}, limit: {:?}",
+                content_length,
+                limit
+            );
+            let response = Layer8Error::PayloadTooLarge(limit.unwrap_or_default()).into_error_response(ctx);
+            self.respond(session, ctx, response).await?;
             return Ok(true);
         }
 
@@ -353,13 +290,50 @@ impl ProxyHttp for ForwardProxy {
     where
         Self::CTX: Send + Sync,
     {
+        ctx.timings_mut().start(Phases::BODY_READ);
+
         if let Some(b) = body {
-            ctx.extend_request_body(b.to_vec());
-            // drop the body
-            b.clear();
+            let limit = self.body_limit(ctx);
+            if let Some(limit) = limit.filter(|limit| request_body_size(ctx) + b.len() > *limit) {
+                error!(
+                    correlation_id = ctx.get_correlation_id(),
+                    log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                    request_summary = session.request_summary(),
+                    "Request body exceeds the limit of {} bytes", limit
+                );
+                // answered by `fail_to_proxy`
+                return Err(Layer8Error::PayloadTooLarge(limit).abort(ctx));
+            }
+        }
+
+        // without a hook, e.g. on `/proxy`, chunks are sent as they arrive, so a streamed upload
+        // reaches the RP without being buffered here
+        if !self.router.has_request_body_filter(ctx) {
+            if let Some(b) = body {
+                let sent = passed_through(ctx);
+                let request = request_body_size(ctx) + b.len();
+                ctx.extensions_mut().insert(PassedThroughBytes { request: Some(request), ..sent });
+            }
+            if end_of_stream {
+                ctx.timings_mut().stop(Phases::BODY_READ);
+                info!(
+                    correlation_id = ctx.get_correlation_id(),
+                    log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                    request_summary = session.request_summary(),
+                    "Request body passed through: {} bytes.",
+                    request_body_size(ctx),
+                );
+            }
+            return Ok(());
+        }
+
+        if let Some(b) = body {
+            // move the chunk into the context, leaving an empty body to send upstream for now
+            ctx.extend_request_body(std::mem::take(b));
         }
 
         if end_of_stream {
+            ctx.timings_mut().stop(Phases::BODY_READ);
             let correlation_id = ctx.get_correlation_id();
 
             info!(
@@ -372,16 +346,16 @@ impl ProxyHttp for ForwardProxy {
 
             // This is the last chunk, we can process the data now
 
-            let handler_response = match session.req_header().uri.path() {
-                RequestPaths::INIT_TUNNEL => self.handler.handle_init_tunnel_request(ctx).await,
-                _ => {
+            let handler_response = match self.router.request_body_filter(ctx).await {
+                Some(handler_response) => handler_response,
+                None => {
                     info!(
                         %correlation_id,
                         log_type = LogTypes::HANDLE_CLIENT_REQUEST,
                         request_summary = session.request_summary(),
                         "Forward proxy passing through request body unchanged."
                     );
-                    *body = Some(Bytes::copy_from_slice(ctx.get_request_body().as_slice()));
+                    *body = Some(ctx.freeze_request_body());
                     return Ok(());
                 }
             };
@@ -391,7 +365,7 @@ impl ProxyHttp for ForwardProxy {
                     %correlation_id,
                     log_type = LogTypes::HANDLE_CLIENT_REQUEST,
                     request_summary = session.request_summary(),
-                    "Failed to handle init-tunnel request with status: {}, error: {}",
+                    "Failed to handle request body with status: {}, error: {}",
                     handler_response.status,
                     utils::bytes_to_string(&handler_response.body.unwrap_or_default())
                 );
@@ -404,18 +378,16 @@ impl ProxyHttp for ForwardProxy {
                 %correlation_id,
                 log_type = LogTypes::HANDLE_CLIENT_REQUEST,
                 request_summary = session.request_summary(),
-                "Handle init-tunnel Request response with status: {}",
+                "Request body handled with status: {}",
                 handler_response.status,
             );
             debug!(
                 %correlation_id,
                 request_summary = session.request_summary(),
-                "Handle init-tunnel response body: {}",
+                "Handled request body: {}",
                 utils::bytes_to_string(&handler_response.body.as_ref().unwrap_or(&vec![]))
             );
-            let fp_req_body = handler_response.body.as_ref().unwrap_or(&vec![]).clone();
-
-            *body = Some(Bytes::copy_from_slice(fp_req_body.as_slice()));
+            *body = Some(Bytes::from(handler_response.body.unwrap_or_default()));
         }
 
         Ok(())
@@ -432,67 +404,7 @@ impl ProxyHttp for ForwardProxy {
     {
         let correlation_id = ctx.get_correlation_id();
 
-        match session.req_header().uri.path() {
-            RequestPaths::PROXY => match upstream_request.headers.get(HeaderKeys::INT_FP_JWT) {
-                None => {
-                    error!(
-                        %correlation_id,
-                        log_type = LogTypes::HANDLE_CLIENT_REQUEST,
-                        request_summary = session.request_summary(),
-                        "Missing required header: {}",
-                        HeaderKeys::INT_FP_JWT
-                    );
-
-                    return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(
-                        u16::from(StatusCode::BAD_REQUEST),
-                    )));
-                }
-                Some(token) => {
-                    let token_str = token.to_str().or_err(
-                        pingora::ErrorType::InvalidHTTPHeader,
-                        "Invalid header value for token",
-                    )?;
-
-                    if token_str.is_empty() {
-                        error!(
-                            %correlation_id,
-                            log_type = LogTypes::HANDLE_CLIENT_REQUEST,
-                            request_summary = session.request_summary(),
-                            "{} token is empty",
-                            HeaderKeys::INT_FP_JWT
-                        );
-
-                        return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(
-                            u16::from(StatusCode::BAD_REQUEST),
-                        )));
-                    }
-
-                    match self.handler.verify_int_fp_jwt(token_str) {
-                        Ok(session) => {
-                            upstream_request
-                                .insert_header(HeaderKeys::FP_RP_JWT, session.fp_rp_jwt)
-                                .unwrap_or_default();
-                            upstream_request.remove_header(HeaderKeys::INT_FP_JWT);
-                        }
-                        Err(err) => {
-                            error!(
-                                %correlation_id,
-                                log_type = LogTypes::HANDLE_CLIENT_REQUEST,
-                                request_summary = session.request_summary(),
-                                "Error verifying {} token: {}",
-                                HeaderKeys::INT_FP_JWT,
-                                err
-                            );
-                            return Err(pingora::Error::explain(
-                                pingora::ErrorType::InvalidHTTPHeader,
-                                err,
-                            ));
-                        }
-                    }
-                }
-            },
-            _ => {}
-        }
+        self.router.upstream_request_filter(ctx, upstream_request)?;
 
         if upstream_request.headers.get("x-empty-body").is_none() {
             upstream_request.remove_header("content-length");
@@ -514,6 +426,30 @@ impl ProxyHttp for ForwardProxy {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
+        ctx.timings_mut().stop(Phases::UPSTREAM);
+
+        // a response body hook, e.g. on `/init-tunnel`, only handles a successful response: any
+        // other is answered here, before its header is sent, instead of failing the hook once the
+        // client already received it
+        let status = upstream_response.status;
+        if !status.is_success() && self.router.has_response_body_filter(ctx) {
+            error!(
+                correlation_id = ctx.get_correlation_id(),
+                log_type = LogTypes::HANDLE_UPSTREAM_RESPONSE,
+                "Upstream answered with status: {}",
+                status
+            );
+            let error = match status.as_u16() {
+                500..=599 => Layer8Error::UpstreamUnavailable(format!(
+                    "The reverse proxy answered with status {}",
+                    status.as_u16()
+                )),
+                code => self.failure(ctx, code),
+            };
+            // answered by `fail_to_proxy`
+            return Err(error.abort(ctx));
+        }
+
         self.set_response_header(ctx, upstream_response)?;
         // if let Some(req_headers) = session
         //     .req_header()
@@ -543,10 +479,29 @@ impl ProxyHttp for ForwardProxy {
     where
         Self::CTX: Send + Sync,
     {
+        // without a hook, e.g. on `/proxy`, chunks are sent as they arrive, so a streamed
+        // download reaches the client without being buffered here
+        if !self.router.has_response_body_filter(ctx) {
+            if let Some(b) = body {
+                let sent = passed_through(ctx);
+                let response = response_body_size(ctx) + b.len();
+                ctx.extensions_mut().insert(PassedThroughBytes { response: Some(response), ..sent });
+            }
+            if end_of_stream {
+                info!(
+                    correlation_id = ctx.get_correlation_id(),
+                    log_type = LogTypes::HANDLE_UPSTREAM_RESPONSE,
+                    request_summary = session.request_summary(),
+                    "Response body passed through: {} bytes.",
+                    response_body_size(ctx),
+                );
+            }
+            return Ok(None);
+        }
+
         if let Some(b) = body {
-            ctx.extend_response_body(b.to_vec());
-            // drop the body
-            b.clear();
+            // move the chunk into the context, leaving an empty body to send downstream for now
+            ctx.extend_response_body(std::mem::take(b));
         }
 
         if end_of_stream {
@@ -561,33 +516,35 @@ impl ProxyHttp for ForwardProxy {
                 &ctx.get_response_body().len(),
             );
 
-            let handler_response = match session.req_header().uri.path() {
-                RequestPaths::INIT_TUNNEL => self.handler.handle_init_tunnel_response(ctx),
-                _ => {
+            let handler_response = match self.router.response_body_filter(ctx) {
+                Some(handler_response) => handler_response,
+                None => {
                     info!(
                         %correlation_id,
                         log_type = LogTypes::HANDLE_UPSTREAM_RESPONSE,
                         request_summary = session.request_summary(),
                         "Forward proxy passing through response body unchanged."
                     );
-                    *body = Some(Bytes::copy_from_slice(ctx.get_response_body().as_slice()));
+                    *body = Some(ctx.freeze_response_body());
                     return Ok(None);
                 }
             };
 
+            // the response header is already sent, see `response_filter`: a failing hook can only
+            // abort the response
             if handler_response.status != StatusCode::OK {
                 error!(
                     %correlation_id,
                     log_type = LogTypes::HANDLE_UPSTREAM_RESPONSE,
                     request_summary = session.request_summary(),
-                    "Failed to handle init-tunnel Response response with status: {}, error: {}",
+                    "Failed to handle response body with status: {}, error: {}",
                     handler_response.status,
                     utils::bytes_to_string(&handler_response.body.unwrap_or_default())
                 );
 
-                ctx.response.status = StatusCode::INTERNAL_SERVER_ERROR;
+                ctx.response.status = handler_response.status;
                 return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(
-                    u16::from(StatusCode::INTERNAL_SERVER_ERROR),
+                    u16::from(handler_response.status),
                 )));
             }
 
@@ -595,15 +552,16 @@ impl ProxyHttp for ForwardProxy {
                 %correlation_id,
                 log_type = LogTypes::HANDLE_UPSTREAM_RESPONSE,
                 request_summary = session.request_summary(),
-                "Handle init-tunnel Response response with status: {}",
+                "Response body handled with status: {}",
                 handler_response.status,
             );
 
-            let fp_res_body = handler_response.body.as_ref().unwrap_or(&vec![]).clone();
+            let fp_res_body = Bytes::from(handler_response.body.unwrap_or_default());
 
             ctx.response.status = handler_response.status;
             ctx.set_response_body(fp_res_body.clone());
-            *body = Some(Bytes::copy_from_slice(fp_res_body.as_slice()));
+            *body = Some(fp_res_body);
+            ctx.timings_mut().finish();
         }
 
         Ok(None)
@@ -613,6 +571,8 @@ impl ProxyHttp for ForwardProxy {
     where
         Self::CTX: Send + Sync,
     {
+        // the response is written by now, unless a filter already recorded it
+        ctx.timings_mut().finish();
         let correlation_id = ctx.get_correlation_id();
 
         let mut status = ctx.response.status.as_u16();
@@ -624,24 +584,35 @@ impl ProxyHttp for ForwardProxy {
             && (session.req_header().uri.path() == RequestPaths::PROXY
             || session.req_header().uri.path() == RequestPaths::INIT_TUNNEL)
         {
-            let client_id = ctx
-                .get(&CtxKeys::BACKEND_AUTH_CLIENT_ID.to_string())
-                .unwrap_or(&"".to_string())
-                .clone();
             let request_path = session.req_header().uri.path().to_string();
-            let total_byte_transferred =
-                (ctx.get_request_body().len() + ctx.get_response_body().len()) as i64;
-            let correlation_id = correlation_id.clone();
-
-            tokio::spawn(async move {
-                Statistics::update(
-                    client_id,
-                    correlation_id,
-                    request_path,
-                    total_byte_transferred,
-                    status,
-                ).await;
-            });
+            match ctx.extensions().get::<BackendClientId>() {
+                Some(BackendClientId(client_id)) => {
+                    let client_id = client_id.clone();
+                    let total_byte_transferred =
+                        (request_body_size(ctx) + response_body_size(ctx)) as i64;
+                    let correlation_id = correlation_id.clone();
+
+                    tokio::spawn(async move {
+                        Statistics::update(
+                            client_id,
+                            correlation_id,
+                            request_path,
+                            total_byte_transferred,
+                            status,
+                        ).await;
+                    });
+                }
+                // refused before the backend was known, e.g. an invalid `int_fp_jwt`
+                None if status >= 400 => {}
+                None => {
+                    error!(
+                        %correlation_id,
+                        log_type=LogTypes::ACCESS_LOG_RESULT,
+                        "Missing backend client id, {} request not counted",
+                        request_path
+                    );
+                }
+            }
         }
 
         info!(
@@ -651,13 +622,62 @@ impl ProxyHttp for ForwardProxy {
             origin = ctx.request.header.get("origin"),
             referer = ctx.request.header.get("referer"),
             status=status,
-            latency_ms=ctx.get_latency_ms(), // todo: is it necessary?
-            response_body_size=ctx.get_response_body().len(),
+            latency_ms=ctx.get_latency_ms(),
+            timings=%ctx.timings(),
+            response_body_size=response_body_size(ctx),
             user_agent=ctx.request.header.get("User-Agent"),
             error=?e,
         );
     }
 
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        // same status mapping as pingora's default implementation
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // the connection is already dead
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        // the connection is already dead
+        if code == 0 {
+            return FailToProxy {
+                error_code: code,
+                can_reuse_downstream: false,
+            };
+        }
+
+        let error = self.failure(ctx, code);
+        let code = error.status().as_u16();
+        let response = error.into_error_response(ctx);
+        if let Err(err) = self.respond(session, ctx, response).await {
+            error!(
+                correlation_id = ctx.get_correlation_id(),
+                log_type = LogTypes::HANDLE_CLIENT_REQUEST,
+                "Failed to send error response to downstream: {}", err
+            );
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     fn fail_to_connect(
         &self,
         _session: &mut Session,
@@ -670,19 +690,13 @@ impl ProxyHttp for ForwardProxy {
             || e.etype == ErrorType::ConnectError
             || e.etype == ErrorType::ConnectRefused
         {
-            let mut addrs = ctx
-                .get(&CtxKeys::UPSTREAM_ADDRESS.to_string())
-                .unwrap_or(&"".to_string())
-                .clone();
-
             // remove failed socket address from the list
-            let idx = addrs.find(",");
-            if let Some(idx) = idx {
-                // set retry=true to recall Self::upstream_peer to try next address
-                retry = true;
-                addrs = addrs[idx + 1..].to_string();
-
-                ctx.set(CtxKeys::UPSTREAM_ADDRESS.to_string(), addrs);
+            if let Some(upstream) = ctx.extensions_mut().get_mut::<UpstreamTarget>() {
+                if upstream.addresses.len() > 1 {
+                    // set retry=true to recall Self::upstream_peer to try next address
+                    retry = true;
+                    upstream.addresses.remove(0);
+                }
             }
 
             error!(
@@ -698,3 +712,25 @@ impl ProxyHttp for ForwardProxy {
         e
     }
 }
+
+/// Sizes of the request and upstream response bodies sent without being buffered in the context,
+/// see `request_body_filter` and `response_body_filter`.
+#[derive(Clone, Copy, Default)]
+struct PassedThroughBytes {
+    request: Option<usize>,
+    response: Option<usize>,
+}
+
+fn passed_through(ctx: &Layer8Context) -> PassedThroughBytes {
+    ctx.extensions().get::<PassedThroughBytes>().copied().unwrap_or_default()
+}
+
+/// Size of the request body, buffered or passed through.
+fn request_body_size(ctx: &Layer8Context) -> usize {
+    passed_through(ctx).request.unwrap_or(ctx.get_request_body().len())
+}
+
+/// Size of the response body, buffered or passed through.
+fn response_body_size(ctx: &Layer8Context) -> usize {
+    passed_through(ctx).response.unwrap_or(ctx.get_response_body().len())
+}
@@ -0,0 +1,143 @@
+use bytes::{Bytes, BytesMut};
+
+/// `Layer8Body` stores a request or response body in `Layer8Context` without copying it
+/// more than needed.
+///
+/// - Setting a body (`set`) or pushing the first chunk (`extend`) keeps the given `Bytes`
+///   as is, without copying.
+/// - Further chunks are appended to a single growable buffer, so a streamed body is copied
+///   once, when it is buffered.
+/// - `freeze` turns the buffer back into `Bytes`, a cheap reference-counted view that can be
+///   handed to pingora (e.g. as the body of `request_body_filter`) while the context keeps its own.
+/// - `as_slice` borrows the current content.
+#[derive(Debug, Clone, Default)]
+pub struct Layer8Body {
+    /// Content as of the last `set`/`freeze`, or the first chunk.
+    frozen: Bytes,
+    /// Content appended since then. Only one of `frozen` and `pending` is non-empty at a time.
+    pending: BytesMut,
+}
+
+impl Layer8Body {
+    pub fn new() -> Self {
+        Layer8Body::default()
+    }
+
+    /// Replaces the body.
+    pub fn set(&mut self, body: impl Into<Bytes>) {
+        self.frozen = body.into();
+        self.pending.clear();
+    }
+
+    /// Appends a chunk to the body.
+    pub fn extend(&mut self, chunk: impl Into<Bytes>) {
+        let chunk = chunk.into();
+        if self.is_empty() {
+            self.frozen = chunk;
+            return;
+        }
+
+        if self.pending.is_empty() {
+            self.pending.reserve(self.frozen.len() + chunk.len());
+            self.pending.extend_from_slice(&self.frozen);
+            self.frozen = Bytes::new();
+        }
+        self.pending.extend_from_slice(&chunk);
+    }
+
+    /// Borrows the current content.
+    pub fn as_slice(&self) -> &[u8] {
+        if self.pending.is_empty() {
+            &self.frozen
+        } else {
+            &self.pending
+        }
+    }
+
+    /// Returns the current content as `Bytes`. The body is kept: later reads and `freeze`
+    /// calls are served from the same allocation.
+    pub fn freeze(&mut self) -> Bytes {
+        if !self.pending.is_empty() {
+            self.frozen = self.pending.split().freeze();
+        }
+        self.frozen.clone()
+    }
+
+    /// Takes the content out, leaving the body empty.
+    pub fn take(&mut self) -> Bytes {
+        let body = self.freeze();
+        self.frozen = Bytes::new();
+        body
+    }
+
+    pub fn len(&self) -> usize {
+        self.frozen.len() + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frozen.is_empty() && self.pending.is_empty()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_slice().to_vec()
+    }
+}
+
+impl AsRef<[u8]> for Layer8Body {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_single_chunk_is_not_copied() {
+        let chunk = Bytes::from_static(b"{\"public_key\":[1,2,3]}");
+
+        let mut body = Layer8Body::new();
+        body.set(chunk.clone());
+        assert_eq!(body.as_slice().as_ptr(), chunk.as_ptr());
+
+        let mut body = Layer8Body::new();
+        body.extend(chunk.clone());
+        assert_eq!(body.as_slice().as_ptr(), chunk.as_ptr());
+        assert_eq!(body.freeze().as_ptr(), chunk.as_ptr());
+    }
+
+    #[test]
+    fn frozen_slices_share_the_buffer() {
+        let mut body = Layer8Body::new();
+        for chunk in ["first ", "second ", "third"] {
+            body.extend(Bytes::from_static(chunk.as_bytes()));
+        }
+        assert_eq!(body.as_slice(), b"first second third");
+        assert_eq!(body.len(), 18);
+
+        let frozen = body.freeze();
+        assert_eq!(body.freeze().as_ptr(), frozen.as_ptr());
+        assert_eq!(body.as_slice().as_ptr(), frozen.as_ptr());
+
+        let second = frozen.slice(6..12);
+        assert_eq!(&second[..], b"second");
+        assert_eq!(second.as_ptr(), frozen[6..].as_ptr());
+    }
+
+    #[test]
+    fn extending_a_frozen_body_keeps_the_handed_out_bytes() {
+        let mut body = Layer8Body::new();
+        body.extend(Bytes::from_static(b"a"));
+        body.extend(Bytes::from_static(b"b"));
+        let frozen = body.freeze();
+
+        body.extend(Bytes::from_static(b"c"));
+        assert_eq!(body.as_slice(), b"abc");
+        assert_eq!(&frozen[..], b"ab");
+
+        assert_eq!(&body.take()[..], b"abc");
+        assert!(body.is_empty());
+        assert!(body.take().is_empty());
+    }
+}
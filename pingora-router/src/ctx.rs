@@ -1,8 +1,12 @@
 use std::collections::HashMap;
-use std::time::Instant;
 use pingora::http::{Method, RequestHeader, StatusCode};
 use pingora::proxy::Session;
-use crate::utils::get_request_body;
+use bytes::Bytes;
+pub use crate::body::Layer8Body;
+pub use crate::extensions::Extensions;
+pub use crate::header::Layer8Header;
+pub use crate::timing::Timings;
+use crate::utils::{read_request_body, read_request_body_with_limit};
 use uuid;
 
 /*
@@ -16,7 +20,11 @@ pub struct Layer8ContextRequestSummary {
     pub scheme: String,
     pub host: String,
     pub path: String,
-    pub params: HashMap<String, String>,
+    /// Query params in request order, percent-decoded. A key may appear several times.
+    pub params: Vec<(String, String)>,
+    /// Params captured from the matched route pattern, e.g. `id` for `/sessions/:id`.
+    /// Filled by the `Router` once a route is matched.
+    pub path_params: HashMap<String, String>,
 }
 
 impl Layer8ContextRequestSummary {
@@ -29,17 +37,7 @@ impl Layer8ContextRequestSummary {
             .map(|h| h.to_string())
             .unwrap_or_else(|| "".to_string());
         let path = session.req_header().uri.path().to_string();
-        let query = session.req_header().uri.query();
-
-        let mut params = HashMap::new();
-        if let Some(query) = query {
-            for pair in query.split('&') {
-                let mut iter = pair.splitn(2, '=');
-                if let (Some(key), Some(value)) = (iter.next(), iter.next()) {
-                    params.insert(key.to_string(), value.to_string());
-                }
-            }
-        }
+        let params = parse_query(session.req_header().uri.query());
 
         Layer8ContextRequestSummary {
             method,
@@ -47,17 +45,28 @@ impl Layer8ContextRequestSummary {
             host,
             path,
             params,
+            path_params: HashMap::new(),
         }
     }
 }
 
+/// Parses a query string into params, following the WHATWG `application/x-www-form-urlencoded`
+/// parser: keys and values are percent-decoded, `+` is decoded as a space, a key without `=`
+/// gets an empty value, and repeated keys are all kept in order.
+fn parse_query(query: Option<&str>) -> Vec<(String, String)> {
+    match query {
+        Some(query) => form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        None => Vec::new(),
+    }
+}
+
 /// `Layer8ContextRequest` is expected to contain all relevant request information
 /// needed for processing and handler access
 #[derive(Debug, Clone, Default)]
 pub struct Layer8ContextRequest {
     pub summary: Layer8ContextRequestSummary,
     pub header: Layer8Header,
-    body: Vec<u8>,
+    body: Layer8Body,
 }
 
 impl Layer8ContextRequest {
@@ -72,7 +81,7 @@ impl Layer8ContextRequest {
 pub struct Layer8ContextResponse {
     pub status: StatusCode,
     pub header: Layer8Header,
-    body: Vec<u8>,
+    body: Layer8Body,
 }
 
 /// `Layer8Context` is the main context object passed to handlers during request processing.
@@ -81,11 +90,12 @@ pub struct Layer8ContextResponse {
 /// - `request`: All relevant request information (method, path, headers, body, params).
 /// - `response`: Data to be returned to the client and shared across handlers (headers, body).
 /// - `memory`: Arbitrary key-value data for sharing state between handlers during processing.
+/// - `extensions`: Typed values for sharing state between handlers during processing.
 ///
 /// This struct is designed to provide a unified interface for accessing and modifying
 /// request and response data, as well as sharing state across middleware and handlers.
 /// All fields are private and should be accessed or modified only through dedicated `get` and `set` methods.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Layer8Context {
     /// `request`: contains all relevant request information needed for processing and handler access
     pub request: Layer8ContextRequest,
@@ -96,18 +106,13 @@ pub struct Layer8Context {
     /// during request processing.
     /// Accessed via `get(&self, key: &str)` and `set(&mut self, key: String, value: String)` methods
     memory: HashMap<String, String>,
-    pub latency_start: Instant, // todo: remove if not needed
-}
-
-impl Default for Layer8Context {
-    fn default() -> Self {
-        Self {
-            request: Default::default(),
-            response: Default::default(),
-            memory: Default::default(),
-            latency_start: Instant::now(),
-        }
-    }
+    /// `extensions`: stores typed values, one per type, that need to be shared across handlers
+    /// during request processing, e.g. resolved upstream addresses or a verified session.
+    /// Accessed via `extensions()` and `extensions_mut()` methods
+    extensions: Extensions,
+    /// `timings`: when the request was received and how long each of its phases took.
+    /// Accessed via `timings()` and `timings_mut()` methods
+    timings: Timings,
 }
 
 impl Layer8Context {
@@ -122,13 +127,128 @@ impl Layer8Context {
     }
 
     pub async fn read_request_body(&mut self, session: &mut Session) -> pingora::Result<bool> {
-        match get_request_body(session).await {
-            Ok(body) => self.request.body = body,
-            Err(err) => return Err(err)
-        };
+        read_request_body(session, &mut self.request.body).await?;
         Ok(true)
     }
 
+    /// Reads the request body like `read_request_body`, but stops as soon as the body is known
+    /// to exceed `limit` bytes: upfront from its `Content-Length`, or while streaming it.
+    /// No limit is applied when `limit` is `None`.
+    ///
+    /// Returns `Ok(false)` when the limit is exceeded. The body is then only partially read,
+    /// so the connection should not be reused.
+    pub async fn read_request_body_with_limit(
+        &mut self,
+        session: &mut Session,
+        limit: Option<usize>,
+    ) -> pingora::Result<bool> {
+        let Some(limit) = limit else {
+            return self.read_request_body(session).await;
+        };
+
+        if self.request_content_length().is_some_and(|length| length > limit) {
+            return Ok(false);
+        }
+
+        read_request_body_with_limit(session, &mut self.request.body, limit).await
+    }
+
+    /// The request's declared `Content-Length`, if present and valid.
+    pub fn request_content_length(&self) -> Option<usize> {
+        self.request.header
+            .get("content-length")
+            .and_then(|length| length.trim().parse().ok())
+    }
+
+}
+
+/// `Layer8ContextBuilder` builds a `Layer8Context` without a pingora `Session`, e.g. to unit test
+/// handlers or drive a `Router` with `testing::dispatch`.
+///
+/// The built context is in the same state as after `Layer8Context::update` and
+/// `Layer8Context::read_request_body`: header names are lowercased and the query string
+/// of `path` is parsed into params.
+///
+/// # Example
+/// ```ignore
+/// let mut ctx = Layer8Context::builder()
+///     .method(Method::POST)
+///     .path("/init-tunnel?backend_url=http://localhost:3000")
+///     .header("x-correlation-id", "test-1")
+///     .body(serde_json::to_vec(&request).unwrap())
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct Layer8ContextBuilder {
+    ctx: Layer8Context,
+}
+
+impl Layer8ContextBuilder {
+    pub fn method(mut self, method: Method) -> Self {
+        self.ctx.request.summary.method = method;
+        self
+    }
+
+    pub fn scheme(mut self, scheme: &str) -> Self {
+        self.ctx.request.summary.scheme = scheme.to_string();
+        self
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.ctx.request.summary.host = host.to_string();
+        self
+    }
+
+    /// Sets the request path. A query string, if any, is parsed into params, which are added
+    /// after the ones set with `param`.
+    pub fn path(mut self, path: &str) -> Self {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        self.ctx.request.summary.path = path.to_string();
+        self.ctx.request.summary.params.extend(parse_query(query));
+        self
+    }
+
+    /// Adds a decoded query param. Calling it several times with the same key adds several values.
+    pub fn param(mut self, key: &str, value: &str) -> Self {
+        self.ctx.request.summary.params.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a request header. Calling it several times with the same name adds several values.
+    pub fn header(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.ctx.request.header.append(key, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.ctx.request.body.set(body);
+        self
+    }
+
+    /// Adds a `memory` entry, as `Layer8ContextTrait::set` would.
+    pub fn memory(mut self, key: &str, value: &str) -> Self {
+        self.ctx.memory.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Adds a typed value to the context's `extensions`.
+    pub fn extension<T: Clone + Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.ctx.extensions.insert(value);
+        self
+    }
+
+    pub fn build(self) -> Layer8Context {
+        self.ctx
+    }
+}
+
+impl Layer8Context {
+    pub fn builder() -> Layer8ContextBuilder {
+        Layer8ContextBuilder::default()
+    }
 }
 
 impl Layer8ContextTrait for Layer8Context {
@@ -139,18 +259,35 @@ impl Layer8ContextTrait for Layer8Context {
         self.request.summary.path.clone()
     }
 
-    fn params(&self) -> &HashMap<String, String> {
+    fn params(&self) -> &[(String, String)] {
         &self.request.summary.params
     }
 
     fn param(&self, key: &str) -> Option<&String> {
-        self.request.summary.params.get(key)
+        self.request.summary.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    fn param_all(&self, key: &str) -> Vec<&String> {
+        self.request.summary.params
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    fn path_params(&self) -> &HashMap<String, String> {
+        &self.request.summary.path_params
+    }
+
+    fn path_param(&self, key: &str) -> Option<&String> {
+        self.request.summary.path_params.get(key)
     }
 
     fn set_request_header(&mut self, header: RequestHeader) {
-        for (key, val) in header.headers.iter() {
-            self.request.header.insert(key.to_string(), val.to_str().unwrap_or("").to_string());
-        };
+        self.request.header = Layer8Header::from(&header);
     }
 
     fn get_request_header(&self) -> &Layer8Header {
@@ -158,10 +295,14 @@ impl Layer8ContextTrait for Layer8Context {
     }
 
     fn insert_response_header(&mut self, key: &str, val: &str) {
-        self.response.header.insert(key.to_lowercase().to_string(), val.to_string());
+        self.response.header.insert(key, val);
     }
 
-    fn remove_response_header(&mut self, key: &str) -> Option<String> {
+    fn append_response_header(&mut self, key: &str, val: &str) {
+        self.response.header.append(key, val);
+    }
+
+    fn remove_response_header(&mut self, key: &str) -> Vec<Vec<u8>> {
         self.response.header.remove(key)
     }
 
@@ -169,28 +310,36 @@ impl Layer8ContextTrait for Layer8Context {
         &self.response.header
     }
 
-    fn set_request_body(&mut self, body: Vec<u8>) {
-        self.request.body = body
+    fn set_request_body(&mut self, body: impl Into<Bytes>) {
+        self.request.body.set(body)
+    }
+
+    fn extend_request_body(&mut self, chunk: impl Into<Bytes>) {
+        self.request.body.extend(chunk)
+    }
+
+    fn get_request_body(&self) -> &[u8] {
+        self.request.body.as_slice()
     }
 
-    fn extend_request_body(&mut self, body: Vec<u8>) {
-        self.request.body.extend(body)
+    fn freeze_request_body(&mut self) -> Bytes {
+        self.request.body.freeze()
     }
 
-    fn get_request_body(&self) -> Vec<u8> {
-        self.request.body.clone()
+    fn set_response_body(&mut self, body: impl Into<Bytes>) {
+        self.response.body.set(body)
     }
 
-    fn set_response_body(&mut self, body: Vec<u8>) {
-        self.response.body = body
+    fn extend_response_body(&mut self, chunk: impl Into<Bytes>) {
+        self.response.body.extend(chunk)
     }
 
-    fn extend_response_body(&mut self, body: Vec<u8>) {
-        self.response.body.extend(body);
+    fn get_response_body(&self) -> &[u8] {
+        self.response.body.as_slice()
     }
 
-    fn get_response_body(&self) -> Vec<u8> {
-        self.response.body.clone()
+    fn freeze_response_body(&mut self) -> Bytes {
+        self.response.body.freeze()
     }
 
     fn get(&self, key: &str) -> Option<&String> {
@@ -201,6 +350,14 @@ impl Layer8ContextTrait for Layer8Context {
         self.memory.insert(key, value);
     }
 
+    fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
     fn set_request_summary(&mut self, summary: Layer8ContextRequestSummary) {
         self.request.summary = summary
     }
@@ -208,9 +365,9 @@ impl Layer8ContextTrait for Layer8Context {
     fn set_correlation_id(&mut self) -> String {
         let correlation_id: String;
         if let Some(cid) = self.get_request_header().get("x-correlation-id") {
-            correlation_id = cid.clone();
+            correlation_id = cid.to_string();
         } else if let Some(cid) = self.get_request_header().get("x-request-id") {
-            correlation_id = cid.clone();
+            correlation_id = cid.to_string();
         } else {
             correlation_id = uuid::Uuid::new_v4().to_string();
         }
@@ -225,8 +382,16 @@ impl Layer8ContextTrait for Layer8Context {
             .clone()
     }
 
+    fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    fn timings_mut(&mut self) -> &mut Timings {
+        &mut self.timings
+    }
+
     fn get_latency_ms(&self) -> i64 {
-        self.latency_start.elapsed().as_nanos() as i64
+        self.timings.elapsed().as_millis() as i64
     }
 }
 
@@ -236,33 +401,90 @@ impl Layer8ContextTrait for Layer8Context {
 pub trait Layer8ContextTrait {
     fn method(&self) -> Method;
     fn path(&self) -> String;
-    fn params(&self) -> &HashMap<String, String>;
+    fn params(&self) -> &[(String, String)];
+    /// First value of the query param `key`, percent-decoded.
     fn param(&self, key: &str) -> Option<&String>;
+    /// All values of the query param `key` in request order, percent-decoded.
+    fn param_all(&self, key: &str) -> Vec<&String>;
+    fn path_params(&self) -> &HashMap<String, String>;
+    fn path_param(&self, key: &str) -> Option<&String>;
     fn set_request_header(&mut self, header: RequestHeader);
     fn get_request_header(&self) -> &Layer8Header;
     fn insert_response_header(&mut self, key: &str, val: &str);
-    fn remove_response_header(&mut self, key: &str) -> Option<String>;
+    fn append_response_header(&mut self, key: &str, val: &str);
+    fn remove_response_header(&mut self, key: &str) -> Vec<Vec<u8>>;
     fn get_response_header(&self) -> &Layer8Header;
-    fn set_request_body(&mut self, body: Vec<u8>);
-    fn extend_request_body(&mut self, body: Vec<u8>);
-    fn get_request_body(&self) -> Vec<u8>;
-    fn set_response_body(&mut self, body: Vec<u8>);
-    fn extend_response_body(&mut self, body: Vec<u8>);
-    fn get_response_body(&self) -> Vec<u8>;
+    fn set_request_body(&mut self, body: impl Into<Bytes>);
+    /// Appends a body chunk. The first chunk is stored without copying.
+    fn extend_request_body(&mut self, chunk: impl Into<Bytes>);
+    fn get_request_body(&self) -> &[u8];
+    /// Returns the request body as `Bytes`, sharing the context's buffer instead of copying it.
+    fn freeze_request_body(&mut self) -> Bytes;
+    fn set_response_body(&mut self, body: impl Into<Bytes>);
+    /// Appends a body chunk. The first chunk is stored without copying.
+    fn extend_response_body(&mut self, chunk: impl Into<Bytes>);
+    fn get_response_body(&self) -> &[u8];
+    /// Returns the response body as `Bytes`, sharing the context's buffer instead of copying it.
+    fn freeze_response_body(&mut self) -> Bytes;
     fn get(&self, key: &str) -> Option<&String>;
     fn set(&mut self, key: String, value: String);
+    fn extensions(&self) -> &Extensions;
+    fn extensions_mut(&mut self) -> &mut Extensions;
     fn set_request_summary(&mut self, summary: Layer8ContextRequestSummary);
     fn set_correlation_id(&mut self) -> String;
     fn get_correlation_id(&self) -> String;
+    fn timings(&self) -> &Timings;
+    fn timings_mut(&mut self) -> &mut Timings;
+    /// Time since the request was received, or until its response was written, in milliseconds.
     fn get_latency_ms(&self) -> i64;
 }
 
-/// `Layer8Header` is a type alias for a map of HTTP header key-value pairs used
-/// throughout the proxy context.
-///
-/// - Keys and values are both `String`.
-/// - Only string-representable header values are currently supported.
-/// - This may need to be updated in the future to support non-string header values
-/// (e.g., binary or multi-valued headers).
-/// - Used for both request and response headers in `Layer8Context`.
-pub type Layer8Header = HashMap<String, String>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(path: &str) -> Layer8Context {
+        Layer8Context::builder().path(path).build()
+    }
+
+    #[test]
+    fn query_is_decoded_as_a_form() {
+        let ctx = ctx("/init-tunnel?backend_url=http%3A%2F%2Flocalhost%3A3000%2Fa%20b&q=one+two%2Bthree&caf%C3%A9=%E2%9C%93");
+
+        assert_eq!(ctx.path(), "/init-tunnel");
+        assert_eq!(ctx.param("backend_url").map(String::as_str), Some("http://localhost:3000/a b"));
+        assert_eq!(ctx.param("q").map(String::as_str), Some("one two+three"));
+        assert_eq!(ctx.param("café").map(String::as_str), Some("✓"));
+    }
+
+    #[test]
+    fn malformed_query_pairs_are_kept() {
+        let ctx = ctx("/healthcheck?flag&empty=&=value&bad=%zz%&&a=b=c");
+
+        assert_eq!(
+            ctx.params(),
+            [
+                ("flag".to_string(), "".to_string()),
+                ("empty".to_string(), "".to_string()),
+                ("".to_string(), "value".to_string()),
+                ("bad".to_string(), "%zz%".to_string()),
+                ("a".to_string(), "b=c".to_string()),
+            ]
+        );
+        assert_eq!(ctx.param("missing"), None);
+    }
+
+    #[test]
+    fn repeated_keys_are_all_kept_in_order() {
+        let ctx = Layer8Context::builder()
+            .param("tag", "first")
+            .path("/proxy?tag=second&other=x&tag=third+3")
+            .build();
+
+        assert_eq!(ctx.param("tag").map(String::as_str), Some("first"));
+        assert_eq!(ctx.param_all("tag"), ["first", "second", "third 3"]);
+        assert_eq!(ctx.param_all("other"), ["x"]);
+        assert!(ctx.param_all("missing").is_empty());
+        assert_eq!(ctx.params().len(), 4);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+use pingora::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use crate::ctx::{Layer8Context, Layer8ContextTrait};
+use crate::extract::IntoErrorResponse;
+use crate::handler::{APIHandlerResponse, ResponseBodyTrait};
+
+/// Media type of the error responses, see RFC 7807.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// `Layer8Error` is the error model shared by the forward and reverse proxies.
+///
+/// Each variant has a stable machine-readable `code` and an HTTP status, and is answered as an
+/// RFC 7807 `application/problem+json` document carrying the request's correlation id. Clients
+/// should branch on `code`:
+/// - `invalid_session` and `decryption_failed`: the tunnel must be re-initialized.
+/// - `replayed_message`: the encrypted message was already received; it is refused however often
+///   it is resent, a new request has to be encrypted.
+/// - `backend_unavailable`, `upstream_unavailable` and `auth_server_unavailable`: a service
+///   behind the proxy is down; retrying later may succeed.
+/// - `auth_server_circuit_open`: the authentication server failed repeatedly and is not called
+///   for a while; retrying immediately fails the same way.
+/// - `backend_not_allowed`: the backend is refused by the proxy's policy (scheme, domain, port or
+///   address); it will be refused again.
+/// - `bad_request`, `not_found`, `method_not_allowed` and `payload_too_large`: the request
+///   itself is wrong and should not be retried as is.
+/// - `internal_error`: a bug or misconfiguration of the proxy.
+///
+/// # Example
+/// ```rust,ignore
+/// async fn handle(h: Arc<MyHandler>, Json(body): Json<MyRequest>) -> Result<MyResponse, Layer8Error> {
+///     let session = h.sessions.get(&body.session_id)
+///         .ok_or_else(|| Layer8Error::InvalidSession("Unknown session".to_string()))?;
+///     ...
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer8Error {
+    /// The request is malformed: invalid body, params or headers.
+    BadRequest(String),
+    /// No route matches the request path.
+    NotFound,
+    /// The path exists, but not for the request method.
+    MethodNotAllowed,
+    /// The request body exceeds the limit, in bytes.
+    PayloadTooLarge(usize),
+    /// The tunnel session is missing, invalid or expired.
+    InvalidSession(String),
+    /// The request body could not be decrypted with the tunnel's shared secret.
+    DecryptionFailed(String),
+    /// The encrypted message was already received in this tunnel session, see the reverse proxy's
+    /// replay protection.
+    ReplayedMessage(String),
+    /// The requested backend is refused by the proxy's backend policy.
+    BackendNotAllowed(String),
+    /// The backend behind the reverse proxy cannot be reached or failed to answer.
+    BackendUnavailable(String),
+    /// The proxy's upstream, e.g. the reverse proxy behind the forward proxy, cannot be reached.
+    UpstreamUnavailable(String),
+    /// The Layer8 authentication server cannot be reached or failed to answer.
+    AuthServerUnavailable(String),
+    /// Every authentication server is failing; they are not called until their circuit breaker
+    /// lets a probe through.
+    AuthServerCircuitOpen(String),
+    Internal(String),
+}
+
+impl Layer8Error {
+    /// Stable machine-readable identifier of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Layer8Error::BadRequest(_) => "bad_request",
+            Layer8Error::NotFound => "not_found",
+            Layer8Error::MethodNotAllowed => "method_not_allowed",
+            Layer8Error::PayloadTooLarge(_) => "payload_too_large",
+            Layer8Error::InvalidSession(_) => "invalid_session",
+            Layer8Error::DecryptionFailed(_) => "decryption_failed",
+            Layer8Error::ReplayedMessage(_) => "replayed_message",
+            Layer8Error::BackendNotAllowed(_) => "backend_not_allowed",
+            Layer8Error::BackendUnavailable(_) => "backend_unavailable",
+            Layer8Error::UpstreamUnavailable(_) => "upstream_unavailable",
+            Layer8Error::AuthServerUnavailable(_) => "auth_server_unavailable",
+            Layer8Error::AuthServerCircuitOpen(_) => "auth_server_circuit_open",
+            Layer8Error::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Layer8Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Layer8Error::NotFound => StatusCode::NOT_FOUND,
+            Layer8Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Layer8Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Layer8Error::InvalidSession(_) => StatusCode::UNAUTHORIZED,
+            Layer8Error::DecryptionFailed(_) => StatusCode::BAD_REQUEST,
+            Layer8Error::ReplayedMessage(_) => StatusCode::CONFLICT,
+            Layer8Error::BackendNotAllowed(_) => StatusCode::FORBIDDEN,
+            Layer8Error::BackendUnavailable(_) => StatusCode::BAD_GATEWAY,
+            Layer8Error::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            Layer8Error::AuthServerUnavailable(_) => StatusCode::BAD_GATEWAY,
+            Layer8Error::AuthServerCircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Layer8Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Short summary of the error kind, the same for every occurrence.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Layer8Error::BadRequest(_) => "Bad request",
+            Layer8Error::NotFound => "Not found",
+            Layer8Error::MethodNotAllowed => "Method not allowed",
+            Layer8Error::PayloadTooLarge(_) => "Payload too large",
+            Layer8Error::InvalidSession(_) => "Invalid tunnel session",
+            Layer8Error::DecryptionFailed(_) => "Decryption failed",
+            Layer8Error::ReplayedMessage(_) => "Replayed message",
+            Layer8Error::BackendNotAllowed(_) => "Backend not allowed",
+            Layer8Error::BackendUnavailable(_) => "Backend unavailable",
+            Layer8Error::UpstreamUnavailable(_) => "Upstream unavailable",
+            Layer8Error::AuthServerUnavailable(_) => "Authentication server unavailable",
+            Layer8Error::AuthServerCircuitOpen(_) => "Authentication server circuit open",
+            Layer8Error::Internal(_) => "Internal error",
+        }
+    }
+
+    /// Explanation specific to this occurrence.
+    pub fn detail(&self) -> String {
+        match self {
+            Layer8Error::NotFound => "No route matches the request path".to_string(),
+            Layer8Error::MethodNotAllowed => "The request method is not allowed for this path".to_string(),
+            Layer8Error::PayloadTooLarge(limit) => format!("Request body exceeds the limit of {} bytes", limit),
+            Layer8Error::BadRequest(detail)
+            | Layer8Error::InvalidSession(detail)
+            | Layer8Error::DecryptionFailed(detail)
+            | Layer8Error::ReplayedMessage(detail)
+            | Layer8Error::BackendNotAllowed(detail)
+            | Layer8Error::BackendUnavailable(detail)
+            | Layer8Error::UpstreamUnavailable(detail)
+            | Layer8Error::AuthServerUnavailable(detail)
+            | Layer8Error::AuthServerCircuitOpen(detail)
+            | Layer8Error::Internal(detail) => detail.clone(),
+        }
+    }
+
+    pub fn to_problem(&self, correlation_id: &str) -> ProblemDetails {
+        ProblemDetails {
+            problem_type: format!("urn:layer8:problem:{}", self.code()),
+            title: self.title().to_string(),
+            status: self.status().as_u16(),
+            detail: self.detail(),
+            code: self.code().to_string(),
+            correlation_id: correlation_id.to_string(),
+        }
+    }
+
+    /// Records the error in `ctx` and returns the pingora error aborting the request with the
+    /// error's status.
+    ///
+    /// Use it in pingora phases that can only fail with a `pingora::Error` (body filters,
+    /// `upstream_request_filter`, ...): the proxy's `fail_to_proxy` then answers with the
+    /// recorded error, see `Layer8ContextTrait::extensions`.
+    pub fn abort(self, ctx: &mut Layer8Context) -> Box<pingora::Error> {
+        let status = self.status().as_u16();
+        ctx.extensions_mut().insert(self);
+        pingora::Error::new(pingora::ErrorType::HTTPStatus(status))
+    }
+}
+
+impl fmt::Display for Layer8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.detail())
+    }
+}
+
+impl std::error::Error for Layer8Error {}
+
+/// Answers the error as `application/problem+json`. The error is also recorded in the context's
+/// extensions, so later phases (e.g. logging) can read it.
+impl IntoErrorResponse for Layer8Error {
+    fn into_error_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        let body = self.to_problem(&ctx.get_correlation_id()).to_bytes();
+        ctx.insert_response_header("Content-Type", PROBLEM_JSON);
+
+        let response = APIHandlerResponse::new(self.status(), Some(body));
+        ctx.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// `ProblemDetails` is the RFC 7807 body of a `Layer8Error`, extended with the error `code`
+/// and the request's `correlation_id`.
+///
+/// # Example
+/// ```json
+/// {
+///   "type": "urn:layer8:problem:invalid_session",
+///   "title": "Invalid tunnel session",
+///   "status": 401,
+///   "detail": "Invalid or expired nTor session ID",
+///   "code": "invalid_session",
+///   "correlation_id": "0b8e2b7e-3f0c-4a8e-9f5e-2d7c1c3b9a10"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    pub correlation_id: String,
+}
+
+impl ResponseBodyTrait for ProblemDetails {}
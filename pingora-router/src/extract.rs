@@ -0,0 +1,411 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::marker::PhantomData;
+use futures::future::BoxFuture;
+use pingora::http::StatusCode;
+use serde::de::DeserializeOwned;
+use crate::ctx::{Layer8Context, Layer8ContextTrait};
+use crate::error::Layer8Error;
+use crate::handler::{APIHandler, APIHandlerResponse, RequestBodyTrait, ResponseBodyTrait};
+
+/*
+ *  Typed handlers: instead of parsing `Layer8Context` by hand, a handler declares what it needs
+ *  as arguments (extractors) and returns `Result<impl IntoResponse, impl IntoErrorResponse>`.
+ *  `typed` turns such a function into a regular `APIHandler` that can be registered on a `Router`.
+ */
+
+/// `IntoErrorResponse` converts a handler error into the response sent to the client.
+///
+/// The context is available to set response headers or read request metadata
+/// (e.g. the correlation id) while building the response.
+pub trait IntoErrorResponse {
+    fn into_error_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse;
+}
+
+impl IntoErrorResponse for APIHandlerResponse {
+    fn into_error_response(self, _ctx: &mut Layer8Context) -> APIHandlerResponse {
+        self
+    }
+}
+
+impl<E: ResponseBodyTrait> IntoErrorResponse for (StatusCode, E) {
+    fn into_error_response(self, _ctx: &mut Layer8Context) -> APIHandlerResponse {
+        APIHandlerResponse::new(self.0, Some(self.1.to_bytes()))
+    }
+}
+
+/// `IntoResponse` converts a typed handler's result into the response sent to the client, as
+/// `IntoErrorResponse` does for its error.
+pub trait IntoResponse {
+    fn into_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse;
+}
+
+/// A response body is answered with `200 OK`.
+impl<R: ResponseBodyTrait> IntoResponse for R {
+    fn into_response(self, _ctx: &mut Layer8Context) -> APIHandlerResponse {
+        APIHandlerResponse::new(StatusCode::OK, Some(self.to_bytes()))
+    }
+}
+
+/// A response body answered with response headers, e.g. `WithHeaders::new(body).header("x-cache", "hit")`.
+#[derive(Debug)]
+pub struct WithHeaders<R> {
+    body: R,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl<R> WithHeaders<R> {
+    pub fn new(body: R) -> Self {
+        WithHeaders {
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((key, value.into()));
+        self
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for WithHeaders<R> {
+    fn into_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        for (key, value) in self.headers.iter() {
+            ctx.insert_response_header(key, value);
+        }
+        self.body.into_response(ctx)
+    }
+}
+
+/// `ExtractError` is returned when an extractor cannot be built from the request.
+/// It is answered as a `Layer8Error::BadRequest` problem.
+#[derive(Debug)]
+pub struct ExtractError {
+    pub message: String,
+}
+
+impl ExtractError {
+    pub fn bad_request(message: impl Display) -> Self {
+        ExtractError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<ExtractError> for Layer8Error {
+    fn from(err: ExtractError) -> Self {
+        Layer8Error::BadRequest(err.message)
+    }
+}
+
+impl IntoErrorResponse for ExtractError {
+    fn into_error_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        Layer8Error::from(self).into_error_response(ctx)
+    }
+}
+
+/// `FromContext` is implemented by every type usable as a typed handler argument.
+pub trait FromContext: Sized {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError>;
+}
+
+/// An optional extractor never fails: it is `None` when the inner extractor fails.
+impl<T: FromContext> FromContext for Option<T> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        Ok(T::from_context(ctx).ok())
+    }
+}
+
+/// JSON request body, deserialized with `RequestBodyTrait::from_bytes`.
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+impl<T: RequestBodyTrait> FromContext for Json<T> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        T::from_bytes(ctx.get_request_body())
+            .map(|body| Json(*body))
+            .map_err(|err| ExtractError::bad_request(format!("Invalid JSON body: {}", err)))
+    }
+}
+
+/// Bincode request body, decoded with the standard bincode configuration.
+#[derive(Debug)]
+pub struct Bincode<T>(pub T);
+
+impl<T: bincode::Decode<()>> FromContext for Bincode<T> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        bincode::decode_from_slice::<T, _>(ctx.get_request_body(), bincode::config::standard())
+            .map(|(body, _)| Bincode(body))
+            .map_err(|err| ExtractError::bad_request(format!("Invalid bincode body: {}", err)))
+    }
+}
+
+/// Query params, deserialized into a struct. Values are parsed from their string form,
+/// so numeric and boolean fields are supported.
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromContext for Query<T> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        from_pairs(ctx.params())
+            .map(Query)
+            .map_err(|err| ExtractError::bad_request(format!("Invalid query params: {}", err)))
+    }
+}
+
+/// Params captured by the route pattern (e.g. `/sessions/:id`), deserialized into a struct.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromContext for Path<T> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        from_pairs(ctx.path_params())
+            .map(Path)
+            .map_err(|err| ExtractError::bad_request(format!("Invalid path params: {}", err)))
+    }
+}
+
+fn from_pairs<T, I, K, V>(pairs: I) -> Result<T, serde_urlencoded::de::Error>
+where
+    T: DeserializeOwned,
+    I: IntoIterator,
+    I::Item: std::borrow::Borrow<(K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let encoded = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+    serde_urlencoded::from_str(&encoded)
+}
+
+/// `HeaderKey` names the header read by a `Header` extractor.
+///
+/// # Example
+/// ```ignore
+/// struct FpRpJwt;
+///
+/// impl HeaderKey for FpRpJwt {
+///     const NAME: &'static str = "fp_rp_jwt";
+/// }
+///
+/// async fn handle(h: Arc<MyHandler>, token: Header<FpRpJwt>) -> Result<MyResponse, ExtractError> { ... }
+/// ```
+pub trait HeaderKey {
+    const NAME: &'static str;
+}
+
+/// A required, non-empty request header value.
+#[derive(Debug)]
+pub struct Header<K> {
+    pub value: String,
+    _key: PhantomData<K>,
+}
+
+impl<K> Header<K> {
+    pub fn into_inner(self) -> String {
+        self.value
+    }
+}
+
+impl<K: HeaderKey> FromContext for Header<K> {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        match ctx.get_request_header().get(K::NAME) {
+            None => Err(ExtractError::bad_request(format!("Missing {} header", K::NAME))),
+            Some("") => Err(ExtractError::bad_request(format!("Empty {} header", K::NAME))),
+            Some(value) => Ok(Header {
+                value: value.to_string(),
+                _key: PhantomData,
+            }),
+        }
+    }
+}
+
+/// The request's correlation id, as set by `Layer8ContextTrait::set_correlation_id`.
+#[derive(Debug)]
+pub struct CorrelationId(pub String);
+
+impl FromContext for CorrelationId {
+    fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+        Ok(CorrelationId(ctx.get_correlation_id()))
+    }
+}
+
+/// `TypedHandler` is implemented for async functions taking the router's handler followed by
+/// up to four extractors, and returning `Result<impl IntoResponse, impl IntoErrorResponse>`.
+pub trait TypedHandler<T, Args>: Send + Sync + 'static {
+    type Output: IntoResponse + Send + 'static;
+    type Error: IntoErrorResponse + Send + 'static;
+
+    fn call(&self, handler: T, args: Args) -> BoxFuture<'static, Result<Self::Output, Self::Error>>;
+}
+
+macro_rules! impl_typed_handler {
+    ($($arg:ident),*) => {
+        impl<T, F, Fut, R, E, $($arg,)*> TypedHandler<T, ($($arg,)*)> for F
+        where
+            F: Fn(T, $($arg,)*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = Result<R, E>> + Send + 'static,
+            R: IntoResponse + Send + 'static,
+            E: IntoErrorResponse + Send + 'static,
+        {
+            type Output = R;
+            type Error = E;
+
+            #[allow(non_snake_case)]
+            fn call(&self, handler: T, ($($arg,)*): ($($arg,)*)) -> BoxFuture<'static, Result<R, E>> {
+                Box::pin(self(handler, $($arg,)*))
+            }
+        }
+
+        impl<$($arg: FromContext,)*> FromContext for ($($arg,)*) {
+            #[allow(unused_variables)]
+            fn from_context(ctx: &Layer8Context) -> Result<Self, ExtractError> {
+                Ok(($($arg::from_context(ctx)?,)*))
+            }
+        }
+    };
+}
+
+impl_typed_handler!();
+impl_typed_handler!(A1);
+impl_typed_handler!(A1, A2);
+impl_typed_handler!(A1, A2, A3);
+impl_typed_handler!(A1, A2, A3, A4);
+
+/// Wraps a typed handler into an `APIHandler`.
+///
+/// Extractors are built before the handler runs; the first failing one answers the request with
+/// its `ExtractError`, as a `bad_request` problem. A successful result is answered with its
+/// `IntoResponse` conversion, `200 OK` and the serialized body for a `ResponseBodyTrait`, an error
+/// with its `IntoErrorResponse` conversion.
+///
+/// The router's handler `T` is cloned into each call, so it is typically an `Arc`.
+///
+/// # Example
+/// ```ignore
+/// impl MyHandler {
+///     async fn create_session(
+///         self: Arc<Self>,
+///         Json(body): Json<CreateSessionRequest>,
+///         CorrelationId(correlation_id): CorrelationId,
+///     ) -> Result<CreateSessionResponse, Layer8Error> {
+///         ...
+///     }
+/// }
+///
+/// router.post("/sessions".to_string(), Box::new([typed(MyHandler::create_session)]));
+/// ```
+pub fn typed<T, Args, H>(typed_handler: H) -> APIHandler<T>
+where
+    T: Clone + Send + Sync + 'static,
+    Args: FromContext + Send + 'static,
+    H: TypedHandler<T, Args>,
+{
+    Box::new(move |handler, ctx| {
+        let call = Args::from_context(ctx).map(|args| typed_handler.call(handler.clone(), args));
+
+        Box::pin(async move {
+            match call {
+                Err(err) => err.into_error_response(ctx),
+                Ok(call) => match call.await {
+                    Ok(response) => response.into_response(ctx),
+                    Err(err) => err.into_error_response(ctx),
+                },
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use pingora::http::Method;
+    use serde::{Deserialize, Serialize};
+    use super::*;
+    use crate::router::Router;
+    use crate::testing;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Greeting {
+        name: String,
+    }
+
+    impl RequestBodyTrait for Greeting {}
+    impl ResponseBodyTrait for Greeting {}
+
+    #[derive(Deserialize, Debug)]
+    struct Options {
+        #[serde(default)]
+        shout: bool,
+    }
+
+    struct Salutation;
+
+    impl HeaderKey for Salutation {
+        const NAME: &'static str = "x-salutation";
+    }
+
+    async fn greet(
+        _: (),
+        Json(greeting): Json<Greeting>,
+        Query(options): Query<Options>,
+        salutation: Option<Header<Salutation>>,
+    ) -> Result<WithHeaders<Greeting>, Layer8Error> {
+        if greeting.name.is_empty() {
+            return Err(Layer8Error::BadRequest("name is empty".to_string()));
+        }
+
+        let salutation = salutation.map(Header::into_inner).unwrap_or("hello".to_string());
+        let mut name = format!("{} {}", salutation, greeting.name);
+        if options.shout {
+            name = name.to_uppercase();
+        }
+        Ok(WithHeaders::new(Greeting { name }).header("x-greeted", "1"))
+    }
+
+    fn call(path: &str, body: &str, salutation: Option<&str>) -> (APIHandlerResponse, Layer8Context) {
+        let mut router = Router::new(());
+        router.post("/greet".to_string(), Box::new([typed(greet)]));
+
+        let mut builder = Layer8Context::builder().method(Method::POST).path(path).body(body.to_string());
+        if let Some(salutation) = salutation {
+            builder = builder.header("x-salutation", salutation);
+        }
+        let mut ctx = builder.build();
+        let response = block_on(testing::dispatch(&router, &mut ctx));
+        (response, ctx)
+    }
+
+    fn name(response: &APIHandlerResponse) -> String {
+        <Greeting as ResponseBodyTrait>::from_bytes(response.body.as_deref().unwrap_or_default()).unwrap().name
+    }
+
+    #[test]
+    fn extractors_reach_the_handler() {
+        let (response, ctx) = call("/greet", r#"{"name":"int"}"#, None);
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(name(&response), "hello int");
+        assert_eq!(ctx.get_response_header().get("x-greeted"), Some("1"));
+
+        let (response, _) = call("/greet?shout=true", r#"{"name":"int"}"#, Some("hi"));
+        assert_eq!(name(&response), "HI INT");
+    }
+
+    #[test]
+    fn a_failing_extractor_is_a_bad_request() {
+        for (path, body) in [("/greet", "not json"), ("/greet?shout=maybe", r#"{"name":"int"}"#)] {
+            let (response, ctx) = call(path, body, None);
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", path);
+            assert!(!ctx.get_response_header().contains_key("x-greeted"));
+        }
+    }
+
+    #[test]
+    fn a_handler_error_is_converted() {
+        let (response, ctx) = call("/greet", r#"{"name":""}"#, None);
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(ctx.get_response_header().get("content-type"), Some(crate::error::PROBLEM_JSON));
+        assert!(!ctx.get_response_header().contains_key("x-greeted"));
+    }
+}
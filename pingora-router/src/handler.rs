@@ -20,7 +20,7 @@ use serde::ser::Serialize;
 /// - `T`: The handler type, typically containing shared state or logic for processing requests.
 ///
 /// # Example
-/// ```rust
+/// ```ignore
 /// use std::sync::Arc;
 /// use futures::FutureExt;
 /// use pingora::http::StatusCode;
@@ -48,13 +48,14 @@ pub type APIHandler<T> = Box<dyn for<'a> Fn(&'a T, &'a mut Layer8Context) -> Box
 #[derive(Debug, Default)]
 pub struct APIHandlerResponse {
     pub status: StatusCode,
-    pub cookies: Option<String>,
+    /// `Set-Cookie` values, each sent as its own header.
+    pub cookies: Vec<String>,
     pub body: Option<Vec<u8>>,
 }
 
 impl APIHandlerResponse {
-    pub fn new(status: StatusCode, body: Option<Vec<u8>>, cookies: Option<String>) -> Self {
-        APIHandlerResponse { status, cookies, body }
+    pub fn new(status: StatusCode, body: Option<Vec<u8>>) -> Self {
+        APIHandlerResponse { status, cookies: Vec::new(), body }
     }
 }
 
@@ -69,8 +70,8 @@ pub trait ResponseBodyTrait: Serialize + for<'de> Deserialize<'de> + Debug {
         serde_json::to_vec(self).unwrap()
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Box<Self>, serde_json::Error> {
-        serde_json::from_slice(&bytes)
+    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>, serde_json::Error> {
+        serde_json::from_slice(bytes)
     }
 
     /// Override this method to handle error serialization if your handler implements
@@ -90,8 +91,8 @@ pub trait RequestBodyTrait: Serialize + for<'de> Deserialize<'de> + Debug {
         serde_json::to_vec(self).unwrap()
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Box<Self>, serde_json::Error> {
-        serde_json::from_slice(&bytes)
+    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>, serde_json::Error> {
+        serde_json::from_slice(bytes)
     }
 }
 
@@ -108,9 +109,9 @@ pub trait RequestBodyTrait: Serialize + for<'de> Deserialize<'de> + Debug {
 /// If deserialization fails, it returns no body, an error response of type `E: impl
 /// ResponseBodyTrait` (constructed from the JSON error), and a 400 Bad Request status.
 pub trait DefaultHandlerTrait {
-    fn parse_request_body<T: RequestBodyTrait, E: ResponseBodyTrait>(data: &Vec<u8>) -> Result<T, Option<E>>
+    fn parse_request_body<T: RequestBodyTrait, E: ResponseBodyTrait>(data: &[u8]) -> Result<T, Option<E>>
     {
-        match T::from_bytes(data.clone()) {
+        match T::from_bytes(data) {
             Ok(body) => Ok(*body),
             Err(e) => Err(E::from_json_err(e))
         }
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+/// `Layer8Header` is the map of HTTP headers used for both request and response headers in
+/// `Layer8Context`.
+///
+/// - Header names are case-insensitive and stored lowercased.
+/// - Entries keep their insertion order, and a name may carry several values
+///   (e.g. repeated `Cookie` or `Set-Cookie` headers).
+/// - Values are kept as raw bytes, so non-UTF-8 values are not lost. `get` only returns values
+///   that are valid UTF-8; use `get_bytes`/`get_all` to read raw values.
+///
+/// # Example
+/// ```rust
+/// use pingora_router::header::Layer8Header;
+///
+/// let mut header = Layer8Header::new();
+/// header.append("Set-Cookie", "a=1");
+/// header.append("set-cookie", "b=2");
+///
+/// assert_eq!(header.get("SET-COOKIE"), Some("a=1"));
+/// assert_eq!(header.get_all("set-cookie").count(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layer8Header {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Layer8Header {
+    pub fn new() -> Self {
+        Layer8Header { entries: Vec::new() }
+    }
+
+    /// Returns the first value of `name`, if it is valid UTF-8.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_bytes(name).and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Returns the first raw value of `name`.
+    pub fn get_bytes(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns all raw values of `name`, in insertion order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    /// Sets `name` to a single value, replacing any previous values. The header keeps the
+    /// position of its first previous value, if any.
+    pub fn insert(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        let mut value = Some(value.into());
+        self.entries.retain_mut(|(key, slot)| {
+            if !key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            match value.take() {
+                Some(value) => {
+                    *slot = value;
+                    true
+                }
+                None => false,
+            }
+        });
+
+        if let Some(value) = value {
+            self.entries.push((name.to_lowercase(), value));
+        }
+    }
+
+    /// Adds a value to `name`, keeping the previous ones.
+    pub fn append(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.entries.push((name.to_lowercase(), value.into()));
+    }
+
+    /// Removes all values of `name` and returns them.
+    pub fn remove(&mut self, name: &str) -> Vec<Vec<u8>> {
+        let (removed, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(key, _)| key.eq_ignore_ascii_case(name));
+        self.entries = kept;
+        removed.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// Iterates over all `(name, value)` entries in insertion order. Repeated headers yield
+    /// one entry per value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    /// Number of values, counting each value of a repeated header.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the headers to a pingora `RequestHeader`. Headers already present in `header`
+    /// under the same name are replaced; repeated values are appended.
+    pub fn write_to_request(&self, header: &mut RequestHeader) -> pingora::Result<()> {
+        let mut written = HashSet::new();
+        for (key, value) in self.iter() {
+            if written.insert(key) {
+                header.insert_header(key.to_string(), value)?;
+            } else {
+                header.append_header(key.to_string(), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the headers to a pingora `ResponseHeader`. Headers already present in `header`
+    /// under the same name are replaced; repeated values are appended.
+    pub fn write_to_response(&self, header: &mut ResponseHeader) -> pingora::Result<()> {
+        let mut written = HashSet::new();
+        for (key, value) in self.iter() {
+            if written.insert(key) {
+                header.insert_header(key.to_string(), value)?;
+            } else {
+                header.append_header(key.to_string(), value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&RequestHeader> for Layer8Header {
+    fn from(header: &RequestHeader) -> Self {
+        let mut l8_header = Layer8Header::new();
+        for (key, value) in header.headers.iter() {
+            l8_header.append(key.as_str(), value.as_bytes());
+        }
+        l8_header
+    }
+}
+
+impl From<&ResponseHeader> for Layer8Header {
+    fn from(header: &ResponseHeader) -> Self {
+        let mut l8_header = Layer8Header::new();
+        for (key, value) in header.headers.iter() {
+            l8_header.append(key.as_str(), value.as_bytes());
+        }
+        l8_header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::http::{Method, StatusCode};
+    use super::*;
+
+    fn values<'a>(header: &'a Layer8Header, name: &'a str) -> Vec<&'a [u8]> {
+        header.get_all(name).collect()
+    }
+
+    #[test]
+    fn repeated_headers_keep_their_order() {
+        let mut header = Layer8Header::new();
+        header.append("Set-Cookie", "a=1");
+        header.append("Content-Type", "application/json");
+        header.append("set-cookie", "b=2");
+        header.append("SET-COOKIE", "c=3");
+
+        assert_eq!(header.get("set-cookie"), Some("a=1"));
+        assert_eq!(values(&header, "Set-Cookie"), [b"a=1", b"b=2", b"c=3"]);
+        assert_eq!(
+            header.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            ["set-cookie", "content-type", "set-cookie", "set-cookie"]
+        );
+        assert_eq!(header.len(), 4);
+
+        // replaced in place of the first value
+        header.insert("set-cookie", "d=4");
+        assert_eq!(
+            header.iter().collect::<Vec<_>>(),
+            [("set-cookie", &b"d=4"[..]), ("content-type", &b"application/json"[..])]
+        );
+
+        header.append("set-cookie", "e=5");
+        assert_eq!(header.remove("Set-Cookie"), [b"d=4".to_vec(), b"e=5".to_vec()]);
+        assert!(!header.contains_key("set-cookie"));
+        assert_eq!(header.len(), 1);
+    }
+
+    #[test]
+    fn non_utf8_values_are_kept() {
+        let mut header = Layer8Header::new();
+        header.append("x-raw", vec![b'a', 0xff, 0xfe]);
+
+        assert!(header.contains_key("x-raw"));
+        assert_eq!(header.get("x-raw"), None);
+        assert_eq!(header.get_bytes("X-Raw"), Some(&[b'a', 0xff, 0xfe][..]));
+
+        let mut response = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        header.write_to_response(&mut response).unwrap();
+        assert_eq!(response.headers.get("x-raw").unwrap().as_bytes(), [b'a', 0xff, 0xfe]);
+        assert_eq!(Layer8Header::from(&response), header);
+    }
+
+    #[test]
+    fn written_headers_replace_the_previous_values() {
+        let mut request = RequestHeader::build(Method::POST, b"/proxy", None).unwrap();
+        request.insert_header("cookie", "old=0").unwrap();
+        request.insert_header("host", "localhost").unwrap();
+
+        let mut header = Layer8Header::new();
+        header.append("Cookie", "a=1");
+        header.append("cookie", "b=2");
+        header.write_to_request(&mut request).unwrap();
+
+        let cookies: Vec<_> = request.headers.get_all("cookie").iter().map(|value| value.as_bytes()).collect();
+        assert_eq!(cookies, [b"a=1", b"b=2"]);
+        assert_eq!(request.headers.get("host").unwrap(), "localhost");
+
+        let read = Layer8Header::from(&request);
+        assert_eq!(values(&read, "cookie"), [b"a=1", b"b=2"]);
+    }
+}
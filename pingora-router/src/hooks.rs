@@ -0,0 +1,90 @@
+use pingora::http::RequestHeader;
+use crate::ctx::Layer8Context;
+use crate::handler::{APIHandler, APIHandlerResponse};
+
+/*
+ *  Proxied routes: instead of answering the request itself, a route may forward it upstream and
+ *  hook into the pingora phases of the proxy serving it. The proxy calls the matching `Router`
+ *  method from each phase, see `Router::proxy`.
+ */
+
+/// `UpstreamRequestHook` adjusts the request header sent upstream, e.g. to swap credentials.
+pub type UpstreamRequestHook<T> =
+    Box<dyn Fn(&T, &mut Layer8Context, &mut RequestHeader) -> pingora::Result<()> + Send + Sync>;
+
+/// `ResponseBodyHook` processes the complete upstream response body. It is not async because
+/// pingora's `response_body_filter` is not.
+pub type ResponseBodyHook<T> = Box<dyn Fn(&T, &mut Layer8Context) -> APIHandlerResponse + Send + Sync>;
+
+/// `ProxyHooks` holds the per-phase hooks of a proxied route. Every hook is optional.
+///
+/// - `request_filter`: runs once the request headers are read, after the middlewares' `before`
+///   hooks, e.g. to validate the request and pick the upstream. A non-`200 OK` response is sent
+///   to the client instead of proxying the request.
+/// - `upstream_request_filter`: adjusts the request header sent upstream.
+/// - `request_body_filter`: runs once the request body is complete. Its `200 OK` body replaces
+///   the body sent upstream; any other status aborts the request.
+/// - `response_body_filter`: runs once the upstream response body is complete. Its `200 OK` body
+///   replaces the body sent to the client; any other status aborts the request.
+///
+/// Without a body hook, the body is passed through unchanged.
+///
+/// # Example
+/// ```rust,ignore
+/// let hooks = ProxyHooks::new()
+///     .request_filter(resolve_upstream_handler)
+///     .request_body_filter(Box::new(|h, ctx| async move { h.encrypt_request(ctx).await }.boxed()))
+///     .response_body_filter(Box::new(|h, ctx| h.decrypt_response(ctx)));
+///
+/// router.proxy(Method::POST, "/tunnel".to_string(), hooks);
+/// ```
+pub struct ProxyHooks<T> {
+    pub(crate) request_filter: Option<APIHandler<T>>,
+    pub(crate) upstream_request_filter: Option<UpstreamRequestHook<T>>,
+    pub(crate) request_body_filter: Option<APIHandler<T>>,
+    pub(crate) response_body_filter: Option<ResponseBodyHook<T>>,
+}
+
+impl<T> Default for ProxyHooks<T> {
+    fn default() -> Self {
+        ProxyHooks {
+            request_filter: None,
+            upstream_request_filter: None,
+            request_body_filter: None,
+            response_body_filter: None,
+        }
+    }
+}
+
+impl<T> ProxyHooks<T> {
+    pub fn new() -> Self {
+        ProxyHooks::default()
+    }
+
+    pub fn request_filter(mut self, hook: APIHandler<T>) -> Self {
+        self.request_filter = Some(hook);
+        self
+    }
+
+    pub fn upstream_request_filter(mut self, hook: UpstreamRequestHook<T>) -> Self {
+        self.upstream_request_filter = Some(hook);
+        self
+    }
+
+    pub fn request_body_filter(mut self, hook: APIHandler<T>) -> Self {
+        self.request_body_filter = Some(hook);
+        self
+    }
+
+    pub fn response_body_filter(mut self, hook: ResponseBodyHook<T>) -> Self {
+        self.response_body_filter = Some(hook);
+        self
+    }
+
+    /// Splits the `request_filter` hook out as the route's handler chain: it runs like a
+    /// regular handler, behind the route's middlewares.
+    pub(crate) fn into_route_parts(mut self) -> (Box<[APIHandler<T>]>, ProxyHooks<T>) {
+        let handlers = self.request_filter.take().into_iter().collect();
+        (handlers, self)
+    }
+}
@@ -1,4 +1,14 @@
+pub mod body;
+pub mod error;
 pub mod ctx;
+pub mod extensions;
+pub mod extract;
 pub mod handler;
+pub mod header;
+pub mod hooks;
+pub mod middleware;
 mod utils;
 pub mod router;
+pub mod testing;
+pub mod timing;
+mod route;
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use pingora::http::Method;
+use crate::ctx::Layer8Context;
+use crate::handler::{APIHandler, APIHandlerResponse};
+use crate::hooks::ProxyHooks;
+
+/// `Middleware` wraps the handler chain of a route with `before` and `after` hooks.
+///
+/// - `before` runs ahead of the route's handlers, in registration order. Returning
+///   `Some(response)` short-circuits the request: the remaining `before` hooks and the handlers
+///   are skipped and `response` becomes the final response.
+/// - `after` runs once the final response is known, in reverse registration order, and may
+///   mutate it (e.g. add CORS headers to the context or rewrite the status). It only runs for
+///   the middlewares whose `before` hook has run.
+///
+/// Both hooks default to no-ops, so implementors only override what they need.
+///
+/// # Type Parameters
+/// - `T`: The router's handler type, shared with all route handlers.
+///
+/// # Example
+/// ```rust
+/// use futures::FutureExt;
+/// use futures::future::BoxFuture;
+/// use pingora::http::StatusCode;
+/// use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
+/// use pingora_router::handler::APIHandlerResponse;
+/// use pingora_router::middleware::Middleware;
+///
+/// struct RequireToken;
+///
+/// impl<T> Middleware<T> for RequireToken {
+///     fn before<'a>(&'a self, _h: &'a T, ctx: &'a mut Layer8Context) -> BoxFuture<'a, Option<APIHandlerResponse>> {
+///         async move {
+///             match ctx.get_request_header().get("authorization") {
+///                 Some(_) => None,
+///                 None => Some(APIHandlerResponse::new(StatusCode::UNAUTHORIZED, None)),
+///             }
+///         }.boxed()
+///     }
+/// }
+/// ```
+pub trait Middleware<T>: Send + Sync {
+    fn before<'a>(
+        &'a self,
+        _handler: &'a T,
+        _ctx: &'a mut Layer8Context,
+    ) -> BoxFuture<'a, Option<APIHandlerResponse>> {
+        Box::pin(async { None })
+    }
+
+    fn after<'a>(
+        &'a self,
+        _handler: &'a T,
+        _ctx: &'a mut Layer8Context,
+        _response: &'a mut APIHandlerResponse,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// `MiddlewareStack` is an ordered list of middlewares applied to a route.
+pub type MiddlewareStack<T> = Vec<Arc<dyn Middleware<T>>>;
+
+/// `GroupRoute` is a route registered on a `RouteGroup`, waiting to be merged into a `Router`.
+pub(crate) struct GroupRoute<T> {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) handlers: Box<[APIHandler<T>]>,
+    pub(crate) middlewares: MiddlewareStack<T>,
+    pub(crate) max_body_size: Option<usize>,
+    pub(crate) proxy: Option<ProxyHooks<T>>,
+}
+
+/// `RouteGroup` is a set of routes sharing a path prefix and a middleware stack.
+///
+/// Groups can be nested: the inner group's prefix is appended to the outer one, and the outer
+/// group's middlewares run before the inner group's.
+///
+/// A group may set a request body limit for its routes with `max_body_size`; a nested group's
+/// own limit wins over the outer one.
+///
+/// # Example
+/// ```ignore
+/// let mut api = RouteGroup::new("/api");
+/// api.middleware(Arc::new(RequireToken));
+/// api.post("/sessions/:id".to_string(), Box::new([session_handler]));
+///
+/// router.group(api); // registers `POST /api/sessions/:id` behind `RequireToken`
+/// ```
+pub struct RouteGroup<T> {
+    prefix: String,
+    middlewares: MiddlewareStack<T>,
+    max_body_size: Option<usize>,
+    routes: Vec<GroupRoute<T>>,
+}
+
+impl<T> RouteGroup<T> {
+    pub fn new(prefix: &str) -> Self {
+        RouteGroup {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            middlewares: Vec::new(),
+            max_body_size: None,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Sets the maximum request body size, in bytes, of every route of the group, overriding
+    /// the router's global limit.
+    pub fn max_body_size(&mut self, limit: usize) {
+        self.max_body_size = Some(limit);
+    }
+
+    /// Appends a middleware to the group's stack. It applies to every route of the group,
+    /// including routes registered before this call.
+    pub fn middleware(&mut self, middleware: Arc<dyn Middleware<T>>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Nests `group` under this group's prefix and middleware stack.
+    pub fn group(&mut self, group: RouteGroup<T>) {
+        self.routes.extend(group.into_routes());
+    }
+
+    pub fn post(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::POST, path, handlers);
+    }
+
+    pub fn get(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::GET, path, handlers);
+    }
+
+    pub fn put(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::PUT, path, handlers);
+    }
+
+    pub fn patch(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::PATCH, path, handlers);
+    }
+
+    pub fn delete(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::DELETE, path, handlers);
+    }
+
+    pub fn head(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::HEAD, path, handlers);
+    }
+
+    pub fn options(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::OPTIONS, path, handlers);
+    }
+
+    /// Registers handlers for any HTTP method, including extension methods.
+    pub fn route(&mut self, method: Method, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.routes.push(GroupRoute {
+            method,
+            path,
+            handlers,
+            middlewares: Vec::new(),
+            max_body_size: None,
+            proxy: None,
+        });
+    }
+
+    /// Registers a proxied route, see `Router::proxy`.
+    pub fn proxy(&mut self, method: Method, path: String, hooks: ProxyHooks<T>) {
+        let (handlers, hooks) = hooks.into_route_parts();
+        self.routes.push(GroupRoute {
+            method,
+            path,
+            handlers,
+            middlewares: Vec::new(),
+            max_body_size: None,
+            proxy: Some(hooks),
+        });
+    }
+
+    /// Resolves the group into routes with full paths and complete middleware stacks.
+    pub(crate) fn into_routes(self) -> Vec<GroupRoute<T>> {
+        let RouteGroup { prefix, middlewares, max_body_size, routes } = self;
+
+        routes
+            .into_iter()
+            .map(|route| {
+                let mut stack = middlewares.clone();
+                stack.extend(route.middlewares);
+
+                GroupRoute {
+                    method: route.method,
+                    path: format!("{}/{}", prefix, route.path.trim_start_matches('/')),
+                    handlers: route.handlers,
+                    middlewares: stack,
+                    max_body_size: route.max_body_size.or(max_body_size),
+                    proxy: route.proxy,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use pingora::http::StatusCode;
+    use super::*;
+    use crate::ctx::Layer8ContextTrait;
+    use crate::router::Router;
+    use crate::testing;
+
+    /// Appends `entry` to the `log` memory entry of `ctx`.
+    fn log(ctx: &mut Layer8Context, entry: String) {
+        let log = match ctx.get("log") {
+            Some(log) => format!("{}, {}", log, entry),
+            None => entry,
+        };
+        ctx.set("log".to_string(), log);
+    }
+
+    /// Logs its hooks, and answers `401 Unauthorized` from `before` when `stop` is set.
+    struct Record {
+        name: &'static str,
+        stop: bool,
+    }
+
+    fn record(name: &'static str) -> Arc<Record> {
+        Arc::new(Record { name, stop: false })
+    }
+
+    impl Middleware<()> for Record {
+        fn before<'a>(&'a self, _handler: &'a (), ctx: &'a mut Layer8Context) -> BoxFuture<'a, Option<APIHandlerResponse>> {
+            Box::pin(async move {
+                log(ctx, format!("before {}", self.name));
+                self.stop.then(|| APIHandlerResponse::new(StatusCode::UNAUTHORIZED, None))
+            })
+        }
+
+        fn after<'a>(
+            &'a self,
+            _handler: &'a (),
+            ctx: &'a mut Layer8Context,
+            response: &'a mut APIHandlerResponse,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move { log(ctx, format!("after {} {}", self.name, response.status.as_u16())) })
+        }
+    }
+
+    fn handler() -> Box<[APIHandler<()>]> {
+        Box::new([Box::new(|_, ctx| {
+            log(ctx, "handler".to_string());
+            Box::pin(async { APIHandlerResponse::new(StatusCode::OK, None) })
+        })])
+    }
+
+    fn call(router: &Router<()>, path: &str, body: &'static [u8]) -> (StatusCode, String) {
+        let mut ctx = Layer8Context::builder().method(Method::GET).path(path).body(body).build();
+        let response = block_on(testing::dispatch(router, &mut ctx));
+        (response.status, ctx.get("log").cloned().unwrap_or_default())
+    }
+
+    #[test]
+    fn hooks_wrap_the_handlers_from_the_outermost_stack() {
+        let mut inner = RouteGroup::new("/v1");
+        inner.middleware(record("inner"));
+        inner.get("/sessions".to_string(), handler());
+
+        let mut outer = RouteGroup::new("/api");
+        outer.get("/status".to_string(), handler());
+        outer.group(inner);
+        // applies to the routes registered before it too
+        outer.middleware(record("outer"));
+
+        let mut router = Router::new(());
+        router.group(outer);
+        router.middleware(record("global"));
+
+        assert_eq!(
+            call(&router, "/api/v1/sessions", b""),
+            (
+                StatusCode::OK,
+                "before global, before outer, before inner, handler, after inner 200, after outer 200, after global 200"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            call(&router, "/api/status", b""),
+            (StatusCode::OK, "before global, before outer, handler, after outer 200, after global 200".to_string())
+        );
+    }
+
+    #[test]
+    fn a_before_hook_stops_the_request() {
+        let mut group = RouteGroup::new("/api");
+        group.middleware(record("outer"));
+        group.middleware(Arc::new(Record { name: "auth", stop: true }));
+        group.middleware(record("inner"));
+        group.get("/sessions".to_string(), handler());
+
+        let mut router = Router::new(());
+        router.group(group);
+
+        // the handlers and the hooks after `auth` are skipped, `after` only unwinds entered hooks
+        assert_eq!(
+            call(&router, "/api/sessions", b""),
+            (StatusCode::UNAUTHORIZED, "before outer, before auth, after auth 401, after outer 401".to_string())
+        );
+    }
+
+    #[test]
+    fn nested_group_prefixes_are_joined() {
+        let mut inner = RouteGroup::new("/v1/");
+        inner.get("sessions/:id".to_string(), handler());
+
+        let mut outer = RouteGroup::new("/api/");
+        outer.group(inner);
+
+        let mut router = Router::new(());
+        router.group(outer);
+
+        assert_eq!(call(&router, "/api/v1/sessions/42", b"").0, StatusCode::OK);
+        assert_eq!(call(&router, "/api/sessions/42", b"").0, StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, "/v1/sessions/42", b"").0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn the_innermost_body_limit_wins() {
+        let mut inner = RouteGroup::new("/uploads");
+        inner.max_body_size(8);
+        inner.get("/small".to_string(), handler());
+
+        let mut outer = RouteGroup::new("/api");
+        outer.max_body_size(4);
+        outer.get("/status".to_string(), handler());
+        outer.group(inner);
+
+        let mut router = Router::new(());
+        router.max_body_size(2);
+        router.group(outer);
+        router.get("/other".to_string(), handler());
+
+        assert_eq!(call(&router, "/api/uploads/small", b"12345678").0, StatusCode::OK);
+        assert_eq!(call(&router, "/api/uploads/small", b"123456789").0, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(call(&router, "/api/status", b"1234").0, StatusCode::OK);
+        assert_eq!(call(&router, "/api/status", b"12345").0, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(call(&router, "/other", b"123").0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use crate::handler::APIHandler;
+use crate::hooks::ProxyHooks;
+use crate::middleware::MiddlewareStack;
+
+/// `Segment` is a single `/`-separated piece of a registered route pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Matches the segment literally, e.g. `proxy` in `/proxy/:backend_id`.
+    Static(String),
+    /// Matches exactly one segment and captures it, e.g. `:backend_id`.
+    Param(String),
+    /// Matches all remaining segments (possibly none) and captures them joined by `/`,
+    /// e.g. `*rest`. Only allowed as the last segment of a pattern.
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Lower rank means more specific. Used to order candidate routes.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+/// `RoutePattern` is a parsed route path such as `/sessions/:id` or `/proxy/:backend_id/*rest`.
+///
+/// Supported segments:
+/// - static segments, matched literally: `/healthcheck`
+/// - named params, matching exactly one segment: `/sessions/:id`
+/// - a trailing wildcard, matching the rest of the path: `/files/*path`
+///
+/// Empty segments are ignored, so `/a/b`, `/a/b/` and `/a//b` are the same path.
+#[derive(Debug, Clone)]
+pub(crate) struct RoutePattern {
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    /// Parses a route pattern. Any query string is ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics on an unnamed param (`/:`) or a wildcard that is not the last segment,
+    /// since both are programming errors in the route registration.
+    pub(crate) fn parse(pattern: &str) -> Self {
+        let path = pattern.split('?').next().unwrap_or(pattern);
+        let raw_segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let mut segments = Vec::with_capacity(raw_segments.len());
+        for (idx, segment) in raw_segments.iter().enumerate() {
+            if let Some(name) = segment.strip_prefix(':') {
+                assert!(!name.is_empty(), "route `{pattern}`: path params must be named");
+                segments.push(Segment::Param(name.to_string()));
+            } else if let Some(name) = segment.strip_prefix('*') {
+                assert!(
+                    idx == raw_segments.len() - 1,
+                    "route `{pattern}`: a wildcard is only allowed as the last segment"
+                );
+                let name = if name.is_empty() { "*" } else { name };
+                segments.push(Segment::Wildcard(name.to_string()));
+            } else {
+                segments.push(Segment::Static(segment.to_string()));
+            }
+        }
+
+        RoutePattern { segments }
+    }
+
+    /// Specificity of the pattern, compared segment by segment: a static segment wins over a
+    /// param, which wins over a wildcard. The first differing segment decides.
+    fn priority(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+
+    /// Two patterns are equivalent if they match exactly the same paths, regardless of the
+    /// names given to their params.
+    fn is_equivalent(&self, other: &RoutePattern) -> bool {
+        self.segments.len() == other.segments.len()
+            && self.segments.iter().zip(other.segments.iter()).all(|(a, b)| match (a, b) {
+                (Segment::Static(a), Segment::Static(b)) => a == b,
+                (Segment::Param(_), Segment::Param(_)) => true,
+                (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+                _ => false,
+            })
+    }
+
+    /// Matches `path` (without query string) against the pattern and returns the captured params.
+    pub(crate) fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut parts = path.split('/').filter(|s| !s.is_empty());
+
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Static(expected) => {
+                    if parts.next()? != expected {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), parts.next()?.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), parts.by_ref().collect::<Vec<&str>>().join("/"));
+                }
+            }
+        }
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(params)
+    }
+}
+
+/// `Route` binds a pattern to the handler chain and middleware stack registered for it.
+pub(crate) struct Route<T> {
+    pub(crate) pattern: RoutePattern,
+    pub(crate) handlers: Box<[APIHandler<T>]>,
+    pub(crate) middlewares: MiddlewareStack<T>,
+    /// Maximum request body size in bytes, overriding the router's global limit.
+    pub(crate) max_body_size: Option<usize>,
+    /// Phase hooks of a proxied route, `None` for a route answered by its handlers.
+    /// The `request_filter` hook is moved to `handlers`.
+    pub(crate) proxy: Option<ProxyHooks<T>>,
+}
+
+impl<T> Route<T> {
+    pub(crate) fn new(pattern: &str, handlers: Box<[APIHandler<T>]>) -> Self {
+        Route {
+            pattern: RoutePattern::parse(pattern),
+            handlers,
+            middlewares: Vec::new(),
+            max_body_size: None,
+            proxy: None,
+        }
+    }
+}
+
+/// `Routes` holds all routes registered for one HTTP method, ordered from the most to the
+/// least specific pattern so that the first match is always the best one.
+pub(crate) struct Routes<T> {
+    routes: Vec<Route<T>>,
+}
+
+impl<T> Default for Routes<T> {
+    fn default() -> Self {
+        Routes { routes: Vec::new() }
+    }
+}
+
+impl<T> Routes<T> {
+    /// Registers a route. Registering an equivalent pattern again replaces the previous route.
+    pub(crate) fn insert(&mut self, route: Route<T>) {
+        if let Some(existing) = self.routes.iter_mut().find(|r| r.pattern.is_equivalent(&route.pattern)) {
+            *existing = route;
+            return;
+        }
+
+        let priority = route.pattern.priority();
+        let idx = self.routes.partition_point(|r| r.pattern.priority() <= priority);
+        self.routes.insert(idx, route);
+    }
+
+    /// Sets the body limit of the route registered with an equivalent pattern.
+    /// Returns `false` if no such route exists.
+    pub(crate) fn set_max_body_size(&mut self, pattern: &str, limit: usize) -> bool {
+        let pattern = RoutePattern::parse(pattern);
+        match self.routes.iter_mut().find(|r| r.pattern.is_equivalent(&pattern)) {
+            Some(route) => {
+                route.max_body_size = Some(limit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finds the most specific route matching `path` along with its captured params.
+    pub(crate) fn find(&self, path: &str) -> Option<(&Route<T>, HashMap<String, String>)> {
+        self.routes
+            .iter()
+            .find_map(|route| route.pattern.matches(path).map(|params| (route, params)))
+    }
+
+    pub(crate) fn contains(&self, path: &str) -> bool {
+        self.find(path).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params: Vec<(String, String)> = RoutePattern::parse(pattern).matches(path)?.into_iter().collect();
+        params.sort();
+        Some(params)
+    }
+
+    fn param(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    fn routes(patterns: &[&str]) -> Routes<()> {
+        let mut routes = Routes::default();
+        for pattern in patterns {
+            routes.insert(Route::new(pattern, Box::new([])));
+        }
+        routes
+    }
+
+    /// The pattern of the route `routes` picks for `path`.
+    fn found(routes: &Routes<()>, path: &str) -> Option<Vec<Segment>> {
+        routes.find(path).map(|(route, _)| route.pattern.segments.clone())
+    }
+
+    fn segments(pattern: &str) -> Option<Vec<Segment>> {
+        Some(RoutePattern::parse(pattern).segments)
+    }
+
+    #[test]
+    fn static_segments_match_literally() {
+        assert_eq!(captures("/healthcheck", "/healthcheck"), Some(vec![]));
+        assert_eq!(captures("/healthcheck", "/healthcheck/x"), None);
+        assert_eq!(captures("/healthcheck", "/Healthcheck"), None);
+        assert_eq!(captures("/", "/"), Some(vec![]));
+        assert_eq!(captures("/", "/a"), None);
+    }
+
+    #[test]
+    fn params_capture_exactly_one_segment() {
+        assert_eq!(captures("/sessions/:id", "/sessions/42"), Some(vec![param("id", "42")]));
+        assert_eq!(
+            captures("/a/:x/b/:y", "/a/1/b/2"),
+            Some(vec![param("x", "1"), param("y", "2")])
+        );
+        assert_eq!(captures("/sessions/:id", "/sessions"), None);
+        assert_eq!(captures("/sessions/:id", "/sessions/42/keys"), None);
+    }
+
+    #[test]
+    fn wildcards_capture_the_rest_of_the_path() {
+        assert_eq!(captures("/files/*path", "/files/a/b/c"), Some(vec![param("path", "a/b/c")]));
+        assert_eq!(captures("/files/*path", "/files/a"), Some(vec![param("path", "a")]));
+        assert_eq!(captures("/files/*path", "/files"), Some(vec![param("path", "")]));
+        assert_eq!(captures("/files/*", "/files/a/b"), Some(vec![param("*", "a/b")]));
+        assert_eq!(captures("/files/*path", "/other/a"), None);
+    }
+
+    #[test]
+    fn trailing_slashes_and_empty_segments_are_ignored() {
+        assert_eq!(captures("/a/b", "/a/b/"), Some(vec![]));
+        assert_eq!(captures("/a/b", "//a//b"), Some(vec![]));
+        assert_eq!(captures("/a/b/", "/a/b"), Some(vec![]));
+        assert_eq!(captures("/sessions/:id", "/sessions//42/"), Some(vec![param("id", "42")]));
+        assert_eq!(captures("/files/*path", "/files//a//b/"), Some(vec![param("path", "a/b")]));
+    }
+
+    #[test]
+    fn the_query_of_a_pattern_is_ignored() {
+        assert_eq!(captures("/init-tunnel?backend_url=x", "/init-tunnel"), Some(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "path params must be named")]
+    fn unnamed_params_are_refused() {
+        RoutePattern::parse("/sessions/:");
+    }
+
+    #[test]
+    #[should_panic(expected = "only allowed as the last segment")]
+    fn wildcards_must_be_last() {
+        RoutePattern::parse("/files/*path/meta");
+    }
+
+    #[test]
+    fn the_most_specific_route_wins() {
+        // registration order doesn't matter
+        let routes = routes(&["/sessions/*rest", "/sessions/:id", "/sessions/new"]);
+
+        assert_eq!(found(&routes, "/sessions/new"), segments("/sessions/new"));
+        assert_eq!(found(&routes, "/sessions/42"), segments("/sessions/:id"));
+        assert_eq!(found(&routes, "/sessions/42/keys"), segments("/sessions/*rest"));
+        assert_eq!(found(&routes, "/sessions"), segments("/sessions/*rest"));
+        assert_eq!(found(&routes, "/other"), None);
+    }
+
+    #[test]
+    fn the_first_differing_segment_decides() {
+        let routes = routes(&["/:kind/b/c", "/a/:x/c"]);
+
+        assert_eq!(found(&routes, "/a/b/c"), segments("/a/:x/c"));
+        assert_eq!(found(&routes, "/z/b/c"), segments("/:kind/b/c"));
+    }
+
+    #[test]
+    fn an_equivalent_pattern_replaces_the_route() {
+        let routes = routes(&["/sessions/:id", "/sessions/:session_id"]);
+
+        assert_eq!(routes.routes.len(), 1);
+        let (_, params) = routes.find("/sessions/42").unwrap();
+        assert_eq!(params.get("session_id").map(String::as_str), Some("42"));
+    }
+}
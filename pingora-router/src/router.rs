@@ -1,7 +1,15 @@
 use std::collections::HashMap;
-use pingora::http::{Method, StatusCode};
+use std::sync::Arc;
+use pingora::http::{Method, RequestHeader, StatusCode};
+use pingora::proxy::Session;
 use crate::ctx::{Layer8Context, Layer8ContextTrait};
+use crate::error::Layer8Error;
+use crate::timing::Phases;
+use crate::extract::IntoErrorResponse;
 use crate::handler::{APIHandler, APIHandlerResponse};
+use crate::hooks::ProxyHooks;
+use crate::middleware::{Middleware, MiddlewareStack, RouteGroup};
+use crate::route::{Route, Routes};
 
 /// `Router` is a generic struct that manages HTTP route registration and handler dispatching.
 ///
@@ -10,36 +18,73 @@ use crate::handler::{APIHandler, APIHandlerResponse};
 ///
 /// # Fields
 /// - `handler`: The main handler instance shared with all route handlers.
-/// - `_groups`: Reserved for future use (e.g., route grouping or middleware).
-/// - `posts`, `gets`, `puts`, `deletes`: Maps of HTTP method and path to arrays of handler functions.
+/// - `middlewares`: Global middleware stack, applied to every route ahead of the route's own stack.
+/// - `routes`: Routes of each HTTP method mapped to arrays of handler functions.
+/// - `max_body_size`: Global request body limit, applied to routes without their own limit.
 ///
 /// # Usage
-/// Register handlers for specific HTTP methods and paths using the `post`, `get`, `put`, and `delete` methods.
+/// Register handlers for specific HTTP methods and paths using `route`, or the `post`, `get`,
+/// `put`, `patch`, `delete`, `head` and `options` shortcuts.
 /// Call `call_handler` to dispatch a request to the appropriate handler(s) based on method and path.
 ///
+/// Paths may contain named params (`:name`, matching one segment) and a trailing wildcard
+/// (`*name`, matching the rest of the path). When several routes match a path, the most
+/// specific one wins: static segments beat params, and params beat wildcards, compared from
+/// left to right. Captured values are available to handlers via `Layer8ContextTrait::path_param`.
+///
+/// Routes sharing a prefix and a middleware stack (auth, CORS, rate limits, ...) can be
+/// registered together with a `RouteGroup` and `group`. See `Middleware` for the hooks order.
+///
+/// Routes registered with `proxy` are forwarded upstream instead of being answered by handlers.
+/// The proxy serving them calls `request_filter`, `upstream_request_filter`,
+/// `request_body_filter` and `response_body_filter` from the pingora phases of the same name,
+/// which run the route's `ProxyHooks`.
+///
+/// Request bodies can be limited globally with `max_body_size`, per route with
+/// `route_max_body_size`, or per group with `RouteGroup::max_body_size`. Read the body with
+/// `read_request_body` to enforce the limit while streaming: an oversized body is answered with
+/// `413 Payload Too Large` before it is fully read.
+///
+/// Method handling:
+/// - A path registered under other methods only answers `405 Method Not Allowed` with an
+///   `Allow` header listing them; a path unknown to every method answers `404 Not Found`.
+///   Both come with a `Layer8Error` problem body.
+/// - `HEAD` falls back to the `GET` route when no `HEAD` route is registered. The `GET`
+///   handlers run as usual, the body is dropped and its length is sent as `Content-Length`.
+/// - `OPTIONS` answers `204 No Content` (with `Allow` for known paths) unless an `OPTIONS`
+///   route is registered.
+///
 /// # Example
-/// ```rust
+/// ```ignore
 /// let mut router = Router::new(handler);
 /// router.post("/example".to_string(), Box::new([example_handler]));
+/// router.get("/sessions/:id".to_string(), Box::new([session_handler]));
+/// router.patch("/sessions/:id".to_string(), Box::new([update_session_handler]));
+/// router.post("/proxy/:backend_id/*rest".to_string(), Box::new([proxy_handler]));
+/// router.proxy(Method::POST, "/tunnel".to_string(), ProxyHooks::new().request_filter(resolve_upstream));
+///
+/// let mut admin = RouteGroup::new("/admin");
+/// admin.middleware(Arc::new(RequireToken));
+/// admin.delete("/sessions/:id".to_string(), Box::new([delete_session_handler]));
+/// router.group(admin);
+///
+/// router.max_body_size(1024 * 1024);
+/// router.route_max_body_size(Method::POST, "/example", 4 * 1024);
 /// ```
 pub struct Router<T> {
     handler: T,
-    _groups: Vec<String>, // placeholder for later use
-    posts: HashMap<String, Box<[APIHandler<T>]>>,
-    gets: HashMap<String, Box<[APIHandler<T>]>>,
-    puts: HashMap<String, Box<[APIHandler<T>]>>,
-    deletes: HashMap<String, Box<[APIHandler<T>]>>,
+    middlewares: MiddlewareStack<T>,
+    routes: HashMap<Method, Routes<T>>,
+    max_body_size: Option<usize>,
 }
 
 impl<T> Router<T> {
     pub fn new(handler: T) -> Self {
         Router {
             handler,
-            _groups: Vec::new(),
-            posts: HashMap::new(),
-            gets: HashMap::new(),
-            puts: HashMap::new(),
-            deletes: HashMap::new(),
+            middlewares: Vec::new(),
+            routes: HashMap::new(),
+            max_body_size: None,
         }
     }
 
@@ -52,75 +97,485 @@ impl<T> Router<T> {
     ///
     /// # Returns
     ///
-    /// `true` if a handler exists for the specified method and path, or if the method is OPTIONS;
-    /// otherwise, `false`.
+    /// `true` if a handler exists for the specified method and path, if the method is HEAD and a
+    /// GET handler exists, or if the method is OPTIONS; otherwise, `false`.
     pub fn contains(&self, method: &Method, path: &str) -> bool {
         match *method {
-            Method::POST => self.posts.contains_key(path),
-            Method::GET => self.gets.contains_key(path),
-            Method::PUT => self.puts.contains_key(path),
-            Method::DELETE => self.deletes.contains_key(path),
             Method::OPTIONS => true,
-            _ => false,
+            _ => self.get_route(method, path).is_some(),
         }
     }
 
-    fn get_handlers(&self, method: &Method, path: &str) -> Option<&Box<[APIHandler<T>]>> {
-        match *method {
-            Method::POST => self.posts.get(path),
-            Method::GET => self.gets.get(path),
-            Method::PUT => self.puts.get(path),
-            Method::DELETE => self.deletes.get(path),
-            _ => None,
+    /// Returns the methods a path can be called with, in a stable order, as sent in the `Allow`
+    /// header. Empty if no route matches the path.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods: Vec<Method> = self.routes
+            .iter()
+            .filter(|(_, routes)| routes.contains(path))
+            .map(|(method, _)| method.clone())
+            .collect();
+
+        if methods.is_empty() {
+            return methods;
+        }
+
+        if methods.contains(&Method::GET) && !methods.contains(&Method::HEAD) {
+            methods.push(Method::HEAD);
+        }
+        if !methods.contains(&Method::OPTIONS) {
+            methods.push(Method::OPTIONS);
+        }
+
+        methods.sort_by(|a, b| method_order(a).cmp(&method_order(b)).then_with(|| a.as_str().cmp(b.as_str())));
+        methods
+    }
+
+    /// Returns the `Allow` header value of a path, its allowed methods comma separated, or `None`
+    /// if no route matches the path. Proxies also send it as `Access-Control-Allow-Methods`.
+    pub fn allow(&self, path: &str) -> Option<String> {
+        let methods = self.allowed_methods(path);
+        if methods.is_empty() {
+            return None;
+        }
+        Some(methods.iter().map(Method::as_str).collect::<Vec<&str>>().join(", "))
+    }
+
+    fn get_route(&self, method: &Method, path: &str) -> Option<(&Route<T>, HashMap<String, String>)> {
+        let found = self.routes.get(method).and_then(|routes| routes.find(path));
+        match (found, method) {
+            (None, &Method::HEAD) => self.routes.get(&Method::GET).and_then(|routes| routes.find(path)),
+            (found, _) => found,
+        }
+    }
+
+    /// Returns the request body limit for the given method and path: the limit of the matching
+    /// route if it has one, otherwise the global limit. `None` means unlimited.
+    pub fn body_limit(&self, method: &Method, path: &str) -> Option<usize> {
+        self.get_route(method, path)
+            .and_then(|(route, _)| route.max_body_size)
+            .or(self.max_body_size)
+    }
+
+    /// Reads the request body into `ctx`, enforcing `body_limit` for the request's route.
+    ///
+    /// Returns `Ok(None)` once the body is fully read. If the body is larger than the limit,
+    /// reading stops early and the `payload_too_large` problem response to send is returned;
+    /// the connection should not be reused afterwards.
+    pub async fn read_request_body(
+        &self,
+        session: &mut Session,
+        ctx: &mut Layer8Context,
+    ) -> pingora::Result<Option<APIHandlerResponse>> {
+        let limit = self.body_limit(&ctx.method(), &ctx.path());
+        ctx.timings_mut().start(Phases::BODY_READ);
+        let within_limit = ctx.read_request_body_with_limit(session, limit).await?;
+        ctx.timings_mut().stop(Phases::BODY_READ);
+
+        if within_limit {
+            return Ok(None);
         }
+        Ok(Some(payload_too_large(limit.unwrap_or_default(), ctx)))
+    }
+
+    /// Checks a body that is already in `ctx` against `body_limit`, returning the
+    /// `413 Payload Too Large` response if it is larger.
+    pub(crate) fn check_body_size(&self, ctx: &mut Layer8Context) -> Option<APIHandlerResponse> {
+        let limit = self.body_limit(&ctx.method(), &ctx.path())?;
+        if ctx.get_request_body().len() > limit {
+            return Some(payload_too_large(limit, ctx));
+        }
+        None
     }
 
     pub async fn call_handler(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        let mut response = self.dispatch(ctx).await;
+
+        // HEAD responses carry the headers of the equivalent GET response, but no body
+        if ctx.method() == Method::HEAD {
+            let length = response.body.take().map(|body| body.len()).unwrap_or_default();
+            ctx.insert_response_header("Content-Length", &length.to_string());
+        }
+
+        response
+    }
+
+    async fn dispatch(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
         let method = ctx.method();
         let path = ctx.path();
 
+        if let Some((route, path_params)) = self.get_route(&method, &path) {
+            ctx.request.summary.path_params = path_params;
+
+            let stack: Vec<&Arc<dyn Middleware<T>>> = self.middlewares
+                .iter()
+                .chain(route.middlewares.iter())
+                .collect();
+
+            // run `before` hooks until one of them short-circuits the request
+            let mut entered = 0;
+            let mut early_response = None;
+            for middleware in stack.iter() {
+                entered += 1;
+                if let Some(response) = middleware.before(&self.handler, ctx).await {
+                    early_response = Some(response);
+                    break;
+                }
+            }
+
+            let mut response = match early_response {
+                Some(response) => response,
+                None => self.run_handlers(route, ctx).await,
+            };
+
+            // `after` hooks unwind in reverse order, only for middlewares that were entered
+            for middleware in stack[..entered].iter().rev() {
+                middleware.after(&self.handler, ctx, &mut response).await;
+            }
+
+            return response;
+        }
+
+        let allow = self.allow(&path);
+        if let Some(allow) = allow.as_deref() {
+            ctx.insert_response_header("Allow", allow);
+        }
+
         if method == Method::OPTIONS {
-            return APIHandlerResponse::new(StatusCode::NO_CONTENT, None, None);
+            return APIHandlerResponse::new(StatusCode::NO_CONTENT, None);
+        }
+        if allow.is_some() {
+            return Layer8Error::MethodNotAllowed.into_error_response(ctx);
         }
 
-        if let Some(handlers) = self.get_handlers(&method, &path) {
-            let mut response = APIHandlerResponse::new(StatusCode::OK, None, None);
-            for handler in handlers.iter() {
-                response = handler(&self.handler, ctx).await;
-                if response.status != StatusCode::OK {
-                    return response;
-                }
+        Layer8Error::NotFound.into_error_response(ctx)
+    }
 
-                if response.body != None {
-                    ctx.set_response_body(response.body.clone().unwrap());
-                }
+    /// Runs the `request_filter` phase.
+    ///
+    /// Returns the response to send to the client, or `None` if the request should be proxied
+    /// upstream: the route is a proxied one, and its middlewares and `request_filter` hook let
+    /// the request through with `200 OK`. Routes answered by handlers, unknown paths and
+    /// disallowed methods always return a response, as with `call_handler`.
+    pub async fn request_filter(&self, ctx: &mut Layer8Context) -> Option<APIHandlerResponse> {
+        let proxied = self.get_proxy_hooks(ctx).is_some();
+        let response = self.call_handler(ctx).await;
+        if proxied && response.status == StatusCode::OK {
+            return None;
+        }
+        Some(response)
+    }
+
+    /// Runs the `upstream_request_filter` hook of the request's proxied route, if any.
+    pub fn upstream_request_filter(
+        &self,
+        ctx: &mut Layer8Context,
+        upstream_request: &mut RequestHeader,
+    ) -> pingora::Result<()> {
+        match self.get_proxy_hooks(ctx).and_then(|hooks| hooks.upstream_request_filter.as_ref()) {
+            Some(hook) => hook(&self.handler, ctx, upstream_request),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the `request_body_filter` hook of the request's proxied route once the request body
+    /// is complete. Returns `None` when the route has no such hook: the body is passed through.
+    pub async fn request_body_filter(&self, ctx: &mut Layer8Context) -> Option<APIHandlerResponse> {
+        let hook = self.get_proxy_hooks(ctx)?.request_body_filter.as_ref()?;
+        Some(hook(&self.handler, ctx).await)
+    }
+
+    /// Runs the `response_body_filter` hook of the request's proxied route once the upstream
+    /// response body is complete. Returns `None` when the route has no such hook: the body is
+    /// passed through.
+    pub fn response_body_filter(&self, ctx: &mut Layer8Context) -> Option<APIHandlerResponse> {
+        let hook = self.get_proxy_hooks(ctx)?.response_body_filter.as_ref()?;
+        Some(hook(&self.handler, ctx))
+    }
+
+    /// Whether the request's proxied route has a `request_body_filter` hook, which needs the
+    /// complete request body. Without one, the body can be sent upstream as it is received.
+    pub fn has_request_body_filter(&self, ctx: &Layer8Context) -> bool {
+        self.get_proxy_hooks(ctx)
+            .is_some_and(|hooks| hooks.request_body_filter.is_some())
+    }
+
+    /// Whether the request's proxied route has a `response_body_filter` hook, which needs the
+    /// complete upstream response body. Without one, the body can be sent as it is received.
+    pub fn has_response_body_filter(&self, ctx: &Layer8Context) -> bool {
+        self.get_proxy_hooks(ctx)
+            .is_some_and(|hooks| hooks.response_body_filter.is_some())
+    }
+
+    fn get_proxy_hooks(&self, ctx: &Layer8Context) -> Option<&ProxyHooks<T>> {
+        self.get_route(&ctx.method(), &ctx.path())
+            .and_then(|(route, _)| route.proxy.as_ref())
+    }
+
+    async fn run_handlers(&self, route: &Route<T>, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        let mut response = APIHandlerResponse::new(StatusCode::OK, None);
+        for handler in route.handlers.iter() {
+            response = handler(&self.handler, ctx).await;
+            if response.status != StatusCode::OK {
+                return response;
             }
 
-            response
-        } else {
-            APIHandlerResponse::new(StatusCode::NOT_FOUND, None, None)
+            if let Some(body) = response.body.as_ref() {
+                ctx.set_response_body(body.clone());
+            }
         }
+
+        response
+    }
+
+    /// Appends a middleware to the global stack. Global middlewares apply to every route,
+    /// including routes registered before this call, and run before the route's own stack.
+    pub fn middleware(&mut self, middleware: Arc<dyn Middleware<T>>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Registers all routes of `group` with the group's prefix, middleware stack and body limit.
+    pub fn group(&mut self, group: RouteGroup<T>) {
+        for group_route in group.into_routes() {
+            let mut route = Route::new(&group_route.path, group_route.handlers);
+            route.middlewares = group_route.middlewares;
+            route.max_body_size = group_route.max_body_size;
+            route.proxy = group_route.proxy;
+            self.add(group_route.method, route);
+        }
+    }
+
+    fn add(&mut self, method: Method, route: Route<T>) {
+        self.routes.entry(method).or_default().insert(route);
+    }
+
+    /// Sets the global maximum request body size, in bytes. It applies to every route without
+    /// a limit of its own.
+    pub fn max_body_size(&mut self, limit: usize) {
+        self.max_body_size = Some(limit);
+    }
+
+    /// Sets the maximum request body size, in bytes, of an already registered route, overriding
+    /// the global limit. Registering the route again resets its limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route is registered for `method` and `path`, since this is a programming
+    /// error in the route registration.
+    pub fn route_max_body_size(&mut self, method: Method, path: &str, limit: usize) {
+        let found = self.routes
+            .get_mut(&method)
+            .is_some_and(|routes| routes.set_max_body_size(path, limit));
+        assert!(found, "route `{method} {path}` must be registered before setting its body limit");
+    }
+
+    /// Registers handlers for any HTTP method, including extension methods.
+    pub fn route(&mut self, method: Method, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.add(method, Route::new(&path, handlers));
     }
 
-    fn get_base_path(&self, path: &str) -> String {
-        path.split('?').next().unwrap_or(path).to_string()
+    /// Registers a proxied route: the request is forwarded upstream, and `hooks` run from the
+    /// proxy's phases. See `ProxyHooks`.
+    pub fn proxy(&mut self, method: Method, path: String, hooks: ProxyHooks<T>) {
+        let (handlers, hooks) = hooks.into_route_parts();
+        let mut route = Route::new(&path, handlers);
+        route.proxy = Some(hooks);
+        self.add(method, route);
     }
 
     pub fn post(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
-        self.posts.insert(self.get_base_path(&path), handlers);
+        self.route(Method::POST, path, handlers);
     }
 
     pub fn get(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
-        self.gets.insert(self.get_base_path(&path), handlers);
+        self.route(Method::GET, path, handlers);
     }
 
     pub fn put(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
-        self.puts.insert(self.get_base_path(&path), handlers);
+        self.route(Method::PUT, path, handlers);
+    }
+
+    pub fn patch(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::PATCH, path, handlers);
     }
 
     pub fn delete(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
-        self.deletes.insert(self.get_base_path(&path), handlers);
+        self.route(Method::DELETE, path, handlers);
+    }
+
+    /// Registers an explicit `HEAD` route, overriding the fallback to the `GET` route.
+    pub fn head(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::HEAD, path, handlers);
+    }
+
+    /// Registers an explicit `OPTIONS` route, overriding the default `204 No Content` answer.
+    pub fn options(&mut self, path: String, handlers: Box<[APIHandler<T>]>) {
+        self.route(Method::OPTIONS, path, handlers);
+    }
+}
+
+/// The response to a request body larger than `limit` bytes.
+fn payload_too_large(limit: usize, ctx: &mut Layer8Context) -> APIHandlerResponse {
+    Layer8Error::PayloadTooLarge(limit).into_error_response(ctx)
+}
+
+/// Position of a method in the `Allow` header; extension methods go last.
+fn method_order(method: &Method) -> u8 {
+    match *method {
+        Method::GET => 0,
+        Method::HEAD => 1,
+        Method::POST => 2,
+        Method::PUT => 3,
+        Method::PATCH => 4,
+        Method::DELETE => 5,
+        Method::OPTIONS => 6,
+        Method::CONNECT => 7,
+        Method::TRACE => 8,
+        _ => 9,
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use super::*;
+    use crate::testing;
+
+    fn respond(body: &'static str) -> APIHandler<()> {
+        Box::new(move |_, _| Box::pin(async move { APIHandlerResponse::new(StatusCode::OK, Some(body.into())) }))
+    }
+
+    /// Answers with the path param `name`.
+    fn path_param(name: &'static str) -> APIHandler<()> {
+        Box::new(move |_, ctx| {
+            let value = ctx.path_param(name).cloned().unwrap_or_default();
+            Box::pin(async move { APIHandlerResponse::new(StatusCode::OK, Some(value.into_bytes())) })
+        })
+    }
+
+    fn call(router: &Router<()>, method: Method, path: &str) -> (APIHandlerResponse, Layer8Context) {
+        let mut ctx = Layer8Context::builder().method(method).path(path).build();
+        let response = block_on(testing::dispatch(router, &mut ctx));
+        (response, ctx)
+    }
+
+    fn body(response: &APIHandlerResponse) -> &str {
+        std::str::from_utf8(response.body.as_deref().unwrap_or_default()).unwrap()
+    }
+
+    #[test]
+    fn dispatches_to_the_most_specific_route() {
+        let mut router = Router::new(());
+        router.get("/sessions/*rest".to_string(), Box::new([respond("wildcard")]));
+        router.get("/sessions/:id".to_string(), Box::new([path_param("id")]));
+        router.get("/sessions/new".to_string(), Box::new([respond("static")]));
+
+        assert_eq!(body(&call(&router, Method::GET, "/sessions/new").0), "static");
+        assert_eq!(body(&call(&router, Method::GET, "/sessions/42").0), "42");
+        assert_eq!(body(&call(&router, Method::GET, "/sessions/42/keys").0), "wildcard");
+    }
+
+    #[test]
+    fn path_params_reach_the_handlers() {
+        let mut router = Router::new(());
+        router.post("/proxy/:backend_id/*rest".to_string(), Box::new([path_param("rest")]));
+
+        let (response, ctx) = call(&router, Method::POST, "/proxy/b1/api/v1/users/?page=2");
+        assert_eq!(body(&response), "api/v1/users");
+        assert_eq!(ctx.path_param("backend_id").map(String::as_str), Some("b1"));
+        assert_eq!(ctx.param("page").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn trailing_slashes_reach_the_same_route() {
+        let mut router = Router::new(());
+        router.get("/healthcheck".to_string(), Box::new([respond("ok")]));
+
+        for path in ["/healthcheck", "/healthcheck/", "//healthcheck"] {
+            assert_eq!(call(&router, Method::GET, path).0.status, StatusCode::OK, "{}", path);
+        }
+        assert_eq!(call(&router, Method::GET, "/healthcheck/x").0.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn a_wrong_method_is_not_allowed() {
+        let mut router = Router::new(());
+        router.get("/sessions/:id".to_string(), Box::new([respond("get")]));
+        router.delete("/sessions/:id".to_string(), Box::new([respond("delete")]));
+        router.post("/sessions/new".to_string(), Box::new([respond("post")]));
+
+        let (response, ctx) = call(&router, Method::PUT, "/sessions/42");
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(ctx.get_response_header().get("allow"), Some("GET, HEAD, DELETE, OPTIONS"));
+        assert_eq!(ctx.get_response_header().get("content-type"), Some(crate::error::PROBLEM_JSON));
+        assert!(body(&response).contains("\"code\":\"method_not_allowed\""));
+
+        // every route matching the path counts, `/sessions/:id` included
+        let (response, ctx) = call(&router, Method::PUT, "/sessions/new");
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(ctx.get_response_header().get("allow"), Some("GET, HEAD, POST, DELETE, OPTIONS"));
+    }
 
+    #[test]
+    fn an_unknown_path_is_not_found() {
+        let mut router = Router::new(());
+        router.get("/sessions/:id".to_string(), Box::new([respond("get")]));
+
+        let (response, ctx) = call(&router, Method::GET, "/unknown");
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(!ctx.get_response_header().contains_key("allow"));
+        assert_eq!(router.allow("/unknown"), None);
+    }
+
+    #[test]
+    fn head_is_served_by_the_get_handlers_without_a_body() {
+        let mut router = Router::new(());
+        router.get("/healthcheck".to_string(), Box::new([respond("healthy")]));
+        router.get("/status".to_string(), Box::new([respond("get")]));
+        router.head("/status".to_string(), Box::new([respond("")]));
+
+        let (response, ctx) = call(&router, Method::HEAD, "/healthcheck");
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, None);
+        assert_eq!(ctx.get_response_header().get("content-length"), Some("7"));
+        // the GET handler did run
+        assert_eq!(ctx.get_response_body(), b"healthy");
+
+        // an explicit HEAD route wins
+        let (response, ctx) = call(&router, Method::HEAD, "/status");
+        assert_eq!(response.body, None);
+        assert_eq!(ctx.get_response_header().get("content-length"), Some("0"));
+        assert_eq!(router.allow("/status").as_deref(), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn options_answers_the_allowed_methods() {
+        let mut router = Router::new(());
+        router.post("/init-tunnel".to_string(), Box::new([respond("post")]));
+        router.options("/custom".to_string(), Box::new([respond("custom")]));
+
+        let (response, ctx) = call(&router, Method::OPTIONS, "/init-tunnel");
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(ctx.get_response_header().get("allow"), Some("POST, OPTIONS"));
+        assert!(router.contains(&Method::OPTIONS, "/init-tunnel"));
+
+        let (response, ctx) = call(&router, Method::OPTIONS, "/unknown");
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert!(!ctx.get_response_header().contains_key("allow"));
+
+        assert_eq!(body(&call(&router, Method::OPTIONS, "/custom").0), "custom");
+    }
+
+    #[test]
+    fn handlers_run_in_order_until_one_fails() {
+        let fail: APIHandler<()> = Box::new(|_, _| Box::pin(async { APIHandlerResponse::new(StatusCode::BAD_REQUEST, None) }));
+        let mut router = Router::new(());
+        router.get("/chain".to_string(), Box::new([respond("first"), respond("second")]));
+        router.get("/fail".to_string(), Box::new([fail, respond("unreachable")]));
+
+        let (response, ctx) = call(&router, Method::GET, "/chain");
+        assert_eq!(body(&response), "second");
+        assert_eq!(ctx.get_response_body(), b"second");
+
+        assert_eq!(call(&router, Method::GET, "/fail").0.status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::ctx::{Layer8Context, Layer8ContextTrait};
+use crate::handler::APIHandlerResponse;
+use crate::router::Router;
+
+/*
+ *  Helpers to exercise a `Router` and its handlers without a pingora `Session` or open sockets.
+ *  Build the context with `Layer8Context::builder()`.
+ */
+
+/// Dispatches `ctx` to `router` the way the proxies' `request_filter` does: the correlation id
+/// is set first (from `x-correlation-id`/`x-request-id`, or a new one), then the body is checked
+/// against the route's body limit (`413 Payload Too Large` if larger), then `call_handler` runs.
+///
+/// The context is borrowed, so response headers and memory entries set by handlers and
+/// middlewares can be inspected afterwards.
+///
+/// # Example
+/// ```rust
+/// #[tokio::test]
+/// async fn healthcheck_answers_ok() {
+///     let router = build_router(handler);
+///     let mut ctx = Layer8Context::builder()
+///         .method(Method::GET)
+///         .path("/healthcheck")
+///         .build();
+///
+///     let response = testing::dispatch(&router, &mut ctx).await;
+///     assert_eq!(response.status, StatusCode::OK);
+/// }
+/// ```
+pub async fn dispatch<T>(router: &Router<T>, ctx: &mut Layer8Context) -> APIHandlerResponse {
+    ctx.set_correlation_id();
+    if let Some(response) = router.check_body_size(ctx) {
+        return response;
+    }
+    router.call_handler(ctx).await
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Names of the phases timed by the proxies, as they appear in logs and `Server-Timing`.
+pub struct Phases;
+
+impl Phases {
+    /// Reading the request body from the client.
+    pub const BODY_READ: &'static str = "body_read";
+    /// Fetching the nTor certificate from the Layer8 authentication server.
+    pub const AUTH_SERVER: &'static str = "auth";
+    /// Connecting to the proxy's upstream, retries included.
+    pub const UPSTREAM_CONNECT: &'static str = "upstream_connect";
+    /// From the upstream connection to the upstream response header.
+    pub const UPSTREAM: &'static str = "upstream";
+    pub const DECRYPTION: &'static str = "decryption";
+    /// Round trip of the request rebuilt for the origin backend.
+    pub const BACKEND: &'static str = "backend";
+    pub const ENCRYPTION: &'static str = "encryption";
+}
+
+/// Duration of a phase, and when it started relative to the request being received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub offset: Duration,
+    pub duration: Duration,
+}
+
+/// `Timings` records when a request was received, how long each of its phases took and when its
+/// response was written. It lives in `Layer8Context`, see `Layer8ContextTrait::timings_mut`.
+///
+/// - `start` opens a phase and `stop` closes it. `start` is a no-op while the phase is open, so
+///   it can be called on every chunk of a streamed body.
+/// - A phase may run several times (e.g. connection retries); its durations are summed.
+/// - `finish` marks the response as written. Only the first call counts.
+///
+/// # Example
+/// ```ignore
+/// ctx.timings_mut().start(Phases::BACKEND);
+/// let response = client.get(url).send().await;
+/// ctx.timings_mut().stop(Phases::BACKEND);
+///
+/// // "backend;dur=12.35, total;dur=14.02"
+/// let header = ctx.timings().server_timing();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Timings {
+    received: Instant,
+    finished: Option<Instant>,
+    open: Vec<(&'static str, Instant)>,
+    phases: Vec<PhaseTiming>,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings::new()
+    }
+}
+
+impl Timings {
+    /// Starts timing a request received now.
+    pub fn new() -> Self {
+        Timings {
+            received: Instant::now(),
+            finished: None,
+            open: Vec::new(),
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, name: &'static str) {
+        if !self.is_open(name) {
+            self.open.push((name, Instant::now()));
+        }
+    }
+
+    /// Closes the phase `name`. A phase that was not started is ignored.
+    pub fn stop(&mut self, name: &'static str) {
+        let Some(index) = self.open.iter().position(|(open, _)| *open == name) else {
+            return;
+        };
+
+        let (_, started) = self.open.swap_remove(index);
+        self.phases.push(PhaseTiming {
+            name,
+            offset: started.duration_since(self.received),
+            duration: started.elapsed(),
+        });
+    }
+
+    pub fn is_open(&self, name: &str) -> bool {
+        self.open.iter().any(|(open, _)| *open == name)
+    }
+
+    pub fn finish(&mut self) {
+        self.finished.get_or_insert_with(Instant::now);
+    }
+
+    /// Time since the request was received, or until its response was written once finished.
+    pub fn elapsed(&self) -> Duration {
+        self.finished.unwrap_or_else(Instant::now).duration_since(self.received)
+    }
+
+    /// Completed runs of every phase, in completion order.
+    pub fn phases(&self) -> &[PhaseTiming] {
+        &self.phases
+    }
+
+    /// Total duration of the completed runs of `name`.
+    pub fn get(&self, name: &str) -> Option<Duration> {
+        self.phases
+            .iter()
+            .filter(|phase| phase.name == name)
+            .map(|phase| phase.duration)
+            .reduce(|total, duration| total + duration)
+    }
+
+    /// Value of a `Server-Timing` header: one metric per completed phase, in milliseconds, then
+    /// the `total` so far.
+    pub fn server_timing(&self) -> String {
+        self.totals()
+            .into_iter()
+            .chain([("total", self.elapsed())])
+            .map(|(name, duration)| format!("{};dur={:.2}", name, as_ms(duration)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Summed durations per phase, in order of first completion.
+    fn totals(&self) -> Vec<(&'static str, Duration)> {
+        let mut totals: Vec<(&'static str, Duration)> = Vec::new();
+        for phase in &self.phases {
+            match totals.iter_mut().find(|(name, _)| *name == phase.name) {
+                Some((_, total)) => *total += phase.duration,
+                None => totals.push((phase.name, phase.duration)),
+            }
+        }
+        totals
+    }
+}
+
+/// Formats the phases for logs, e.g. `body_read=0.42ms@0.10ms backend=12.35ms@1.03ms total=14.02ms`,
+/// where `@` is the time the phase started after the request was received.
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for phase in &self.phases {
+            write!(f, "{}={:.2}ms@{:.2}ms ", phase.name, as_ms(phase.duration), as_ms(phase.offset))?;
+        }
+        write!(f, "total={:.2}ms", as_ms(self.elapsed()))
+    }
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
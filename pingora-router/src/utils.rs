@@ -1,19 +1,25 @@
 use pingora::prelude::Session;
+use crate::body::Layer8Body;
 
-pub(crate) async fn get_request_body(session: &mut Session) -> pingora::Result<Vec<u8>> {
-    let mut body = Vec::new();
-    loop {
-        match session.read_request_body().await {
-            Ok(option) => {
-                match option {
-                    Some(chunk) => body.extend_from_slice(&chunk),
-                    None => break,
-                }
-            }
-            Err(err) => {
-                return Err(err);
-            }
+pub(crate) async fn read_request_body(session: &mut Session, body: &mut Layer8Body) -> pingora::Result<()> {
+    while let Some(chunk) = session.read_request_body().await? {
+        body.extend(chunk);
+    }
+    Ok(())
+}
+
+/// Reads the body until its end, or until it would grow beyond `limit` bytes.
+/// Returns `false` in the latter case; the rest of the body is left unread.
+pub(crate) async fn read_request_body_with_limit(
+    session: &mut Session,
+    body: &mut Layer8Body,
+    limit: usize,
+) -> pingora::Result<bool> {
+    while let Some(chunk) = session.read_request_body().await? {
+        if body.len() + chunk.len() > limit {
+            return Ok(false);
         }
+        body.extend(chunk);
     }
-    Ok(body)
-}
\ No newline at end of file
+    Ok(true)
+}
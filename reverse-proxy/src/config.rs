@@ -28,7 +28,15 @@ pub(super) struct LogConfig {
 pub(super) struct ServerConfig {
     pub listen_address: String,
     #[serde(deserialize_with = "utils::deserializer::string_to_number")]
-    pub listen_port: u16
+    pub listen_port: u16,
+    /// Maximum request body size in bytes, for routes without a limit of their own. Default to
+    /// 10 MiB
+    #[serde(default = "default_max_request_body_size", deserialize_with = "utils::deserializer::string_to_number")]
+    pub max_request_body_size: usize,
+    /// Maximum request body size in bytes for `/init-tunnel`, which only carries a public key.
+    /// Default to 4 KiB
+    #[serde(default = "default_init_tunnel_max_body_size", deserialize_with = "utils::deserializer::string_to_number")]
+    pub init_tunnel_max_body_size: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -40,5 +48,74 @@ pub(super) struct HandlerConfig {
     pub jwt_virtual_connection_secret: Vec<u8>,
     #[serde(deserialize_with = "utils::deserializer::string_to_number")]
     pub jwt_exp_in_hours: i64,
+    /// "memory", or "sealed" to seal each session into its `int_rp_jwt`, so RP instances sharing
+    /// `ntor_ticket_key` are interchangeable and sessions survive restarts. Default to "memory".
+    /// Replays are only detected by the instance that received the original request, see
+    /// `ReplayGuard`: sealed sessions have no cross-instance replay protection
+    #[serde(default = "default_ntor_session_store")]
+    pub ntor_session_store: String,
+    /// Maximum number of nTor sessions kept in memory; the least recently used is evicted beyond it
+    #[serde(default = "default_ntor_session_max_entries", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_session_max_entries: usize,
+    /// 32 bytes master key of the session tickets, required when `ntor_session_store` is "sealed"
+    #[serde(default, deserialize_with = "string_to_optional_u8_32")]
+    pub ntor_ticket_key: Option<[u8; 32]>,
+    /// How often the ticket key derived from `ntor_ticket_key` rotates
+    #[serde(default = "default_ntor_ticket_rotation_secs", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_ticket_rotation_secs: u64,
+    /// Messages encrypted with an nTor session key before responses ask to rekey. Default to 0, no
+    /// limit, like the byte and age limits: rekeying is enabled by setting them
+    #[serde(default = "default_ntor_key_limit", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_rekey_max_messages: u64,
+    /// Bytes encrypted with an nTor session key before responses ask to rekey, 0 for no limit
+    #[serde(default = "default_ntor_key_limit", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_rekey_max_bytes: u64,
+    /// Age of an nTor session key before responses ask to rekey, 0 for no limit. The key is refused
+    /// once older than this plus `ntor_rekey_grace_secs`
+    #[serde(default = "default_ntor_key_limit", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_key_max_age_secs: u64,
+    /// How long a key replaced by a rekey is still accepted, for requests already in flight
+    #[serde(default = "default_ntor_rekey_grace_secs", deserialize_with = "utils::deserializer::string_to_number")]
+    pub ntor_rekey_grace_secs: u64,
+    /// Refuse `/proxy` requests without a `seq`. Default to false, so interceptors that don't send
+    /// one yet keep working; but then a request without a `seq` skips the replay check, so any
+    /// captured request can be replayed. Set it once all interceptors send a `seq`
+    #[serde(default, deserialize_with = "utils::deserializer::string_to_bool")]
+    pub ntor_require_sequence: bool,
     pub backend_url: String,
 }
+
+fn string_to_optional_u8_32<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    utils::deserializer::string_to_u8_32(deserializer).map(Some)
+}
+
+fn default_max_request_body_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_init_tunnel_max_body_size() -> usize {
+    4096
+}
+
+fn default_ntor_session_store() -> String {
+    "memory".to_string()
+}
+
+fn default_ntor_ticket_rotation_secs() -> u64 {
+    3600
+}
+
+fn default_ntor_session_max_entries() -> usize {
+    100_000
+}
+
+fn default_ntor_key_limit() -> u64 {
+    0
+}
+
+fn default_ntor_rekey_grace_secs() -> u64 {
+    60
+}
@@ -1,8 +1,26 @@
+use pingora_router::extract::HeaderKey;
+
 pub struct HeaderKeys;
 
 impl HeaderKeys {
     pub const FP_RP_JWT: &'static str = "fp_rp_jwt";
     pub const INT_RP_JWT_KEY: &'static str = "int_rp_jwt";
+    /// Response header asking the interceptor to rekey its nTor session, see `KeyLimits`
+    pub const REKEY: &'static str = "x-layer8-rekey";
+}
+
+/// `fp_rp_jwt` header, read by the typed handlers' `Header` extractor
+pub struct FpRpJwt;
+
+impl HeaderKey for FpRpJwt {
+    const NAME: &'static str = HeaderKeys::FP_RP_JWT;
+}
+
+/// `int_rp_jwt` header, read by the typed handlers' `Header` extractor
+pub struct IntRpJwt;
+
+impl HeaderKey for IntRpJwt {
+    const NAME: &'static str = HeaderKeys::INT_RP_JWT_KEY;
 }
 
 pub struct LogTypes;
@@ -12,8 +30,10 @@ impl LogTypes {
     pub const ACCESS_LOG_RESULT: &'static str = "ACCESS_LOG_RESULT";
     pub const HANDLE_INIT_TUNNEL_REQUEST: &'static str = "HANDLE_INIT_TUNNEL_REQUEST";
     pub const HANDLE_PROXY_REQUEST: &'static str = "HANDLE_PROXY_REQUEST";
+    pub const HANDLE_REKEY_REQUEST: &'static str = "HANDLE_REKEY_REQUEST";
     pub const HANDLE_BACKEND_RESPONSE: &'static str = "HANDLE_BACKEND_RESPONSE";
     #[allow(dead_code)]
     pub const HEALTHCHECK: &'static str = "HEALTHCHECK";
     pub const TLS_HANDSHAKE: &'static str = "TLS_HANDSHAKE";
+    pub const SECURITY_EVENT: &'static str = "SECURITY_EVENT";
 }
\ No newline at end of file
@@ -1,3 +1,3 @@
 pub(crate) mod consts;
-pub mod types;
 pub mod handler;
+pub(crate) mod stream;
@@ -0,0 +1,64 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use pingora_router::error::Layer8Error;
+
+pub type BodyStream = BoxStream<'static, Result<Bytes, Layer8Error>>;
+
+/// `StreamedBody` is a response body sent to the client as it is produced, instead of the
+/// handler's `APIHandlerResponse.body`.
+///
+/// The handler leaves it in the context's extensions and answers without a body; the proxy takes
+/// it once the response header is written. Extensions are cloned with the context, so clones
+/// share the stream and only the first `take` gets it.
+#[derive(Clone)]
+pub struct StreamedBody(Arc<Mutex<Option<BodyStream>>>);
+
+impl StreamedBody {
+    pub fn new(stream: BodyStream) -> Self {
+        StreamedBody(Arc::new(Mutex::new(Some(stream))))
+    }
+
+    pub fn take(&self) -> Option<BodyStream> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// `StreamedRequestBody` is a request body read from the client while the handler runs, instead
+/// of the context's request body, so it is forwarded as it arrives.
+///
+/// The proxy leaves it in the context's extensions before calling the handler, which takes it
+/// like a `StreamedBody`. The stream fails with the error that stopped the proxy from reading the
+/// body, e.g. a `Layer8Error::PayloadTooLarge`.
+#[derive(Clone)]
+pub struct StreamedRequestBody(StreamedBody);
+
+impl StreamedRequestBody {
+    pub fn new(stream: BodyStream) -> Self {
+        StreamedRequestBody(StreamedBody::new(stream))
+    }
+
+    pub fn take(&self) -> Option<BodyStream> {
+        self.0.take()
+    }
+}
+
+/// `SyncBodyStream` lets a `BodyStream` be the body of a `reqwest` request, which must be `Sync`.
+/// The stream is only polled through `&mut self`, so the mutex is never locked.
+pub struct SyncBodyStream(Mutex<BodyStream>);
+
+impl SyncBodyStream {
+    pub fn new(stream: BodyStream) -> Self {
+        SyncBodyStream(Mutex::new(stream))
+    }
+}
+
+impl Stream for SyncBodyStream {
+    type Item = Result<Bytes, Layer8Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.get_mut().unwrap().poll_next_unpin(cx)
+    }
+}
@@ -1,9 +1,22 @@
+use pingora::http::StatusCode;
 use serde::{Deserialize, Serialize};
-use pingora_router::handler::ResponseBodyTrait;
+use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
+use pingora_router::extract::IntoErrorResponse;
+use pingora_router::handler::{APIHandlerResponse, ResponseBodyTrait};
+use crate::handler::session_store::SessionStoreMetrics;
+
+#[derive(Deserialize, Debug)]
+pub struct HealthcheckQuery {
+    /// `true` answers with `RpHealthcheckError`
+    pub(crate) error: Option<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RpHealthcheckSuccess {
     pub(crate) rp_healthcheck_success: String,
+    /// Not reported when sessions are sealed into tickets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ntor_sessions: Option<SessionStoreMetrics>,
 }
 
 impl ResponseBodyTrait for RpHealthcheckSuccess {}
@@ -13,4 +26,11 @@ pub struct RpHealthcheckError {
     pub(crate) rp_healthcheck_error: String,
 }
 
-impl ResponseBodyTrait for RpHealthcheckError {}
\ No newline at end of file
+impl ResponseBodyTrait for RpHealthcheckError {}
+
+impl IntoErrorResponse for RpHealthcheckError {
+    fn into_error_response(self, ctx: &mut Layer8Context) -> APIHandlerResponse {
+        ctx.insert_response_header("x-rp-healthcheck-error", "response-header-error");
+        APIHandlerResponse::new(StatusCode::IM_A_TEAPOT, Some(self.to_bytes()))
+    }
+}
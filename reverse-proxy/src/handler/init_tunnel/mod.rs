@@ -1,5 +1,3 @@
-pub(crate) mod handler;
-
 use serde::{Deserialize, Serialize};
 use pingora_router::handler::{RequestBodyTrait, ResponseBodyTrait};
 
@@ -1,78 +1,123 @@
-use std::collections::HashMap;
-use std::sync::{Mutex, MutexGuard};
-use ntor::common::{InitSessionMessage, NTorParty};
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use ntor::common::{EncryptedMessage, InitSessionMessage, NTorParty};
 use ntor::server::NTorServer;
 use pingora::http::StatusCode;
-use tracing::{info};
+use tracing::{info, warn};
 use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
-use pingora_router::handler::{APIHandlerResponse, ResponseBodyTrait};
-use init_tunnel::handler::InitTunnelHandler;
+use pingora_router::error::Layer8Error;
+use pingora_router::extract::{Bincode, CorrelationId, Header, IntoErrorResponse, Json, Query, WithHeaders};
+use pingora_router::handler::APIHandlerResponse;
+use pingora_router::timing::Phases;
 use proxy::handler::ProxyHandler;
-use init_tunnel::InitEncryptedTunnelResponse;
+use proxy::L8RequestObject;
+use init_tunnel::{InitEncryptedTunnelRequest, InitEncryptedTunnelResponse};
 use utils::{new_uuid};
 use utils::jwt::JWTClaims;
 use crate::config::{HandlerConfig, RPConfig};
-use crate::handler::common::consts::LogTypes;
-use crate::handler::healthcheck::{RpHealthcheckError, RpHealthcheckSuccess};
+use crate::handler::common::consts::{FpRpJwt, HeaderKeys, IntRpJwt, LogTypes};
+use crate::handler::common::stream::{BodyStream, StreamedBody, StreamedRequestBody, SyncBodyStream};
+use crate::handler::healthcheck::{HealthcheckQuery, RpHealthcheckError, RpHealthcheckSuccess};
+use crate::handler::rekey::{KeyLimits, RekeyRequest, RekeyResponse};
+use crate::handler::replay::ReplayGuard;
+use crate::handler::session_store::{new_ntor_sessions, unix_now, KeyUsage, NTorSessions, SessionKey, SharedSecret, StreamedUsage};
 
 pub(crate) mod common;
 mod init_tunnel;
 mod proxy;
 mod healthcheck;
-
-thread_local! {
-    // <session_id, shared_secret>
-    static NTOR_SHARED_SECRETS: Mutex<HashMap<String, Vec<u8>>> = Mutex::new(HashMap::new());
-}
+mod rekey;
+mod replay;
+pub mod session_store;
 
 pub struct ReverseHandler {
     config: HandlerConfig,
     jwt_secret: Vec<u8>,
     ntor_static_secret: [u8; 32],
+    sessions: NTorSessions, // ntor_session_id -> session key
+    key_limits: KeyLimits,
+    replay_guard: ReplayGuard,
 }
 
 impl ReverseHandler {
     pub fn new(config: RPConfig) -> Self {
         let ntor_secret = config.handler.ntor_static_secret.clone();
         let jwt_secret = config.handler.jwt_virtual_connection_secret.clone();
+        let sessions = new_ntor_sessions(&config.handler).expect("Invalid nTor session store");
+        let key_limits = KeyLimits::new(&config.handler);
+        let replay_guard = ReplayGuard::new(config.handler.ntor_session_max_entries, unix_now());
 
         ReverseHandler {
             config: config.handler,
             jwt_secret,
             ntor_static_secret: ntor_secret,
+            sessions,
+            key_limits,
+            replay_guard,
         }
     }
 
-    fn get_ntor_shared_secret(&self, session_id: String) -> Result<Vec<u8>, APIHandlerResponse> {
-        let shared_secret = NTOR_SHARED_SECRETS.with(|memory| {
-            let guard = memory.lock().unwrap();
-            guard.get(&session_id).cloned()
-        });
-
-        match shared_secret {
-            Some(secret) => Ok(secret.clone()),
-            None => {
-                Err(APIHandlerResponse {
-                    status: StatusCode::UNAUTHORIZED,
-                    body: Some("Invalid or expired nTor session ID".as_bytes().to_vec()),
-                    cookies: None,
-                })
+    /// Refuses a request already received in its session, see `ReplayWindow`. Replays are logged
+    /// as security events.
+    fn check_replay(
+        &self,
+        claims: &JWTClaims,
+        key: &SessionKey,
+        seq: Option<u64>,
+        correlation_id: &str,
+    ) -> Result<(), Layer8Error> {
+        let session_id = claims.ntor_session_id.as_deref().unwrap_or_default();
+
+        let result = match (seq, &self.sessions) {
+            (Some(seq), NTorSessions::Stored(store)) => store.accept_sequence(session_id, seq),
+            (Some(seq), NTorSessions::Sealed(_)) => self.replay_guard.check(session_id, key.created_at, seq),
+            (None, _) if self.config.ntor_require_sequence => {
+                Err(Layer8Error::BadRequest("Missing seq in request body".to_string()))
             }
-        }
-    }
+            (None, _) => Ok(()),
+        };
 
-    pub async fn handle_init_tunnel(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
-        let correlation_id = ctx.get_correlation_id();
+        result.inspect_err(|err| {
+            if matches!(err, Layer8Error::ReplayedMessage(_)) {
+                warn!(
+                    %correlation_id,
+                    log_type=LogTypes::SECURITY_EVENT,
+                    ntor_session_id=session_id,
+                    "Refused replayed request: {}",
+                    err.detail()
+                );
+            }
+        })
+    }
 
-        // validate request body
-        let request_body = match InitTunnelHandler::validate_request_body(
-            ctx,
-            self.config.backend_url.clone(),
-        ).await {
-            Ok(res) => res,
-            Err(res) => return res
+    /// Returns the nTor session key of `int_rp_jwt`, of its `key_generation`: looked up in the
+    /// store, or unsealed from the token's ticket.
+    fn get_ntor_session_key(&self, claims: &JWTClaims) -> Result<SessionKey, Layer8Error> {
+        let generation = claims.key_generation.unwrap_or_default();
+        let key = match (&self.sessions, &claims.sealed) {
+            (NTorSessions::Stored(store), _) => claims
+                .ntor_session_id
+                .as_deref()
+                .and_then(|session_id| store.get(session_id, generation)),
+            (NTorSessions::Sealed(tickets), Some(ticket)) => Some(tickets.open(ticket)?),
+            (NTorSessions::Sealed(_), None) => None,
         };
 
+        match key {
+            None => Err(Layer8Error::InvalidSession("Invalid or expired nTor session ID".to_string())),
+            Some(key) if self.key_limits.is_expired(&key) => Err(Layer8Error::InvalidSession(
+                "Expired nTor session key, initialize a new tunnel".to_string(),
+            )),
+            Some(key) => Ok(key),
+        }
+    }
+
+    pub async fn handle_init_tunnel(
+        self: Arc<Self>,
+        Json(request_body): Json<InitEncryptedTunnelRequest>,
+        CorrelationId(correlation_id): CorrelationId,
+    ) -> Result<InitEncryptedTunnelResponse, Layer8Error> {
         // todo I think there are prettier ways to use nTor since we are free to modify the nTor crate, but I'm lazy
         let mut ntor_server = NTorServer::new_with_secret(
             self.config.ntor_server_id.clone(),
@@ -81,11 +126,7 @@ impl ReverseHandler {
 
         let init_session_response = {
             if request_body.public_key.len() != 32 {
-                return APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    body: Some("Invalid public key length".as_bytes().to_vec()),
-                    cookies: None,
-                };
+                return Err(Layer8Error::BadRequest("Invalid public key length".to_string()));
             }
 
             // Client initializes session with the server
@@ -95,9 +136,14 @@ impl ReverseHandler {
 
         let ntor_session_id = new_uuid();
 
+        let key = SessionKey::new(SharedSecret::new(ntor_server.get_shared_secret().unwrap_or_default()));
+
         let int_rp_jwt = {
             let mut claims = JWTClaims::new(Some(self.config.jwt_exp_in_hours));
             claims.ntor_session_id = Some(ntor_session_id.clone());
+            if let NTorSessions::Sealed(tickets) = &self.sessions {
+                claims.sealed = Some(tickets.seal(&key));
+            }
             utils::jwt::create_jwt_token(claims, &self.jwt_secret)
         };
 
@@ -120,109 +166,422 @@ impl ReverseHandler {
             "Save new nTor session: {}",
             ntor_session_id
         );
-        NTOR_SHARED_SECRETS.with(|memory| {
-            let mut guard: MutexGuard<HashMap<String, Vec<u8>>> = memory.lock().unwrap();
-            guard.insert(ntor_session_id, ntor_server.get_shared_secret().unwrap_or_default());
-        });
-
-        APIHandlerResponse {
-            status: StatusCode::OK,
-            cookies: None,
-            body: Some(response.to_bytes()),
+        match &self.sessions {
+            NTorSessions::Stored(store) => {
+                // the session lives as long as `int_rp_jwt`
+                let ttl = Duration::from_secs(self.config.jwt_exp_in_hours.max(0) as u64 * 3600);
+                store.insert(ntor_session_id, key, ttl);
+            }
+            NTorSessions::Sealed(_) => self.replay_guard.register(ntor_session_id, key.created_at),
         }
+
+        Ok(response)
     }
 
+    /// Forwards a user request to the backend and answers with the encrypted response.
+    ///
+    /// The request is an `EncryptedMessage`, or a framed stream when its `Content-Type` is
+    /// `utils::stream::CONTENT_TYPE`, forwarded to the backend as its frames are received. The
+    /// response is an `EncryptedMessage`, or a framed stream sent while the backend response is
+    /// read when the request asks for `stream_response`.
     pub async fn handle_proxy_request(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
         let correlation_id = ctx.get_correlation_id();
 
         // validate request headers (nTor session ID)
-        let session_id = match ProxyHandler::validate_request_headers(ctx, &self.jwt_secret) {
-            Ok(session_id) => session_id,
-            Err(res) => return res,
+        let claims = match ProxyHandler::validate_request_headers(ctx, &self.jwt_secret) {
+            Ok(claims) => claims,
+            Err(err) => return err.into_error_response(ctx),
         };
 
-        let shared_secret = match self.get_ntor_shared_secret(session_id) {
-            Ok(secret) => secret,
-            Err(res) => return res,
+        let key = match self.get_ntor_session_key(&claims) {
+            Ok(key) => key,
+            Err(err) => return err.into_error_response(ctx),
         };
 
-        // validate request body
-        let request_body = match ProxyHandler::validate_request_body(ctx) {
-            Ok(res) => res,
-            Err(res) => return res,
+        let streamed_request = ctx
+            .get_request_header()
+            .get("content-type")
+            .is_some_and(|content_type| content_type == utils::stream::CONTENT_TYPE);
+        // a streamed request is counted as its frames are opened, see `StreamedUsage`
+        let request_size = if streamed_request { 0 } else { ctx.get_request_body().len() };
+
+        // only the header frame of a streamed request is opened here, its body chunks are opened
+        // while they are sent to the backend
+        ctx.timings_mut().start(Phases::DECRYPTION);
+        let decrypted = if streamed_request {
+            let mut streamed_usage = self.streamed_usage(&claims, &key);
+            ProxyHandler::decrypt_request_stream(request_body_stream(ctx), &key.shared_secret, move |bytes| {
+                if let Some(usage) = streamed_usage.as_mut() {
+                    usage.add(bytes);
+                }
+            })
+                .await
+                .map(|(request, body)| (request, reqwest::Body::wrap_stream(SyncBodyStream::new(body))))
+        } else {
+            // validate request body
+            ProxyHandler::validate_request_body(ctx).and_then(|request_body| {
+                ProxyHandler::decrypt_request_body(
+                    request_body,
+                    self.config.ntor_server_id.clone(),
+                    &key.shared_secret,
+                )
+            })
+            .map(|mut request: L8RequestObject| {
+                let body = std::mem::take(&mut request.body);
+                (request, reqwest::Body::from(body))
+            })
         };
+        ctx.timings_mut().stop(Phases::DECRYPTION);
 
-        let wrapped_request = match ProxyHandler::decrypt_request_body(
-            request_body,
-            self.config.ntor_server_id.clone(),
-            shared_secret.clone(),
-        ) {
-            Ok(req) => req,
-            Err(res) => return res,
+        let (wrapped_request, request_body) = match decrypted {
+            Ok(decrypted) => decrypted,
+            Err(err) => return err.into_error_response(ctx),
         };
 
+        if let Err(err) = self.check_replay(&claims, &key, wrapped_request.seq, &correlation_id) {
+            return err.into_error_response(ctx);
+        }
+
         info!(
             %correlation_id,
             log_type=LogTypes::HANDLE_INIT_TUNNEL_REQUEST,
             "Decrypted request body and forward to backend",
         );
 
+        let stream_response = wrapped_request.stream_response;
+
         // reconstruct user request
-        let wrapped_response = match ProxyHandler::rebuild_user_request(
+        let (mut wrapped_response, backend_response) = match ProxyHandler::rebuild_user_request(
             ctx,
             self.config.backend_url.clone(),
             wrapped_request,
+            request_body,
         ).await {
             Ok(res) => res,
-            Err(res) => return res,
+            Err(err) => return err.into_error_response(ctx),
         };
 
-        let cookies: Option<String> = wrapped_response.headers.get("set-cookie")
-            .and_then(|v| v.as_str().map(|s| s.to_string()));
+        let cookies = std::mem::take(&mut wrapped_response.cookies);
+
+        if stream_response {
+            ctx.timings_mut().stop(Phases::BACKEND);
+
+            // the response is counted as it is encrypted, see `StreamedUsage`
+            self.record_key_usage(ctx, &claims, &key, request_size);
+            let mut streamed_usage = self.streamed_usage(&claims, &key);
+
+            let stream = ProxyHandler::encrypt_response_stream(
+                &wrapped_response,
+                backend_response,
+                &key.shared_secret,
+                move |bytes| {
+                    if let Some(usage) = streamed_usage.as_mut() {
+                        usage.add(bytes);
+                    }
+                },
+            );
+            ctx.extensions_mut().insert(StreamedBody::new(stream));
+            ctx.insert_response_header("Content-Type", utils::stream::CONTENT_TYPE);
+
+            return APIHandlerResponse {
+                status: StatusCode::OK,
+                cookies,
+                body: None,
+            };
+        }
 
-        match ProxyHandler::encrypt_response_body(
+        ProxyHandler::read_response_body(ctx, &mut wrapped_response, backend_response).await;
+
+        ctx.timings_mut().start(Phases::ENCRYPTION);
+        let encrypted = ProxyHandler::encrypt_response_body(
             wrapped_response,
             self.config.ntor_server_id.clone(),
-            shared_secret,
-        ) {
+            &key.shared_secret,
+        );
+        ctx.timings_mut().stop(Phases::ENCRYPTION);
+
+        match encrypted {
             Ok(encrypted_message) => {
                 let body = utils::type_to_bincode(&encrypted_message);
+                self.record_key_usage(ctx, &claims, &key, request_size + body.len());
+
                 APIHandlerResponse {
                     status: StatusCode::OK,
                     cookies,
                     body: Some(body),
                 }
             }
-            Err(res) => res
+            Err(err) => err.into_error_response(ctx),
         }
     }
 
-    pub async fn handle_healthcheck(&self, ctx: &mut Layer8Context) -> APIHandlerResponse {
-        if let Some(error) = ctx.param("error") {
-            if error == "true" {
-                let response_bytes = RpHealthcheckError {
-                    rp_healthcheck_error: "this is placeholder for a custom error".to_string()
-                }.to_bytes();
+    /// Counts the bytes of a streamed request or response against the session key, see
+    /// `StreamedUsage`. Sealed keys are not counted.
+    fn streamed_usage(&self, claims: &JWTClaims, key: &SessionKey) -> Option<StreamedUsage> {
+        match (&self.sessions, &claims.ntor_session_id) {
+            (NTorSessions::Stored(store), Some(session_id)) => {
+                Some(StreamedUsage::new(store.clone(), session_id.clone(), key.generation))
+            }
+            _ => None,
+        }
+    }
 
-                ctx.insert_response_header("x-rp-healthcheck-error", "response-header-error");
-                return APIHandlerResponse {
-                    status: StatusCode::IM_A_TEAPOT,
-                    cookies: None,
-                    body: Some(response_bytes),
+    /// Counts a message of `bytes` against the session key, and asks the interceptor to rekey
+    /// once the key reached its limits, see `KeyLimits`.
+    fn record_key_usage(&self, ctx: &mut Layer8Context, claims: &JWTClaims, key: &SessionKey, bytes: usize) {
+        let usage = match (&self.sessions, &claims.ntor_session_id) {
+            (NTorSessions::Stored(store), Some(session_id)) => {
+                let usage = KeyUsage {
+                    messages: 1,
+                    bytes: bytes as u64,
                 };
+                store.record_usage(session_id, key.generation, usage)
             }
+            // sealed keys are not rekeyed, see `NTorSessions::Sealed`
+            _ => return,
+        };
+
+        if self.key_limits.rekey_required(key, usage) {
+            ctx.insert_response_header(HeaderKeys::REKEY, "required");
+        }
+    }
+
+    /// Replaces the nTor session key with its ratchet, see `SessionKey::ratchet`, without a new
+    /// handshake. The request body is a `RekeyRequest` encrypted with the current key; the response
+    /// carries the `int_rp_jwt` to use with the new key. Sealed sessions are not rekeyed.
+    pub async fn handle_rekey(
+        self: Arc<Self>,
+        fp_rp_jwt: Header<FpRpJwt>,
+        int_rp_jwt: Header<IntRpJwt>,
+        Bincode(request_body): Bincode<EncryptedMessage>,
+        CorrelationId(correlation_id): CorrelationId,
+    ) -> Result<RekeyResponse, Layer8Error> {
+        let NTorSessions::Stored(store) = &self.sessions else {
+            return Err(Layer8Error::BadRequest(
+                "Sealed nTor sessions are not rekeyed, initialize a new tunnel".to_string(),
+            ));
+        };
+
+        let mut claims = ProxyHandler::validate_tokens(
+            Some(&fp_rp_jwt.value),
+            Some(&int_rp_jwt.value),
+            &self.jwt_secret,
+            &correlation_id,
+        )?;
+        let key = self.get_ntor_session_key(&claims)?;
+
+        // decrypting proves the interceptor holds the key
+        let request = ProxyHandler::decrypt_request_body::<RekeyRequest>(
+            request_body,
+            self.config.ntor_server_id.clone(),
+            &key.shared_secret,
+        )?;
+        if request.key_generation != key.generation {
+            return Err(Layer8Error::BadRequest("key_generation does not match int_rp_jwt".to_string()));
+        }
+
+        self.check_replay(&claims, &key, request.seq, &correlation_id)?;
+
+        let next_key = key.ratchet();
+        let session_id = claims.ntor_session_id.clone().unwrap_or_default();
+        if !store.rekey(&session_id, next_key.clone(), self.key_limits.grace()) {
+            return Err(Layer8Error::InvalidSession("Invalid or expired nTor session ID".to_string()));
+        }
+
+        info!(
+            %correlation_id,
+            log_type=LogTypes::HANDLE_REKEY_REQUEST,
+            "Rekeyed nTor session {} to key generation {}",
+            session_id,
+            next_key.generation
+        );
+
+        // the token keeps the expiry of the tunnel
+        claims.key_generation = Some(next_key.generation);
+        claims.set_current_iat();
+        Ok(RekeyResponse {
+            int_rp_jwt: utils::jwt::create_jwt_token(claims, &self.jwt_secret),
+            key_generation: next_key.generation,
+        })
+    }
+
+    /// Answers `?error=true` with `RpHealthcheckError`, see its `IntoErrorResponse`.
+    pub async fn handle_healthcheck(
+        self: Arc<Self>,
+        Query(query): Query<HealthcheckQuery>,
+    ) -> Result<WithHeaders<RpHealthcheckSuccess>, RpHealthcheckError> {
+        if query.error.as_deref() == Some("true") {
+            return Err(RpHealthcheckError {
+                rp_healthcheck_error: "this is placeholder for a custom error".to_string()
+            });
         }
 
-        let response_bytes = RpHealthcheckSuccess {
+        let response = RpHealthcheckSuccess {
             rp_healthcheck_success: "this is placeholder for a custom body".to_string(),
-        }.to_bytes();
+            ntor_sessions: match &self.sessions {
+                NTorSessions::Stored(store) => Some(store.metrics()),
+                NTorSessions::Sealed(_) => None,
+            },
+        };
 
-        ctx.insert_response_header("x-rp-healthcheck-success", "response-header-success");
+        Ok(WithHeaders::new(response).header("x-rp-healthcheck-success", "response-header-success"))
+    }
+}
 
-        APIHandlerResponse {
-            status: StatusCode::OK,
-            cookies: None,
-            body: Some(response_bytes),
+/// The body of a streamed request: read from the client while it is forwarded when the proxy
+/// streams it, see `StreamedRequestBody`, or the body already read into `ctx`.
+fn request_body_stream(ctx: &mut Layer8Context) -> BodyStream {
+    match ctx.extensions_mut().remove::<StreamedRequestBody>().and_then(|body| body.take()) {
+        Some(body) => body,
+        None => {
+            let body = ctx.freeze_request_body();
+            futures::stream::once(async move { Ok(body) }).boxed()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use futures::executor::block_on;
+    use pingora::http::Method;
+    use pingora_router::extract::typed;
+    use pingora_router::router::Router;
+    use pingora_router::testing;
+    use super::*;
+
+    const JWT_SECRET: &str = "this is 32-byte rp's jwt secret.";
+
+    fn config(vars: &[(&str, &str)]) -> RPConfig {
+        let mut env: HashMap<&str, &str> = HashMap::from([
+            ("LOG_LEVEL", "info"),
+            ("LOG_FORMAT", "plain"),
+            ("LOG_PATH", "console"),
+            ("LOG_FILENAME", ""),
+            ("LISTEN_ADDRESS", "localhost"),
+            ("LISTEN_PORT", "6193"),
+            ("ENABLE_TLS", "false"),
+            ("CA_CERT", ""),
+            ("CERT", ""),
+            ("KEY", ""),
+            ("CORS_ALLOW_CREDENTIALS", "false"),
+            ("CORS_ALLOW_ORIGINS", ""),
+            ("NTOR_SERVER_ID", "ReverseProxyServer"),
+            ("NTOR_STATIC_SECRET", "this is 32-byte nTorStaticSecret"),
+            ("JWT_VIRTUAL_CONNECTION_SECRET", JWT_SECRET),
+            ("JWT_EXP_IN_HOURS", "24"),
+            ("BACKEND_URL", "http://localhost:3000"),
+        ]);
+        env.extend(vars.iter().copied());
+        envy::from_iter(env.into_iter().map(|(key, value)| (key.to_string(), value.to_string()))).unwrap()
+    }
+
+    /// The routes of `main` answered by typed handlers.
+    fn router(vars: &[(&str, &str)]) -> Router<Arc<ReverseHandler>> {
+        let mut router = Router::new(Arc::new(ReverseHandler::new(config(vars))));
+        router.post("/init-tunnel".to_string(), Box::new([typed(ReverseHandler::handle_init_tunnel)]));
+        router.get("/healthcheck".to_string(), Box::new([typed(ReverseHandler::handle_healthcheck)]));
+        router
+    }
+
+    fn call(router: &Router<Arc<ReverseHandler>>, ctx: &mut Layer8Context) -> (APIHandlerResponse, serde_json::Value) {
+        let response = block_on(testing::dispatch(router, ctx));
+        let body = serde_json::from_slice(response.body.as_deref().unwrap_or_default()).unwrap();
+        (response, body)
+    }
+
+    fn init_tunnel(public_key: &[u8]) -> Layer8Context {
+        Layer8Context::builder()
+            .method(Method::POST)
+            .path("/init-tunnel")
+            .body(serde_json::to_vec(&serde_json::json!({ "public_key": public_key })).unwrap())
+            .build()
+    }
+
+    fn healthcheck(path: &str) -> Layer8Context {
+        Layer8Context::builder().method(Method::GET).path(path).build()
+    }
+
+    #[test]
+    fn init_tunnel_opens_a_session() {
+        let router = router(&[]);
+
+        let (response, body) = call(&router, &mut init_tunnel(&[7; 32]));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(body["public_key"].as_array().map(Vec::len), Some(32));
+
+        let claims = utils::jwt::verify_jwt_token(body["jwt1"].as_str().unwrap(), &JWT_SECRET.into())
+            .unwrap()
+            .claims;
+        assert!(claims.ntor_session_id.is_some());
+        assert!(claims.sealed.is_none());
+        assert!(utils::jwt::verify_jwt_token(body["jwt2"].as_str().unwrap(), &JWT_SECRET.into()).is_ok());
+
+        let (_, body) = call(&router, &mut healthcheck("/healthcheck"));
+        assert_eq!(body["ntor_sessions"]["sessions"], 1);
+    }
+
+    #[test]
+    fn init_tunnel_refuses_an_invalid_public_key() {
+        let router = router(&[]);
+
+        let not_json = Layer8Context::builder()
+            .method(Method::POST)
+            .path("/init-tunnel")
+            .body("public_key")
+            .build();
+
+        for mut ctx in [init_tunnel(&[7; 31]), init_tunnel(&[]), not_json] {
+            let (response, body) = call(&router, &mut ctx);
+            assert_eq!(response.status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], "bad_request");
+        }
+
+        let (_, body) = call(&router, &mut healthcheck("/healthcheck"));
+        assert_eq!(body["ntor_sessions"]["sessions"], 0);
+    }
+
+    #[test]
+    fn sealed_sessions_are_carried_by_the_token() {
+        let router = router(&[
+            ("NTOR_SESSION_STORE", "sealed"),
+            ("NTOR_TICKET_KEY", "this is 32-byte RP ticket secret"),
+        ]);
+
+        let (response, body) = call(&router, &mut init_tunnel(&[7; 32]));
+        assert_eq!(response.status, StatusCode::OK);
+        let claims = utils::jwt::verify_jwt_token(body["jwt1"].as_str().unwrap(), &JWT_SECRET.into())
+            .unwrap()
+            .claims;
+        assert!(claims.sealed.is_some());
+
+        // not stored, so not counted
+        let (response, body) = call(&router, &mut healthcheck("/healthcheck"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(body.get("ntor_sessions").is_none());
+    }
+
+    #[test]
+    fn healthcheck_answers_with_its_headers() {
+        let router = router(&[]);
+
+        let mut ctx = healthcheck("/healthcheck");
+        let (response, body) = call(&router, &mut ctx);
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(body["rp_healthcheck_success"].is_string());
+        assert_eq!(
+            ctx.get_response_header().get("x-rp-healthcheck-success"),
+            Some("response-header-success")
+        );
+
+        let mut ctx = healthcheck("/healthcheck?error=true");
+        let (response, body) = call(&router, &mut ctx);
+        assert_eq!(response.status, StatusCode::IM_A_TEAPOT);
+        assert!(body["rp_healthcheck_error"].is_string());
+        assert_eq!(
+            ctx.get_response_header().get("x-rp-healthcheck-error"),
+            Some("response-header-error")
+        );
+        assert!(!ctx.get_response_header().contains_key("x-rp-healthcheck-success"));
+    }
+}
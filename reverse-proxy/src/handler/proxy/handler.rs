@@ -1,15 +1,20 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{stream, StreamExt};
 use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
 use reqwest::header::HeaderMap;
-use pingora_router::handler::{APIHandlerResponse, DefaultHandlerTrait, ResponseBodyTrait};
+use pingora_router::error::Layer8Error;
+use pingora_router::handler::{DefaultHandlerTrait, ResponseBodyTrait};
+use pingora_router::timing::Phases;
 use ntor::common::{EncryptedMessage, NTorParty};
 use ntor::server::NTorServer;
 use reqwest::Client;
-use pingora::http::StatusCode;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
 use utils::bytes_to_json;
 use utils::jwt::JWTClaims;
+use utils::stream::{Frame, StreamDecryptor, StreamEncryptor, StreamError, CHUNK_SIZE};
 use crate::handler::common::consts::{HeaderKeys, LogTypes};
-use crate::handler::common::types::ErrorResponse;
+use crate::handler::common::stream::BodyStream;
 use crate::handler::proxy::{L8ResponseObject, L8RequestObject};
 
 /// Struct containing only associated methods (no instance methods or fields)
@@ -20,29 +25,16 @@ impl DefaultHandlerTrait for ProxyHandler {}
 impl ProxyHandler {
 
     fn validate_jwt_token(
-        ctx: &mut Layer8Context,
+        token: Option<&str>,
         header_key: &str,
-        jwt_secret: &Vec<u8>
-    ) -> Result<JWTClaims, APIHandlerResponse> {
-        match ctx.get_request_header().get(header_key) {
-            None => {
-                Err(APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    cookies: None,
-                    body: Some(ErrorResponse {
-                        error: format!("Missing {} header", header_key.to_string()),
-                    }.to_bytes()),
-                })
-            },
+        jwt_secret: &Vec<u8>,
+        correlation_id: &str,
+    ) -> Result<JWTClaims, Layer8Error> {
+        match token {
+            None => Err(Layer8Error::BadRequest(format!("Missing {} header", header_key))),
             Some(token) => {
                 if token.is_empty() {
-                    return Err(APIHandlerResponse {
-                        status: StatusCode::BAD_REQUEST,
-                        cookies: None,
-                        body: Some(ErrorResponse {
-                            error: format!("Empty {} header", header_key.to_string()),
-                        }.to_bytes()),
-                    });
+                    return Err(Layer8Error::BadRequest(format!("Empty {} header", header_key)));
                 }
 
                 // verify token
@@ -50,51 +42,59 @@ impl ProxyHandler {
                     Ok(data) => Ok(data.claims),
                     Err(err) => {
                         error!(
-                            correlation_id=ctx.get_correlation_id(),
+                            %correlation_id,
                             log_type=LogTypes::HANDLE_PROXY_REQUEST,
                             "Error verifying {} token: {:?}",
                             header_key,
                             err
                         );
-                        Err(APIHandlerResponse {
-                            status: StatusCode::BAD_REQUEST,
-                            cookies: None,
-                            body: Some(ErrorResponse {
-                                error: err.to_string(),
-                            }.to_bytes()),
-                        })
+                        Err(Layer8Error::InvalidSession(format!("Invalid {} token: {}", header_key, err)))
                     },
                 }
             }
         }
     }
 
-    /// Validates the request headers and get ntor_session_id in return.
+    /// Validates the request headers and returns the `int_rp_jwt` claims, carrying the nTor session:
+    /// its `ntor_session_id`, and its ticket when sessions are sealed.
     pub(crate) fn validate_request_headers(
         ctx: &mut Layer8Context,
         jwt_secret: &Vec<u8>,
-    ) -> Result<String, APIHandlerResponse>
+    ) -> Result<JWTClaims, Layer8Error>
+    {
+        ProxyHandler::validate_tokens(
+            ctx.get_request_header().get(HeaderKeys::FP_RP_JWT),
+            ctx.get_request_header().get(HeaderKeys::INT_RP_JWT_KEY),
+            jwt_secret,
+            &ctx.get_correlation_id(),
+        )
+    }
+
+    /// Verifies `fp_rp_jwt` and `int_rp_jwt`, as read from the request headers, and returns the
+    /// `int_rp_jwt` claims, see `validate_request_headers`.
+    pub(crate) fn validate_tokens(
+        fp_rp_jwt: Option<&str>,
+        int_rp_jwt: Option<&str>,
+        jwt_secret: &Vec<u8>,
+        correlation_id: &str,
+    ) -> Result<JWTClaims, Layer8Error>
     {
         // verify fp_rp_jwt header
-        match ProxyHandler::validate_jwt_token(ctx, HeaderKeys::FP_RP_JWT, jwt_secret) {
+        match ProxyHandler::validate_jwt_token(fp_rp_jwt, HeaderKeys::FP_RP_JWT, jwt_secret, correlation_id) {
             Ok(_claims) => {
                 // todo!() nothing to validate at the moment
             }
             Err(err) => return Err(err)
         }
 
-        match ProxyHandler::validate_jwt_token(ctx, HeaderKeys::INT_RP_JWT_KEY, jwt_secret) {
+        match ProxyHandler::validate_jwt_token(int_rp_jwt, HeaderKeys::INT_RP_JWT_KEY, jwt_secret, correlation_id) {
             Ok(claims) => {
-                // extract ntor_session_id from claims
+                // every nTor session has an id, sealed or not
                 match claims.ntor_session_id {
-                    Some(ntor_session_id) => Ok(ntor_session_id),
-                    None => Err(APIHandlerResponse {
-                        status: StatusCode::BAD_REQUEST,
-                        cookies: None,
-                        body: Some(ErrorResponse {
-                            error: "Missing ntor_session_id in JWT claims".to_string(),
-                        }.to_bytes()),
-                    }),
+                    Some(_) => Ok(claims),
+                    None => Err(Layer8Error::InvalidSession(
+                        "Missing ntor_session_id in JWT claims".to_string(),
+                    )),
                 }
             }
             Err(err) => Err(err)
@@ -103,12 +103,12 @@ impl ProxyHandler {
 
     pub(crate) fn validate_request_body(
         ctx: &mut Layer8Context
-    ) -> Result<EncryptedMessage, APIHandlerResponse>
+    ) -> Result<EncryptedMessage, Layer8Error>
     {
         let correlation_id = ctx.get_correlation_id();
 
         // deserialize from bincode
-        match utils::bincode_to_type(ctx.get_request_body().as_slice()) {
+        match utils::bincode_to_type(ctx.get_request_body()) {
             Ok(res) => Ok(res),
             Err(err) => {
                 error!(
@@ -117,28 +117,24 @@ impl ProxyHandler {
                     "Error parsing request body: {}",
                     err
                 );
-                Err(APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    cookies: None,
-                    body: Some(
-                        ErrorResponse {
-                            error: format!("Error parsing request body: {}", err),
-                        }
-                        .to_bytes(),
-                    ),
-                })
+                Err(Layer8Error::BadRequest(format!("Error parsing request body: {}", err)))
             }
         }
     }
 
-    pub(crate) fn decrypt_request_body(
+    /// Decrypts the request body with the nTor session key and parses it: an `L8RequestObject` for
+    /// `/proxy`, a `RekeyRequest` for `/rekey`.
+    pub(crate) fn decrypt_request_body<T>(
         request_body: EncryptedMessage,
         ntor_server_id: String,
-        shared_secret: Vec<u8>,
-    ) -> Result<L8RequestObject, APIHandlerResponse>
+        shared_secret: &[u8],
+    ) -> Result<T, Layer8Error>
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
     {
+        // the ntor crate takes its own copy of the secret, dropped with `ntor_server`
         let mut ntor_server = NTorServer::new(ntor_server_id);
-        ntor_server.set_shared_secret(shared_secret.clone());
+        ntor_server.set_shared_secret(shared_secret.to_vec());
 
         // Decrypt the request body using nTor shared secret
         let decrypted_data = ntor_server
@@ -146,46 +142,77 @@ impl ProxyHandler {
                 nonce: <[u8; 12]>::try_from(request_body.nonce).unwrap_or_default(),
                 data: request_body.data,
             })
-            .map_err(|err| {
-                return APIHandlerResponse {
-                    status: StatusCode::BAD_REQUEST,
-                    cookies: None,
-                    body: Some(format!("Decryption failed: {}", err).as_bytes().to_vec()),
-                };
-            })?;
+            .map_err(|err| Layer8Error::DecryptionFailed(format!("Decryption failed: {}", err)))?;
         // let decrypted_data = request_body.data;
 
-        // parse decrypted data into WrappedUserRequest
-        let wrapped_request: L8RequestObject = bytes_to_json(decrypted_data).map_err(|err| {
-            return APIHandlerResponse {
-                status: StatusCode::BAD_REQUEST,
-                cookies: None,
-                body: Some(
-                    format!("Failed to parse request body: {}", err)
-                        .as_bytes()
-                        .to_vec(),
-                ),
-            };
-        })?;
-
-        Ok(wrapped_request)
+        bytes_to_json(decrypted_data)
+            .map_err(|err| Layer8Error::BadRequest(format!("Failed to parse request body: {}", err)))
     }
 
+    /// Decrypts a request body sent as a framed stream, see `utils::stream`, as it is received.
+    /// The header frame holds the `L8RequestObject`, without its body; it is returned with the
+    /// stream of the body chunks, each opened once its whole frame is received.
+    ///
+    /// Every chunk is authenticated before it is forwarded, but the backend may receive the first
+    /// chunks of a body whose later frames are forged or missing: the stream then fails, which
+    /// aborts the request to the backend instead of completing it. `on_frame` is called with the
+    /// length of every frame, and dropped with the stream.
+    pub(crate) async fn decrypt_request_stream<F>(
+        request_body: BodyStream,
+        shared_secret: &[u8],
+        on_frame: F,
+    ) -> Result<(L8RequestObject, BodyStream), Layer8Error>
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        let mut frames = RequestFrames {
+            body: request_body,
+            decryptor: StreamDecryptor::new(shared_secret),
+            received: BytesMut::new(),
+            on_frame,
+        };
+
+        let wrapped_request = match frames.next().await? {
+            Some(Frame::Header(header)) => bytes_to_json::<L8RequestObject>(header)
+                .map_err(|err| Layer8Error::BadRequest(format!("Failed to parse request body: {}", err)))?,
+            _ => return Err(Layer8Error::BadRequest("Missing request stream header".to_string())),
+        };
+
+        let body = stream::unfold(Some(frames), |frames| async move {
+            let mut frames = frames?;
+            loop {
+                match frames.next().await {
+                    Ok(Some(Frame::Chunk { data, .. })) if data.is_empty() => continue,
+                    Ok(Some(Frame::Chunk { data, .. })) => return Some((Ok(Bytes::from(data)), Some(frames))),
+                    Ok(Some(Frame::Header(_))) => return Some((Err(stream_error(StreamError::Malformed)), None)),
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        });
+
+        Ok((wrapped_request, body.boxed()))
+    }
+
+    /// Sends the user request to the backend and returns its response as soon as its headers are
+    /// received: the `L8ResponseObject` without its body, and the response to read the body from.
+    /// The `Phases::BACKEND` timing keeps running until the body is read.
     pub(crate) async fn rebuild_user_request(
-        ctx: &Layer8Context,
+        ctx: &mut Layer8Context,
         backend_url: String,
-        wrapped_request: L8RequestObject
-    ) -> Result<L8ResponseObject, APIHandlerResponse>
+        wrapped_request: L8RequestObject,
+        request_body: reqwest::Body,
+    ) -> Result<(L8ResponseObject, reqwest::Response), Layer8Error>
     {
         let correlation_id = ctx.get_correlation_id();
         let mut header_map = utils::hashmap_to_headermap(&wrapped_request.headers)
             .unwrap_or_else(|_| HeaderMap::new());
 
-        if let Some(cookies) = ctx.request.header.get(reqwest::header::COOKIE.as_str()) {
-            if let Ok(cookie_hv) = reqwest::header::HeaderValue::from_str(cookies.as_ref()) {
+        for cookie in ctx.request.header.get_all(reqwest::header::COOKIE.as_str()) {
+            if let Ok(cookie_hv) = reqwest::header::HeaderValue::from_bytes(cookie) {
                 header_map.append(reqwest::header::COOKIE, cookie_hv);
             }
-        };
+        }
 
         debug!(
             %correlation_id,
@@ -204,12 +231,13 @@ impl ProxyHandler {
             "Send reconstructed request to origin backend URL: {}",
             origin_url
         );
+        ctx.timings_mut().start(Phases::BACKEND);
         let response = client.request(
             wrapped_request.method.parse().unwrap_or_default(),
             origin_url.as_str(),
         )
             .headers(header_map.clone())
-            .body(wrapped_request.body)
+            .body(request_body)
             .send()
             .await;
 
@@ -225,7 +253,11 @@ impl ProxyHandler {
                 let redirected = success_res.url().as_str() != origin_url;
 
                 let serialized_headers = utils::headermap_to_hashmap(&success_res.headers());
-                let serialized_body = success_res.bytes().await.unwrap_or_default().to_vec();
+                let cookies = success_res.headers()
+                    .get_all(reqwest::header::SET_COOKIE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok().map(|s| s.to_string()))
+                    .collect();
 
                 info!(
                     %correlation_id,
@@ -235,56 +267,109 @@ impl ProxyHandler {
                     url.as_str()
                 );
 
-                Ok(L8ResponseObject {
+                let wrapped_response = L8ResponseObject {
                     status,
                     status_text,
                     headers: serialized_headers,
-                    body: serialized_body,
+                    body: Vec::new(),
                     ok,
                     url,
                     redirected,
-                })
+                    cookies,
+                };
+                Ok((wrapped_response, success_res))
             }
             Err(err) => {
+                ctx.timings_mut().stop(Phases::BACKEND);
                 error!(
                     %correlation_id,
                     log_type=LogTypes::HANDLE_PROXY_REQUEST,
                     "Error while building request to BE: {:?}",
                     err
                 );
+                // a streamed request body that failed to be read or opened, see
+                // `decrypt_request_stream`, is answered with its own error
+                if let Some(err) = request_body_error(&err) {
+                    return Err(err);
+                }
                 let status = err.status().unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR);
-                let err_body = ErrorResponse {
-                    error: format!("Backend error: {}", status),
-                };
-
-                Err(APIHandlerResponse {
-                    status: StatusCode::BAD_GATEWAY,
-                    cookies: None,
-                    body: Some(err_body.to_bytes()),
-                })
+                Err(Layer8Error::BackendUnavailable(format!("Backend error: {}", status)))
             }
         }
     }
 
+    /// Reads the whole backend response body into `wrapped_response`.
+    pub(crate) async fn read_response_body(
+        ctx: &mut Layer8Context,
+        wrapped_response: &mut L8ResponseObject,
+        response: reqwest::Response,
+    )
+    {
+        wrapped_response.body = response.bytes().await.unwrap_or_default().to_vec();
+        ctx.timings_mut().stop(Phases::BACKEND);
+    }
+
+    /// Encrypts the backend response as a framed stream, see `utils::stream`: the header frame holds
+    /// `wrapped_response` without its body, then the body is sealed chunk by chunk as it is read.
+    /// `on_frame` is called with the length of every frame, and dropped with the stream.
+    pub(crate) fn encrypt_response_stream<F>(
+        wrapped_response: &L8ResponseObject,
+        response: reqwest::Response,
+        shared_secret: &[u8],
+        mut on_frame: F,
+    ) -> BodyStream
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        let (encryptor, header_frame) = StreamEncryptor::new(shared_secret, &wrapped_response.to_bytes());
+        on_frame(header_frame.len());
+        let body = response.bytes_stream().boxed();
+
+        stream::once(async move { Ok(Bytes::from(header_frame)) })
+            .chain(stream::unfold(Some((encryptor, body, on_frame)), |state| async move {
+                let (mut encryptor, mut body, mut on_frame) = state?;
+                loop {
+                    match body.next().await {
+                        Some(Ok(chunk)) if chunk.is_empty() => continue,
+                        Some(Ok(chunk)) => {
+                            let frames: Vec<u8> = chunk
+                                .chunks(CHUNK_SIZE)
+                                .flat_map(|piece| encryptor.seal_chunk(piece, false))
+                                .collect();
+                            on_frame(frames.len());
+                            return Some((Ok(Bytes::from(frames)), Some((encryptor, body, on_frame))));
+                        }
+                        Some(Err(err)) => {
+                            let err = Layer8Error::BackendUnavailable(format!("Backend error: {}", err));
+                            return Some((Err(err), None));
+                        }
+                        None => {
+                            let last = encryptor.seal_chunk(&[], true);
+                            on_frame(last.len());
+                            return Some((Ok(Bytes::from(last)), None));
+                        }
+                    }
+                }
+            }))
+            .boxed()
+    }
+
     pub(crate) fn encrypt_response_body(
         response_body: L8ResponseObject,
         ntor_server_id: String,
-        shared_secret: Vec<u8>,
-    ) -> Result<EncryptedMessage, APIHandlerResponse>
+        shared_secret: &[u8],
+    ) -> Result<EncryptedMessage, Layer8Error>
     {
+        // the ntor crate takes its own copy of the secret, dropped with `ntor_server`
         let mut ntor_server = NTorServer::new(ntor_server_id);
-        ntor_server.set_shared_secret(shared_secret);
+        ntor_server.set_shared_secret(shared_secret.to_vec());
 
         let data = response_body.to_bytes();
 
         // Encrypt the response body using nTor shared secret
-        let encrypted_data = ntor_server.encrypt(data).map_err(|err| {
-            return APIHandlerResponse {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                cookies: None,
-                body: Some(format!("Encryption failed: {}", err).as_bytes().to_vec()),
-            };
-        })?;
+        let encrypted_data = ntor_server
+            .encrypt(data)
+            .map_err(|err| Layer8Error::Internal(format!("Encryption failed: {}", err)))?;
 
         Ok(EncryptedMessage {
             nonce: encrypted_data.nonce,
@@ -292,3 +377,169 @@ impl ProxyHandler {
         })
     }
 }
+
+/// A forged frame is a decryption failure, anything else a malformed request.
+fn stream_error(err: StreamError) -> Layer8Error {
+    match err {
+        StreamError::Forged => Layer8Error::DecryptionFailed(format!("Decryption failed: {}", err)),
+        _ => Layer8Error::BadRequest(format!("Failed to parse request stream: {}", err)),
+    }
+}
+
+/// The error of the request body stream, when it is what failed the request to the backend.
+fn request_body_error(err: &reqwest::Error) -> Option<Layer8Error> {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<Layer8Error>() {
+            return Some(err.clone());
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// `RequestFrames` opens the frames of a streamed request body as its chunks are received, see
+/// `ProxyHandler::decrypt_request_stream`. A frame may span several chunks.
+struct RequestFrames<F> {
+    body: BodyStream,
+    decryptor: StreamDecryptor,
+    /// Received bytes of the frames not opened yet
+    received: BytesMut,
+    on_frame: F,
+}
+
+impl<F: FnMut(usize)> RequestFrames<F> {
+    /// Returns the next frame, or `None` once the body ended after its final chunk.
+    async fn next(&mut self) -> Result<Option<Frame>, Layer8Error> {
+        loop {
+            let mut input: &[u8] = &self.received;
+            if let Some(frame) = self.decryptor.open_frame(&mut input).map_err(stream_error)? {
+                let length = self.received.len() - input.len();
+                self.received.advance(length);
+                (self.on_frame)(length);
+                return Ok(Some(frame));
+            }
+
+            match self.body.next().await {
+                Some(chunk) => self.received.extend_from_slice(&chunk?),
+                // a partial frame is left
+                None if !self.received.is_empty() => return Err(stream_error(StreamError::Malformed)),
+                None => return self.decryptor.finish().map(|_| None).map_err(stream_error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use futures::executor::block_on;
+    use super::*;
+
+    const SECRET: &[u8] = b"this is a 32-byte shared secret!";
+
+    /// The frames of a streamed `POST /upload` carrying `chunks`.
+    fn request_frames(chunks: &[&[u8]]) -> Vec<u8> {
+        let header = serde_json::json!({
+            "method": "POST",
+            "uri": "/upload",
+            "headers": {},
+            "body": [],
+            "seq": 1,
+        });
+        let (mut encryptor, mut frames) = StreamEncryptor::new(SECRET, &serde_json::to_vec(&header).unwrap());
+        for (i, chunk) in chunks.iter().enumerate() {
+            frames.extend(encryptor.seal_chunk(chunk, i == chunks.len() - 1));
+        }
+        frames
+    }
+
+    /// `received` as it arrives from the client, `split` bytes at a time.
+    fn in_chunks(received: &[u8], split: usize) -> BodyStream {
+        let chunks: Vec<_> = received
+            .chunks(split)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        stream::iter(chunks).boxed()
+    }
+
+    fn decrypt(body: BodyStream) -> Result<(L8RequestObject, Vec<Result<Bytes, Layer8Error>>), Layer8Error> {
+        block_on(async {
+            let (request, body) = ProxyHandler::decrypt_request_stream(body, SECRET, |_| {}).await?;
+            Ok((request, body.collect().await))
+        })
+    }
+
+    #[test]
+    fn frames_are_opened_as_they_arrive() {
+        let frames = request_frames(&[b"first chunk", b"second chunk"]);
+
+        for split in [1, 7, frames.len()] {
+            let (request, body) = decrypt(in_chunks(&frames, split)).unwrap();
+            assert_eq!(
+                (request.method.as_str(), request.uri.as_str(), request.seq),
+                ("POST", "/upload", Some(1))
+            );
+            assert!(request.body.is_empty());
+            assert_eq!(body, vec![Ok(Bytes::from("first chunk")), Ok(Bytes::from("second chunk"))]);
+        }
+    }
+
+    #[test]
+    fn every_frame_is_counted() {
+        let frames = request_frames(&[b"first chunk", b""]);
+        let counted = Arc::new(Mutex::new(0));
+
+        let on_frame = {
+            let counted = counted.clone();
+            move |bytes| *counted.lock().unwrap() += bytes
+        };
+        block_on(async {
+            let (_, body) = ProxyHandler::decrypt_request_stream(in_chunks(&frames, 5), SECRET, on_frame)
+                .await
+                .unwrap();
+            body.collect::<Vec<_>>().await
+        });
+
+        assert_eq!(*counted.lock().unwrap(), frames.len());
+    }
+
+    #[test]
+    fn a_forged_frame_fails_the_body_after_the_chunks_before_it() {
+        let mut frames = request_frames(&[b"first chunk", b"second chunk"]);
+        let last = frames.len() - 1;
+        frames[last] ^= 1;
+
+        let (_, body) = decrypt(in_chunks(&frames, 7)).unwrap();
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0], Ok(Bytes::from("first chunk")));
+        assert!(matches!(body[1], Err(Layer8Error::DecryptionFailed(_))));
+    }
+
+    #[test]
+    fn a_truncated_body_fails_instead_of_ending() {
+        let frames = request_frames(&[b"first chunk", b"second chunk"]);
+        let (header_and_first, _) = frames.split_at(frames.len() - 20);
+
+        let (_, body) = decrypt(in_chunks(header_and_first, 7)).unwrap();
+        assert_eq!(body[0], Ok(Bytes::from("first chunk")));
+        assert!(matches!(body.last(), Some(Err(Layer8Error::BadRequest(_)))));
+
+        // without the final chunk frame
+        let without_last = request_frames(&[b"first chunk"]);
+        let (_, body) = decrypt(in_chunks(&without_last[..without_last.len() - 20], 7)).unwrap();
+        assert!(matches!(body.last(), Some(Err(Layer8Error::BadRequest(_)))));
+    }
+
+    #[test]
+    fn a_failed_read_fails_the_body_with_its_error() {
+        let frames = request_frames(&[b"first chunk", b"second chunk"]);
+        let received = [Ok(Bytes::copy_from_slice(&frames[..frames.len() - 10])), Err(Layer8Error::PayloadTooLarge(16))];
+        let (_, body) = decrypt(stream::iter(received).boxed()).unwrap();
+        assert_eq!(body.last(), Some(&Err(Layer8Error::PayloadTooLarge(16))));
+
+        // before the header frame is whole
+        let header_cut = [Ok(Bytes::copy_from_slice(&frames[..10])), Err(Layer8Error::PayloadTooLarge(16))];
+        assert_eq!(decrypt(stream::iter(header_cut).boxed()).err(), Some(Layer8Error::PayloadTooLarge(16)));
+    }
+}
@@ -9,7 +9,15 @@ pub struct L8RequestObject {
     pub method: String,
     pub uri: String,
     pub headers: HashMap<String, serde_json::Value>,
-    pub body: Vec<u8>
+    pub body: Vec<u8>,
+
+    /// Sequence number of the request in its tunnel session, from 1, see `ReplayWindow`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+
+    /// Answer with a framed stream, see `utils::stream`, instead of a single `EncryptedMessage`.
+    #[serde(default)]
+    pub stream_response: bool,
 }
 impl RequestBodyTrait for L8RequestObject {}
 
@@ -23,6 +31,10 @@ pub struct L8ResponseObject {
     pub url: String,
     pub redirected: bool,
 
+    /// `Set-Cookie` values returned by the backend, forwarded to the client as response headers.
+    #[serde(skip)]
+    pub cookies: Vec<String>,
+
     /* Other fields are ignored because reqwest does not support */
 }
 
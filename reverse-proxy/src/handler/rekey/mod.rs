@@ -0,0 +1,70 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use pingora_router::handler::{RequestBodyTrait, ResponseBodyTrait};
+use crate::config::HandlerConfig;
+use crate::handler::session_store::{KeyUsage, SessionKey};
+
+/// Body of `/rekey`, encrypted with the current session key to prove the interceptor holds it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RekeyRequest {
+    /// Generation of the key being replaced, the one of `int_rp_jwt`
+    pub key_generation: u32,
+
+    /// Sequence number of the request in its tunnel session, shared with `/proxy` requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl RequestBodyTrait for RekeyRequest {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RekeyResponse {
+    /// Replaces the `int_rp_jwt` of the tunnel, for the requests encrypted with the new key
+    #[serde(rename = "jwt1")]
+    pub int_rp_jwt: String,
+    pub key_generation: u32,
+}
+
+impl ResponseBodyTrait for RekeyResponse {}
+
+/// `KeyLimits` bound the use of an nTor session key.
+///
+/// Once a key has encrypted `max_messages` or `max_bytes`, or is `max_age` old, responses carry
+/// the `x-layer8-rekey` header and the interceptor calls `/rekey`. A key older than `max_age` plus
+/// `grace` is refused, so its tunnel has to be initialized again. A limit of 0 is no limit.
+///
+/// Sealed sessions are not rekeyed, see `NTorSessions::Sealed`: only `max_age` applies to them.
+pub struct KeyLimits {
+    max_messages: u64,
+    max_bytes: u64,
+    max_age: Duration,
+    grace: Duration,
+}
+
+impl KeyLimits {
+    pub fn new(config: &HandlerConfig) -> Self {
+        KeyLimits {
+            max_messages: config.ntor_rekey_max_messages,
+            max_bytes: config.ntor_rekey_max_bytes,
+            max_age: Duration::from_secs(config.ntor_key_max_age_secs),
+            grace: Duration::from_secs(config.ntor_rekey_grace_secs),
+        }
+    }
+
+    pub fn rekey_required(&self, key: &SessionKey, usage: KeyUsage) -> bool {
+        let reached = |limit: u64, value: u64| limit > 0 && value >= limit;
+
+        reached(self.max_messages, usage.messages)
+            || reached(self.max_bytes, usage.bytes)
+            || reached(self.max_age.as_secs(), key.age().as_secs())
+    }
+
+    pub fn is_expired(&self, key: &SessionKey) -> bool {
+        !self.max_age.is_zero() && key.age() > self.max_age + self.grace
+    }
+
+    /// How long a replaced key is still accepted
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+}
@@ -0,0 +1,229 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use pingora_router::error::Layer8Error;
+
+/// Number of sequence numbers below the highest one received that are still accepted, so
+/// concurrent requests may arrive out of order.
+const REPLAY_WINDOW: u64 = 64;
+
+/// `ReplayWindow` accepts each sequence number of a tunnel session once.
+///
+/// The interceptor numbers the requests of a tunnel session from 1, in the `seq` of the encrypted
+/// `L8RequestObject`, so the number can't be changed without failing decryption. As in IPsec
+/// (RFC 4303, section 3.4.3), a session keeps the highest number received and a bitmap of the
+/// `REPLAY_WINDOW` numbers below it: a number already seen, or older than the window, is a replay.
+///
+/// Stored sessions keep their window next to their key, see `NTorSessionStore::accept_sequence`,
+/// so it is dropped with the key; sealed sessions keep it in a `ReplayGuard`.
+#[derive(Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set when `highest - i` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Records `seq`, or returns a `Layer8Error::ReplayedMessage` if it can't be accepted.
+    pub fn accept(&mut self, seq: u64) -> Result<(), Layer8Error> {
+        if seq == 0 {
+            return Err(Layer8Error::BadRequest("seq starts at 1".to_string()));
+        }
+
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = seq;
+            return Ok(());
+        }
+
+        let offset = self.highest - seq;
+        if offset >= REPLAY_WINDOW {
+            return Err(Layer8Error::ReplayedMessage(format!(
+                "Message {} is older than the replay window",
+                seq
+            )));
+        }
+
+        let bit = 1 << offset;
+        if self.seen & bit != 0 {
+            return Err(Layer8Error::ReplayedMessage(format!("Message {} was already received", seq)));
+        }
+        self.seen |= bit;
+        Ok(())
+    }
+}
+
+/// `ReplayGuard` keeps the `ReplayWindow` of sealed sessions, which have no entry on the RP, for
+/// the `max_sessions` most recently used sessions.
+///
+/// A session without a window fails closed. Its window is created only if its key was derived
+/// after `floor`: the time this RP instance started, raised to the key time of every window
+/// evicted since. Otherwise the messages it already received are unknown, and the interceptor
+/// has to initialize a new tunnel.
+///
+/// The windows are local to this RP instance and nothing is shared between instances: with
+/// several RP instances, sealed sessions have no cross-instance replay protection. A request
+/// replayed to another instance than the one that received it is accepted once there.
+pub struct ReplayGuard {
+    inner: Mutex<ReplayGuardInner>,
+}
+
+struct ReplayGuardInner {
+    /// Windows by session id, with the Unix time of the session key, in seconds
+    windows: LruCache<String, (ReplayWindow, u64)>,
+    floor: u64,
+}
+
+impl ReplayGuard {
+    /// `started_at` is the Unix time, in seconds, this RP instance started.
+    pub fn new(max_sessions: usize, started_at: u64) -> Self {
+        let max_sessions = NonZeroUsize::new(max_sessions).unwrap_or(NonZeroUsize::MIN);
+
+        ReplayGuard {
+            inner: Mutex::new(ReplayGuardInner {
+                windows: LruCache::new(max_sessions),
+                floor: started_at,
+            }),
+        }
+    }
+
+    /// Creates the window of a session initialized by this RP instance.
+    pub fn register(&self, session_id: String, key_created_at: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.push(session_id, ReplayWindow::default(), key_created_at);
+    }
+
+    /// Records `seq` for the session whose key was derived at `key_created_at`, or returns a
+    /// `Layer8Error::ReplayedMessage` if it can't be accepted.
+    pub fn check(&self, session_id: &str, key_created_at: u64, seq: u64) -> Result<(), Layer8Error> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some((window, _)) = inner.windows.get_mut(session_id) {
+            return window.accept(seq);
+        }
+
+        if key_created_at <= inner.floor {
+            return Err(Layer8Error::InvalidSession(
+                "Unknown replay window of the nTor session, initialize a new tunnel".to_string(),
+            ));
+        }
+
+        // a session initialized by another RP instance since this one started or evicted a window
+        let mut window = ReplayWindow::default();
+        window.accept(seq)?;
+        inner.push(session_id.to_string(), window, key_created_at);
+        Ok(())
+    }
+}
+
+impl ReplayGuardInner {
+    fn push(&mut self, session_id: String, window: ReplayWindow, key_created_at: u64) {
+        // `push` also returns the previous window of the same session, which is not evicted
+        match self.windows.push(session_id.clone(), (window, key_created_at)) {
+            Some((evicted_id, (_, evicted_created_at))) if evicted_id != session_id => {
+                self.floor = self.floor.max(evicted_created_at);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_replay(result: Result<(), Layer8Error>) -> bool {
+        matches!(result, Err(Layer8Error::ReplayedMessage(_)))
+    }
+
+    #[test]
+    fn accepts_each_sequence_number_once() {
+        let mut window = ReplayWindow::default();
+
+        assert!(window.accept(1).is_ok());
+        assert!(window.accept(2).is_ok());
+        assert!(is_replay(window.accept(2)));
+        assert!(is_replay(window.accept(1)));
+        assert!(matches!(window.accept(0), Err(Layer8Error::BadRequest(_))));
+    }
+
+    #[test]
+    fn accepts_out_of_order_numbers_within_the_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(window.accept(100).is_ok());
+        assert!(window.accept(100 - REPLAY_WINDOW + 1).is_ok());
+        assert!(window.accept(99).is_ok());
+        assert!(is_replay(window.accept(100 - REPLAY_WINDOW)));
+        assert!(is_replay(window.accept(100 - REPLAY_WINDOW + 1)));
+        assert!(is_replay(window.accept(99)));
+    }
+
+    #[test]
+    fn slides_the_window_forward() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(1).is_ok());
+        assert!(window.accept(3).is_ok());
+
+        // 2 is still in the window, 1 and 3 are remembered after the shift
+        assert!(window.accept(1 + REPLAY_WINDOW - 1).is_ok());
+        assert!(is_replay(window.accept(1)));
+        assert!(is_replay(window.accept(3)));
+        assert!(window.accept(2).is_ok());
+
+        // a jump past the whole window forgets every number below it
+        assert!(window.accept(1000).is_ok());
+        assert!(is_replay(window.accept(1000 - REPLAY_WINDOW)));
+        assert!(window.accept(1000 - REPLAY_WINDOW + 1).is_ok());
+    }
+
+    #[test]
+    fn keeps_a_window_per_session() {
+        let guard = ReplayGuard::new(10, 100);
+        guard.register("a".to_string(), 101);
+        guard.register("b".to_string(), 101);
+
+        assert!(guard.check("a", 101, 1).is_ok());
+        assert!(guard.check("b", 101, 1).is_ok());
+        assert!(is_replay(guard.check("a", 101, 1)));
+    }
+
+    #[test]
+    fn fails_closed_on_sessions_older_than_this_instance() {
+        let guard = ReplayGuard::new(10, 100);
+
+        assert!(matches!(guard.check("a", 100, 1), Err(Layer8Error::InvalidSession(_))));
+
+        // initialized by another instance since this one started
+        assert!(guard.check("b", 101, 5).is_ok());
+        assert!(is_replay(guard.check("b", 101, 5)));
+    }
+
+    #[test]
+    fn fails_closed_on_a_session_whose_window_was_evicted() {
+        let guard = ReplayGuard::new(2, 100);
+        guard.register("a".to_string(), 110);
+        assert!(guard.check("a", 110, 1).is_ok());
+
+        guard.register("b".to_string(), 120);
+        guard.register("c".to_string(), 130);
+
+        // "a" was evicted: replaying its message must not start a new window
+        assert!(matches!(guard.check("a", 110, 1), Err(Layer8Error::InvalidSession(_))));
+
+        // the floor only rose to the evicted key, newer sessions are still accepted
+        assert!(guard.check("d", 111, 1).is_ok());
+    }
+
+    #[test]
+    fn registering_a_session_again_keeps_the_floor() {
+        let guard = ReplayGuard::new(2, 100);
+        guard.register("a".to_string(), 110);
+        guard.register("a".to_string(), 110);
+
+        assert!(guard.check("b", 105, 1).is_ok());
+    }
+}
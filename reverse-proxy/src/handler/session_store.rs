@@ -0,0 +1,557 @@
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hkdf::Hkdf;
+use lru::LruCache;
+use pingora_router::error::Layer8Error;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::config::HandlerConfig;
+use crate::handler::replay::ReplayWindow;
+
+/// nTor shared secret of a session. Every copy is wiped from memory when dropped.
+pub type SharedSecret = Zeroizing<Vec<u8>>;
+
+/// An nTor session key: the shared secret of the handshake, generation 0, or a key ratcheted
+/// from it by `/rekey`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionKey {
+    pub shared_secret: SharedSecret,
+    pub generation: u32,
+    /// Unix time the key was derived at, in seconds
+    pub created_at: u64,
+}
+
+impl SessionKey {
+    pub fn new(shared_secret: SharedSecret) -> Self {
+        SessionKey {
+            shared_secret,
+            generation: 0,
+            created_at: unix_now(),
+        }
+    }
+
+    /// Derives the key of the next generation with HKDF-SHA256. The interceptor derives the same
+    /// key on its side, so no new handshake is needed, and this key can't be recovered from the
+    /// next one.
+    pub fn ratchet(&self) -> SessionKey {
+        let generation = self.generation.wrapping_add(1);
+        let mut shared_secret = SharedSecret::new(vec![0u8; self.shared_secret.len()]);
+        Hkdf::<Sha256>::new(None, &self.shared_secret)
+            .expand_multi_info(&[b"layer8 ntor rekey ", &generation.to_be_bytes()], &mut shared_secret)
+            .expect("an nTor shared secret is a valid HKDF-SHA256 output length");
+
+        SessionKey {
+            shared_secret,
+            generation,
+            created_at: unix_now(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.created_at))
+    }
+}
+
+/// Messages and bytes encrypted with a session key since it was derived.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyUsage {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// `NTorSessionStore` keeps the nTor session key of each tunnel, by `ntor_session_id`.
+///
+/// A single store is shared by all pingora workers, so a session created by `/init-tunnel` on
+/// one thread is found by `/proxy` requests on any other. Expired sessions are never returned.
+pub trait NTorSessionStore: Send + Sync {
+    /// Returns the key of `generation`: the current key of the session, or the one it replaced
+    /// while in its grace window.
+    fn get(&self, session_id: &str, generation: u32) -> Option<SessionKey>;
+    /// Stores a session for `ttl`. When the store is full, the least recently used session is
+    /// evicted.
+    fn insert(&self, session_id: String, key: SessionKey, ttl: Duration);
+    /// Makes `key`, the ratchet of the current key, the current key of the session. The replaced
+    /// key stays usable for `grace`, for requests already in flight, then is dropped.
+    ///
+    /// Rekeying again to the current key is a no-op, so a retried `/rekey` succeeds. Returns false
+    /// when the session is unknown, or `key` is not the next generation.
+    fn rekey(&self, session_id: &str, key: SessionKey, grace: Duration) -> bool;
+    /// Adds `usage` to the usage of the current key and returns its usage so far. Messages with a
+    /// replaced key are not counted.
+    fn record_usage(&self, session_id: &str, generation: u32, usage: KeyUsage) -> KeyUsage;
+    /// Records the sequence number of a message in the session's `ReplayWindow`. An unknown or
+    /// expired session fails closed, with `Layer8Error::InvalidSession`.
+    fn accept_sequence(&self, session_id: &str, seq: u64) -> Result<(), Layer8Error>;
+    fn metrics(&self) -> SessionStoreMetrics;
+}
+
+/// Counters of a `NTorSessionStore` since the RP started.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SessionStoreMetrics {
+    /// Sessions currently stored, expired ones not yet evicted included
+    pub sessions: usize,
+    pub hits: u64,
+    /// Lookups of unknown or expired sessions
+    pub misses: u64,
+    pub expired: u64,
+    /// Sessions evicted before expiring because the store was full
+    pub evicted: u64,
+}
+
+struct SessionEntry {
+    key: SessionKey,
+    usage: KeyUsage,
+    /// The key replaced by the last rekey, until the end of its grace window
+    previous: Option<(SessionKey, Instant)>,
+    /// Sequence numbers received in the session, with any key
+    replay: ReplayWindow,
+    expires_at: Instant,
+}
+
+impl SessionEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// `MemoryNTorSessionStore` keeps sessions in memory, in LRU order, up to `max_entries`.
+///
+/// Expired sessions are dropped when looked up, or when the store is full, before evicting a live
+/// session. Dropping a session zeroizes its shared secret.
+pub struct MemoryNTorSessionStore {
+    sessions: Mutex<LruCache<String, SessionEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl MemoryNTorSessionStore {
+    pub fn new(max_entries: usize) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN);
+
+        MemoryNTorSessionStore {
+            sessions: Mutex::new(LruCache::new(max_entries)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl NTorSessionStore for MemoryNTorSessionStore {
+    fn get(&self, session_id: &str, generation: u32) -> Option<SessionKey> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+
+        let key = match sessions.get_mut(session_id) {
+            None => None,
+            Some(entry) if entry.is_expired(now) => {
+                sessions.pop(session_id);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some(entry) => {
+                // the grace window of the replaced key is over, drop it
+                if entry.previous.as_ref().is_some_and(|(_, until)| *until <= now) {
+                    entry.previous = None;
+                }
+
+                if entry.key.generation == generation {
+                    Some(entry.key.clone())
+                } else {
+                    entry.previous
+                        .as_ref()
+                        .filter(|(previous, _)| previous.generation == generation)
+                        .map(|(previous, _)| previous.clone())
+                }
+            }
+        };
+
+        let counter = if key.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        key
+    }
+
+    fn insert(&self, session_id: String, key: SessionKey, ttl: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+
+        // when full, make room with an expired session rather than the least recently used one
+        if sessions.len() >= sessions.cap().get() && !sessions.contains(&session_id) {
+            let expired_id = sessions
+                .iter()
+                .rev()
+                .find(|(_, entry)| entry.is_expired(now))
+                .map(|(id, _)| id.clone());
+            if let Some(expired_id) = expired_id {
+                sessions.pop(&expired_id);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let entry = SessionEntry {
+            key,
+            usage: KeyUsage::default(),
+            previous: None,
+            replay: ReplayWindow::default(),
+            expires_at: now + ttl,
+        };
+        // `push` also returns the previous entry of a replaced session
+        let evicted = sessions.push(session_id.clone(), entry);
+        if evicted.is_some_and(|(evicted_id, _)| evicted_id != session_id) {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn rekey(&self, session_id: &str, key: SessionKey, grace: Duration) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+
+        match sessions.get_mut(session_id) {
+            Some(entry) if !entry.is_expired(now) => {
+                if entry.key.generation == key.generation {
+                    return true;
+                }
+                if entry.key.generation.wrapping_add(1) != key.generation {
+                    return false;
+                }
+
+                let previous = std::mem::replace(&mut entry.key, key);
+                entry.previous = Some((previous, now + grace));
+                entry.usage = KeyUsage::default();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn record_usage(&self, session_id: &str, generation: u32, usage: KeyUsage) -> KeyUsage {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.peek_mut(session_id) {
+            Some(entry) if entry.key.generation == generation => {
+                entry.usage.messages += usage.messages;
+                entry.usage.bytes += usage.bytes;
+                entry.usage
+            }
+            _ => KeyUsage::default(),
+        }
+    }
+
+    fn accept_sequence(&self, session_id: &str, seq: u64) -> Result<(), Layer8Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.peek_mut(session_id) {
+            Some(entry) if !entry.is_expired(Instant::now()) => entry.replay.accept(seq),
+            _ => Err(Layer8Error::InvalidSession("Invalid or expired nTor session ID".to_string())),
+        }
+    }
+
+    fn metrics(&self) -> SessionStoreMetrics {
+        SessionStoreMetrics {
+            sessions: self.sessions.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `StreamedUsage` counts the bytes of a streamed request or response as they are decrypted or
+/// encrypted, and adds them to the usage of the session key when dropped: once the stream ended,
+/// failed or was abandoned by the client. The message itself is counted when the response starts.
+pub struct StreamedUsage {
+    store: Arc<dyn NTorSessionStore>,
+    session_id: String,
+    generation: u32,
+    bytes: u64,
+}
+
+impl StreamedUsage {
+    pub fn new(store: Arc<dyn NTorSessionStore>, session_id: String, generation: u32) -> Self {
+        StreamedUsage {
+            store,
+            session_id,
+            generation,
+            bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for StreamedUsage {
+    fn drop(&mut self) {
+        let usage = KeyUsage {
+            messages: 0,
+            bytes: self.bytes,
+        };
+        self.store.record_usage(&self.session_id, self.generation, usage);
+    }
+}
+
+/// `NTorSessions` is where the reverse proxy keeps the key of each nTor session.
+pub enum NTorSessions {
+    /// Looked up by `ntor_session_id` in a store of this RP instance.
+    Stored(Arc<dyn NTorSessionStore>),
+    /// Sealed into `int_rp_jwt` as a ticket, see `SessionTickets`. Any RP instance configured with
+    /// the same ticket key opens it, so instances are interchangeable and survive restarts.
+    ///
+    /// Nothing is kept per session, so a replaced key could not be dropped: sealed keys are not
+    /// rekeyed. They are only bounded by their age, see `KeyLimits`, then the tunnel is initialized
+    /// again; the message and byte limits are refused at startup.
+    Sealed(SessionTickets),
+}
+
+/// Builds the sessions configured by `ntor_session_store`: "memory" or "sealed".
+pub fn new_ntor_sessions(config: &HandlerConfig) -> Result<NTorSessions, String> {
+    match config.ntor_session_store.as_str() {
+        "memory" => Ok(NTorSessions::Stored(Arc::new(MemoryNTorSessionStore::new(
+            config.ntor_session_max_entries,
+        )))),
+        "sealed" if config.ntor_rekey_max_messages > 0 || config.ntor_rekey_max_bytes > 0 => Err(
+            "Sealed nTor sessions are not rekeyed, set ntor_rekey_max_messages and ntor_rekey_max_bytes to 0"
+                .to_string(),
+        ),
+        "sealed" => Ok(NTorSessions::Sealed(SessionTickets::new(
+            config.ntor_ticket_key.ok_or("Sealed nTor sessions require ntor_ticket_key")?,
+            Duration::from_secs(config.ntor_ticket_rotation_secs),
+            // a ticket lives as long as the `int_rp_jwt` carrying it
+            Duration::from_secs(config.jwt_exp_in_hours.max(0) as u64 * 3600),
+        ))),
+        mode => Err(format!("Unknown nTor session store \"{}\", expected \"memory\" or \"sealed\"", mode)),
+    }
+}
+
+/// Binds tickets to their purpose, see `utils::seal::seal`.
+const TICKET_AAD: &[u8] = b"ntor_session_ticket";
+
+/// `SessionTickets` seals nTor session keys into tickets, `<epoch>.<sealed secret>`, under a
+/// ticket key that rotates every `rotation`.
+///
+/// The key of an epoch is derived from the master key with HKDF-SHA256, so every RP instance
+/// sharing the master key derives the same keys without coordination. A ticket opens while its
+/// epoch is no older than `max_age`; tickets from the future are accepted one epoch ahead, for
+/// clock skew between instances.
+pub struct SessionTickets {
+    master_key: Zeroizing<[u8; 32]>,
+    rotation: Duration,
+    max_age: Duration,
+}
+
+impl SessionTickets {
+    pub fn new(master_key: [u8; 32], rotation: Duration, max_age: Duration) -> Self {
+        SessionTickets {
+            master_key: Zeroizing::new(master_key),
+            rotation: rotation.max(Duration::from_secs(1)),
+            max_age,
+        }
+    }
+
+    pub fn seal(&self, key: &SessionKey) -> String {
+        let epoch = self.current_epoch();
+        let sealed = utils::seal::seal(&self.key(epoch), TICKET_AAD, key);
+        format!("{}.{}", epoch, sealed)
+    }
+
+    pub fn open(&self, ticket: &str) -> Result<SessionKey, Layer8Error> {
+        let invalid = || Layer8Error::InvalidSession("Invalid or expired nTor session ticket".to_string());
+
+        let (epoch, sealed) = ticket.split_once('.').ok_or_else(invalid)?;
+        let epoch: u64 = epoch.parse().map_err(|_| invalid())?;
+
+        let current = self.current_epoch();
+        let max_age = self.max_age.as_secs().div_ceil(self.rotation.as_secs());
+        if epoch > current + 1 || epoch + max_age < current {
+            return Err(invalid());
+        }
+
+        utils::seal::open(&self.key(epoch), TICKET_AAD, sealed).map_err(|_| invalid())
+    }
+
+    fn current_epoch(&self) -> u64 {
+        unix_now() / self.rotation.as_secs()
+    }
+
+    fn key(&self, epoch: u64) -> Zeroizing<[u8; 32]> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &*self.master_key)
+            .expand_multi_info(&[b"layer8 ntor ticket key ", &epoch.to_be_bytes()], &mut *key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_key(secret: &[u8]) -> SessionKey {
+        SessionKey::new(SharedSecret::new(secret.to_vec()))
+    }
+
+    #[test]
+    fn ratchet_is_deterministic() {
+        let key = session_key(b"this is a 32-byte shared secret!");
+
+        let next = key.ratchet();
+        assert_eq!(next.generation, 1);
+        assert_eq!(next.shared_secret, key.ratchet().shared_secret);
+        assert_eq!(next.shared_secret.len(), key.shared_secret.len());
+        assert_ne!(next.shared_secret, key.shared_secret);
+
+        // bound to the generation and to the previous key
+        assert_ne!(next.ratchet().shared_secret, next.shared_secret);
+        assert_ne!(
+            session_key(b"this is another 32-byte secret!!").ratchet().shared_secret,
+            next.shared_secret
+        );
+    }
+
+    #[test]
+    fn rekey_keeps_the_replaced_key_during_its_grace() {
+        let store = MemoryNTorSessionStore::new(10);
+        let key = session_key(b"secret");
+        store.insert("a".to_string(), key.clone(), Duration::from_secs(60));
+
+        assert!(store.rekey("a", key.ratchet(), Duration::from_secs(60)));
+        assert_eq!(store.get("a", 1).unwrap().shared_secret, key.ratchet().shared_secret);
+        assert_eq!(store.get("a", 0).unwrap().shared_secret, key.shared_secret);
+
+        // a retried rekey is a no-op, skipping a generation is refused
+        assert!(store.rekey("a", key.ratchet(), Duration::from_secs(60)));
+        assert!(!store.rekey("a", key.ratchet().ratchet().ratchet(), Duration::from_secs(60)));
+        assert!(!store.rekey("b", key.ratchet(), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rekey_drops_the_replaced_key_after_its_grace() {
+        let store = MemoryNTorSessionStore::new(10);
+        let key = session_key(b"secret");
+        store.insert("a".to_string(), key.clone(), Duration::from_secs(60));
+
+        assert!(store.rekey("a", key.ratchet(), Duration::ZERO));
+        assert!(store.get("a", 0).is_none());
+        assert!(store.get("a", 1).is_some());
+
+        // a second rekey replaces the grace key too
+        let next = key.ratchet();
+        assert!(store.rekey("a", next.ratchet(), Duration::from_secs(60)));
+        assert!(store.get("a", 0).is_none());
+        assert!(store.get("a", 1).is_some());
+    }
+
+    #[test]
+    fn usage_is_counted_for_the_current_key() {
+        let store = MemoryNTorSessionStore::new(10);
+        let key = session_key(b"secret");
+        store.insert("a".to_string(), key.clone(), Duration::from_secs(60));
+
+        store.record_usage("a", 0, KeyUsage { messages: 1, bytes: 10 });
+        let usage = store.record_usage("a", 0, KeyUsage { messages: 1, bytes: 5 });
+        assert_eq!((usage.messages, usage.bytes), (2, 15));
+
+        assert!(store.rekey("a", key.ratchet(), Duration::from_secs(60)));
+        let usage = store.record_usage("a", 0, KeyUsage { messages: 1, bytes: 5 });
+        assert_eq!((usage.messages, usage.bytes), (0, 0));
+        let usage = store.record_usage("a", 1, KeyUsage { messages: 1, bytes: 5 });
+        assert_eq!((usage.messages, usage.bytes), (1, 5));
+    }
+
+    #[test]
+    fn sequence_numbers_fail_closed_without_a_session() {
+        let store = MemoryNTorSessionStore::new(1);
+        store.insert("a".to_string(), session_key(b"secret"), Duration::from_secs(60));
+        store.insert("expired".to_string(), session_key(b"secret"), Duration::ZERO);
+
+        assert!(matches!(store.accept_sequence("a", 1), Err(Layer8Error::InvalidSession(_))));
+        assert!(matches!(store.accept_sequence("expired", 1), Err(Layer8Error::InvalidSession(_))));
+
+        store.insert("a".to_string(), session_key(b"secret"), Duration::from_secs(60));
+        assert!(store.accept_sequence("a", 1).is_ok());
+        assert!(matches!(store.accept_sequence("a", 1), Err(Layer8Error::ReplayedMessage(_))));
+    }
+
+    fn tickets(master_key: &[u8; 32]) -> SessionTickets {
+        SessionTickets::new(*master_key, Duration::from_secs(60), Duration::from_secs(120))
+    }
+
+    /// A ticket sealed at `epoch`, as `SessionTickets::seal` would have then.
+    fn ticket_at(tickets: &SessionTickets, epoch: u64, key: &SessionKey) -> String {
+        format!("{}.{}", epoch, utils::seal::seal(&tickets.key(epoch), TICKET_AAD, key))
+    }
+
+    #[test]
+    fn tickets_open_with_the_same_master_key() {
+        let key = session_key(b"secret").ratchet();
+        let ticket = tickets(b"this is 32-byte RP ticket secret").seal(&key);
+
+        let opened = tickets(b"this is 32-byte RP ticket secret").open(&ticket).unwrap();
+        assert_eq!(opened.shared_secret, key.shared_secret);
+        assert_eq!(opened.generation, key.generation);
+        assert_eq!(opened.created_at, key.created_at);
+
+        assert!(tickets(b"this is another RP ticket secret").open(&ticket).is_err());
+    }
+
+    #[test]
+    fn tampered_tickets_are_refused() {
+        let tickets = tickets(b"this is 32-byte RP ticket secret");
+        let ticket = tickets.seal(&session_key(b"secret"));
+        let (epoch, sealed) = ticket.split_once('.').unwrap();
+        let epoch: u64 = epoch.parse().unwrap();
+
+        let mut tampered = sealed.to_string().into_bytes();
+        let middle = tampered.len() / 2;
+        tampered[middle] = if tampered[middle] == b'A' { b'B' } else { b'A' };
+        let tampered = format!("{}.{}", epoch, String::from_utf8(tampered).unwrap());
+
+        for ticket in [
+            tampered,
+            // sealed under another epoch's key
+            format!("{}.{}", epoch - 1, sealed),
+            sealed.to_string(),
+            format!("epoch.{}", sealed),
+            String::new(),
+        ] {
+            assert!(
+                matches!(tickets.open(&ticket), Err(Layer8Error::InvalidSession(_))),
+                "{}",
+                ticket
+            );
+        }
+    }
+
+    #[test]
+    fn expired_tickets_are_refused() {
+        let tickets = tickets(b"this is 32-byte RP ticket secret");
+        let key = session_key(b"secret");
+        let current = tickets.current_epoch();
+
+        // max_age is two epochs, and one epoch of clock skew ahead is accepted
+        assert!(tickets.open(&ticket_at(&tickets, current - 2, &key)).is_ok());
+        assert!(tickets.open(&ticket_at(&tickets, current + 1, &key)).is_ok());
+        assert!(matches!(
+            tickets.open(&ticket_at(&tickets, current - 3, &key)),
+            Err(Layer8Error::InvalidSession(_))
+        ));
+        assert!(matches!(
+            tickets.open(&ticket_at(&tickets, current + 2, &key)),
+            Err(Layer8Error::InvalidSession(_))
+        ));
+    }
+}
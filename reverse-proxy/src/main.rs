@@ -4,9 +4,11 @@ mod tls_conf;
 
 use crate::handler::ReverseHandler;
 use futures::FutureExt;
+use pingora::http::Method;
 use pingora::server::Server;
 use pingora::server::configuration::Opt;
 use pingora::{listeners::tls::TlsSettings, prelude::http_proxy_service};
+use pingora_router::extract::typed;
 use pingora_router::handler::APIHandler;
 use pingora_router::router::Router;
 use std::sync::Arc;
@@ -46,20 +48,24 @@ fn main() {
     })).unwrap();
     my_server.bootstrap();
 
-    let handle_init_tunnel: APIHandler<Arc<ReverseHandler>> =
-        Box::new(|h, ctx| async move { h.handle_init_tunnel(ctx).await }.boxed());
+    let handle_init_tunnel: APIHandler<Arc<ReverseHandler>> = typed(ReverseHandler::handle_init_tunnel);
 
+    // streams its response and records timings, so it works on the context itself
     let handle_proxy: APIHandler<Arc<ReverseHandler>> =
         Box::new(|h, ctx| async move { h.handle_proxy_request(ctx).await }.boxed());
 
-    let handle_healthcheck: APIHandler<Arc<ReverseHandler>> =
-        Box::new(|h, ctx| async move { h.handle_healthcheck(ctx).await }.boxed());
+    let handle_rekey: APIHandler<Arc<ReverseHandler>> = typed(ReverseHandler::handle_rekey);
+
+    let handle_healthcheck: APIHandler<Arc<ReverseHandler>> = typed(ReverseHandler::handle_healthcheck);
 
     let rp_handler = Arc::new(ReverseHandler::new(rp_config.clone()));
     let mut router: Router<Arc<ReverseHandler>> = Router::new(rp_handler.clone());
     router.post("/init-tunnel".to_string(), Box::new([handle_init_tunnel]));
     router.post("/proxy".to_string(), Box::new([handle_proxy]));
+    router.post("/rekey".to_string(), Box::new([handle_rekey]));
     router.get("/healthcheck".to_string(), Box::new([handle_healthcheck]));
+    router.max_body_size(rp_config.server.max_request_body_size);
+    router.route_max_body_size(Method::POST, "/init-tunnel", rp_config.server.init_tunnel_max_body_size);
 
     let mut my_proxy = http_proxy_service(
         &my_server.configuration,
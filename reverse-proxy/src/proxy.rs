@@ -3,10 +3,17 @@ use pingora::proxy::Session;
 use pingora::http::{ResponseHeader, StatusCode};
 use async_trait::async_trait;
 use bytes::Bytes;
-use tracing::{info};
+use std::pin::pin;
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::{SinkExt, StreamExt};
+use tracing::{error, info};
 use pingora_router::ctx::{Layer8Context, Layer8ContextTrait};
+use pingora_router::error::Layer8Error;
+use pingora_router::handler::APIHandlerResponse;
 use pingora_router::router::Router;
 use crate::handler::common::consts::LogTypes;
+use crate::handler::common::stream::{BodyStream, StreamedBody, StreamedRequestBody};
 use crate::tls_conf::ProxyConfig;
 
 pub struct ReverseProxy<T> {
@@ -30,16 +37,17 @@ impl<T> ReverseProxy<T> {
     ) -> pingora::Result<()> {
         let mut header = ResponseHeader::build(response_status, None)?;
 
-        let response_header = ctx.get_response_header().clone();
-        for (key, val) in response_header.iter() {
-            header.insert_header(key.clone(), val.clone()).unwrap_or_default();
-        };
+        ctx.get_response_header().write_to_response(&mut header)?;
 
-        // Common headers
-        header.insert_header("Content-Type", "application/json").unwrap_or_default();
-        header
-            .insert_header("Access-Control-Allow-Methods", "*")
-            .unwrap_or_default();
+        // Common headers; handlers may set their own Content-Type (e.g. error responses)
+        if !ctx.get_response_header().contains_key("Content-Type") {
+            header.insert_header("Content-Type", "application/json").unwrap_or_default();
+        }
+        if let Some(allow) = self.router.allow(&ctx.path()) {
+            header
+                .insert_header("Access-Control-Allow-Methods", allow)
+                .unwrap_or_default();
+        }
         header
             .insert_header("Access-Control-Max-Age", "86400")
             .unwrap_or_default();
@@ -69,6 +77,12 @@ impl<T> ReverseProxy<T> {
             header.insert_header("Access-Control-Allow-Headers", req_headers).unwrap_or_default();
         }
 
+        if self.config.server_timing_enabled {
+            header
+                .insert_header("Server-Timing", ctx.timings().server_timing())
+                .unwrap_or_default();
+        }
+
         let correlation_id = ctx.get_correlation_id();
         info!(
             %correlation_id,
@@ -78,6 +92,99 @@ impl<T> ReverseProxy<T> {
         );
         session.write_response_header_ref(&header).await
     }
+
+    /// Calls the handler while the request body is read from the client, so a streamed upload is
+    /// forwarded as it arrives instead of being buffered, see `StreamedRequestBody`. The route's
+    /// body limit applies to the bytes read.
+    async fn call_handler_streaming_body(
+        &self,
+        session: &mut Session,
+        ctx: &mut Layer8Context,
+    ) -> APIHandlerResponse {
+        let limit = self.router.body_limit(&ctx.method(), &ctx.path());
+        let (sender, receiver) = mpsc::channel(REQUEST_STREAM_BUFFER);
+        ctx.extensions_mut().insert(StreamedRequestBody::new(receiver.boxed()));
+
+        let (handler_response, body_read) = {
+            let handler = pin!(self.router.call_handler(ctx));
+            let reader = pin!(read_request_stream(session, limit, sender));
+            match select(handler, reader).await {
+                // the handler answered without reading the whole body, e.g. an invalid session
+                Either::Left((handler_response, _)) => (handler_response, false),
+                Either::Right((body_read, handler)) => (handler.await, body_read),
+            }
+        };
+
+        if !body_read {
+            // the rest of the body is still on the connection
+            session.set_keepalive(None);
+        }
+        handler_response
+    }
+
+    /// Writes a `StreamedBody` to the client chunk by chunk. The response header is already sent,
+    /// so a failing stream can only abort the response.
+    async fn write_streamed_body(
+        &self,
+        session: &mut Session,
+        ctx: &mut Layer8Context,
+        mut stream: BodyStream,
+    ) -> pingora::Result<()> {
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => session.write_response_body(Some(chunk), false).await?,
+                Err(err) => {
+                    error!(
+                        correlation_id=ctx.get_correlation_id(),
+                        log_type=LogTypes::HANDLE_BACKEND_RESPONSE,
+                        "Error while streaming response body: {}",
+                        err
+                    );
+                    session.set_keepalive(None);
+                    return Err(pingora::Error::explain(
+                        pingora::ErrorType::InternalError,
+                        format!("Response body stream failed: {}", err),
+                    ));
+                }
+            }
+        }
+
+        session.write_response_body(None, true).await
+    }
+}
+
+/// Chunks of a streamed request body read ahead of the handler.
+const REQUEST_STREAM_BUFFER: usize = 16;
+
+/// Sends the request body to `sender` as it is read from the client, see
+/// `ReverseProxy::call_handler_streaming_body`. Returns whether the body was read to its end.
+async fn read_request_stream(
+    session: &mut Session,
+    limit: Option<usize>,
+    mut sender: mpsc::Sender<Result<Bytes, Layer8Error>>,
+) -> bool {
+    let mut read = 0;
+    loop {
+        let chunk = match session.read_request_body().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return true,
+            Err(err) => {
+                let err = Layer8Error::BadRequest(format!("Failed to read request body: {}", err));
+                let _ = sender.send(Err(err)).await;
+                return false;
+            }
+        };
+
+        read += chunk.len();
+        if let Some(limit) = limit.filter(|limit| read > *limit) {
+            let _ = sender.send(Err(Layer8Error::PayloadTooLarge(limit))).await;
+            return false;
+        }
+        if sender.send(Ok(chunk)).await.is_err() {
+            // the handler stopped reading the body
+            return false;
+        }
+    }
 }
 
 #[async_trait]
@@ -116,30 +223,55 @@ impl<T: Sync> ProxyHttp for ReverseProxy<T> {
             user_agent = ctx.request.header.get("User-Agent"),
         );
 
-        ctx.read_request_body(session).await?;
+        let streamed_request = ctx
+            .get_request_header()
+            .get("content-type")
+            .is_some_and(|content_type| content_type == utils::stream::CONTENT_TYPE);
 
-        let handler_response = self.router.call_handler(ctx).await;
-        if handler_response.status == StatusCode::NOT_FOUND && handler_response.body.is_none() {
-            let header = ResponseHeader::build(StatusCode::NOT_FOUND, None)?;
-            session.write_response_header_ref(&header).await?;
-            session.set_keepalive(None);
-            return Ok(true);
-        }
+        // a streamed body is read while the handler forwards it; an oversized body is answered
+        // right away, without reading the rest of it
+        let handler_response = if streamed_request {
+            self.call_handler_streaming_body(session, ctx).await
+        } else {
+            match self.router.read_request_body(session, ctx).await? {
+                Some(response) => {
+                    info!(
+                        %correlation_id,
+                        log_type=LogTypes::ACCESS_LOG,
+                        "Request body too large, content-length: {:?}",
+                        ctx.request_content_length()
+                    );
+                    session.set_keepalive(None);
+                    response
+            }
+            None => self.router.call_handler(ctx).await,
+            }
+        };
+        let streamed_body = ctx.extensions_mut()
+            .remove::<StreamedBody>()
+            .and_then(|body| body.take());
 
         let mut response_bytes = vec![];
-        if let Some(body_bytes) = handler_response.body {
+        if streamed_body.is_some() {
+            // the length of a streamed body is unknown until it ends
+            ctx.insert_response_header("Transfer-Encoding", "chunked");
+        } else if let Some(body_bytes) = handler_response.body {
             ctx.insert_response_header("Content-length", &body_bytes.len().to_string());
             response_bytes = body_bytes;
         };
 
         // set cookies to response
-        if let Some(cookies) = handler_response.cookies {
-            ctx.insert_response_header("Set-Cookie", &cookies);
+        for cookie in handler_response.cookies.iter() {
+            ctx.append_response_header("Set-Cookie", cookie);
         }
         self.set_headers(session, ctx, handler_response.status).await?;
 
         // Write the response body to the session after setting headers
-        session.write_response_body(Some(Bytes::from(response_bytes)), true).await?;
+        match streamed_body {
+            Some(stream) => self.write_streamed_body(session, ctx, stream).await?,
+            None => session.write_response_body(Some(Bytes::from(response_bytes)), true).await?,
+        }
+        ctx.timings_mut().finish();
 
         Ok(true)
     }
@@ -150,6 +282,8 @@ impl<T: Sync> ProxyHttp for ReverseProxy<T> {
         e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        // the response is written by now, unless `request_filter` already recorded it
+        ctx.timings_mut().finish();
         let mut status = ctx.response.status.as_u16();
         if let Some(_err) = e {
             status = session.response_written().unwrap().status.as_u16();
@@ -163,7 +297,8 @@ impl<T: Sync> ProxyHttp for ReverseProxy<T> {
             origin = ctx.request.header.get("origin"),
             referer = ctx.request.header.get("referer"),
             status=status,
-            latency_ms=ctx.get_latency_ms(), // todo: is it necessary?
+            latency_ms=ctx.get_latency_ms(),
+            timings=%ctx.timings(),
             response_body_size=ctx.get_response_body().len(),
             user_agent=ctx.request.header.get("User-Agent"),
             error=?e,
@@ -17,7 +17,11 @@ pub struct ProxyConfig {
     #[serde(deserialize_with = "utils::deserializer::string_to_bool")]
     pub cors_allow_credentials: bool,
     #[serde(deserialize_with = "utils::deserializer::string_to_vec")]
-    pub cors_allow_origins: Vec<String>
+    pub cors_allow_origins: Vec<String>,
+    /// Adds a `Server-Timing` header with the duration of each request phase to responses.
+    /// Default to false, as it discloses the time spent in each phase
+    #[serde(default, deserialize_with = "utils::deserializer::string_to_bool")]
+    pub server_timing_enabled: bool,
 }
 
 #[async_trait::async_trait]
@@ -22,7 +22,7 @@ where
     })?;
     let bytes = s.into_bytes();
     if bytes.len() != 32 {
-        return Err(serde::de::Error::custom("Expected 32 bytes for nTor static secret"));
+        return Err(serde::de::Error::custom("Expected a 32 bytes secret"));
     }
     let mut array = [0u8; 32];
     array.copy_from_slice(&bytes);
@@ -72,3 +72,21 @@ where
         .map(|item| item.trim().to_string())
         .collect())
 }
+
+pub fn string_to_vec_number<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr, <T as FromStr>::Err: std::fmt::Display,
+{
+    let s: String = Deserialize::deserialize(deserializer).map_err(|e| {
+        serde::de::Error::custom(format!("Failed to deserialize string to Vec of numbers: {}", e))
+    })?;
+
+    if s.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    s.split(',')
+        .map(|item| item.trim().parse::<T>().map_err(serde::de::Error::custom))
+        .collect()
+}
@@ -0,0 +1,107 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::config::LookupIpStrategy;
+use url::{Host, Url};
+
+/// `DnsResolver` resolves URLs to socket addresses without blocking the async runtime.
+///
+/// Answers are cached for their record's TTL, capped by `max_ttl`, so a host is only queried again
+/// once its records expire. The nameservers and `/etc/hosts` are read from the system
+/// configuration.
+///
+/// # Example
+/// ```rust,ignore
+/// let resolver = DnsResolver::from_system_conf(1024, Duration::from_secs(300))?;
+/// let addresses = resolver.resolve(&url).await?; // [::1]:6193, 127.0.0.1:6193
+/// ```
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    pub fn from_system_conf(cache_size: usize, max_ttl: Duration) -> io::Result<Self> {
+        let (config, mut opts) = hickory_resolver::system_conf::read_system_conf()?;
+        opts.cache_size = cache_size;
+        opts.positive_max_ttl = Some(max_ttl);
+        // query A and AAAA records, to interleave both families
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+        Ok(DnsResolver {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+        })
+    }
+
+    /// Returns the socket addresses of `url`'s host, in the order to try them, see
+    /// `happy_eyeballs_order`. IP literals are returned as is, without a lookup.
+    pub async fn resolve(&self, url: &Url) -> io::Result<Vec<SocketAddr>> {
+        let port = url.port_or_known_default().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("No port for {}", url))
+        })?;
+
+        let ips = match url.host() {
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("No host in {}", url))),
+            Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+            Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+            Some(Host::Domain(domain)) => self.resolver.lookup_ip(domain).await?.iter().collect(),
+        };
+
+        Ok(happy_eyeballs_order(ips)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
+
+/// Orders addresses as Happy Eyeballs (RFC 8305, section 4) does: IPv6 first, then alternating
+/// between IPv6 and IPv4, keeping the resolver's order within each family. When the first
+/// address can't be reached, the next one tried is of the other family.
+pub fn happy_eyeballs_order(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) = ips.into_iter().partition(IpAddr::is_ipv6);
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (ip6, ip4) => ordered.extend(ip6.into_iter().chain(ip4)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn families_are_interleaved_from_ipv6() {
+        let ordered = happy_eyeballs_order(ips(&["10.0.0.1", "10.0.0.2", "::1", "::2"]));
+        assert_eq!(ordered, ips(&["::1", "10.0.0.1", "::2", "10.0.0.2"]));
+    }
+
+    #[test]
+    fn the_remaining_addresses_of_a_family_come_last_in_order() {
+        let ordered = happy_eyeballs_order(ips(&["::1", "10.0.0.1", "::2", "::3"]));
+        assert_eq!(ordered, ips(&["::1", "10.0.0.1", "::2", "::3"]));
+
+        let ordered = happy_eyeballs_order(ips(&["10.0.0.3", "10.0.0.1", "::1", "10.0.0.2"]));
+        assert_eq!(ordered, ips(&["::1", "10.0.0.3", "10.0.0.1", "10.0.0.2"]));
+    }
+
+    #[test]
+    fn a_single_family_keeps_its_order() {
+        let only_v4 = ips(&["10.0.0.2", "10.0.0.1"]);
+        assert_eq!(happy_eyeballs_order(only_v4.clone()), only_v4);
+
+        let only_v6 = ips(&["::2", "::1"]);
+        assert_eq!(happy_eyeballs_order(only_v6.clone()), only_v6);
+
+        assert!(happy_eyeballs_order(vec![]).is_empty());
+    }
+}
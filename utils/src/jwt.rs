@@ -100,9 +100,20 @@ pub struct JWTClaims {
     #[serde(skip_serializing_if = "Option::is_none", rename(serialize = "sid", deserialize = "sid"))]
     pub ntor_session_id: Option<String>,
 
+    /// Generation of the nTor session key `int_rp_jwt` is used with, incremented by every rekey.
+    /// Absent for the key of the handshake.
+    #[serde(skip_serializing_if = "Option::is_none", rename(serialize = "kgen", deserialize = "kgen"))]
+    pub key_generation: Option<u32>,
+
     /// The `uuid` claim is used to uniquely identify the token and help prevent race conditions.
     pub uuid: Option<String>,
 
+    /// Encrypted state carried by the token itself, see `crate::seal`.
+    /// Used in `int_fp_jwt` to hold the ForwardProxy session when sessions are not stored, and in
+    /// `int_rp_jwt` to hold the nTor session ticket when the ReverseProxy seals its sessions.
+    #[serde(skip_serializing_if = "Option::is_none", rename(serialize = "ses", deserialize = "ses"))]
+    pub sealed: Option<String>,
+
     // Additional custom claims can be added here as needed.
 }
 
@@ -129,7 +140,9 @@ impl JWTClaims {
             jti: None,
             rp_host: None,
             ntor_session_id: None,
+            key_generation: None,
             uuid: None,
+            sealed: None,
         }
     }
 
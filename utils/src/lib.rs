@@ -2,6 +2,9 @@ pub mod jwt;
 pub mod cert;
 pub mod deserializer;
 pub mod log;
+pub mod seal;
+pub mod dns;
+pub mod stream;
 
 use url::Url;
 
@@ -135,14 +138,6 @@ pub fn validate_url(url: &str) -> Option<Url> {
     Url::parse(url).ok()
 }
 
-pub fn get_socket_addrs(url: &Url) -> String {
-    url.socket_addrs(|| None).unwrap_or_default()
-        .iter()
-        .map(|addr| addr.to_string())
-        .collect::<Vec<String>>()
-        .join(",")
-}
-
 pub fn bincode_to_type<T: bincode::de::Decode<()>>(
     data: &[u8],
 ) -> Result<T, bincode::error::DecodeError> {
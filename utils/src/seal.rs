@@ -0,0 +1,82 @@
+use std::fmt;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use zeroize::Zeroizing;
+
+/// Length of the random nonce prefixed to every sealed value.
+const NONCE_LEN: usize = 24;
+
+/// Error returned by `open`. It does not tell why opening failed, on purpose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SealError {
+    /// The value is not valid base64url, or too short to hold a nonce.
+    Malformed,
+    /// The value was not sealed with this key and `aad`, or was tampered with.
+    Forged,
+    /// The value opened, but is not the expected type.
+    InvalidPayload,
+}
+
+impl fmt::Display for SealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SealError::Malformed => write!(f, "malformed sealed value"),
+            SealError::Forged => write!(f, "sealed value could not be authenticated"),
+            SealError::InvalidPayload => write!(f, "invalid sealed payload"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// Serializes `value` to JSON and encrypts it with XChaCha20-Poly1305, returning
+/// `base64url(nonce || ciphertext)`. The result can be embedded in a JWT claim or a header.
+///
+/// `aad` is authenticated but not encrypted: it binds the value to its purpose, so a value sealed
+/// for one purpose does not open for another with the same key.
+///
+/// # Example
+/// ```rust
+/// # let key = [7u8; 32];
+/// # let session = vec!["backend".to_string(), "session key".to_string()];
+/// let sealed = utils::seal::seal(&key, b"int_fp_session", &session);
+/// let opened: Vec<String> = utils::seal::open(&key, b"int_fp_session", &sealed)?;
+/// assert_eq!(opened, session);
+/// # Ok::<(), utils::seal::SealError>(())
+/// ```
+pub fn seal<T: Serialize>(key: &[u8; 32], aad: &[u8], value: &T) -> String {
+    // the value may hold a secret, e.g. an nTor session key, wiped once sealed
+    let plaintext = Zeroizing::new(serde_json::to_vec(value).unwrap());
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: &plaintext, aad })
+        .unwrap();
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    URL_SAFE_NO_PAD.encode(sealed)
+}
+
+/// Decrypts and deserializes a value produced by `seal` with the same `key` and `aad`.
+pub fn open<T: DeserializeOwned>(key: &[u8; 32], aad: &[u8], sealed: &str) -> Result<T, SealError> {
+    let sealed = URL_SAFE_NO_PAD.decode(sealed).map_err(|_| SealError::Malformed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(SealError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map(Zeroizing::new)
+        .map_err(|_| SealError::Forged)?;
+
+    serde_json::from_slice(&plaintext).map_err(|_| SealError::InvalidPayload)
+}
@@ -0,0 +1,361 @@
+use std::fmt;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Media type of a body encrypted as a framed stream.
+pub const CONTENT_TYPE: &str = "application/vnd.layer8.stream";
+
+/// Largest plaintext of a body chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest frame accepted by `StreamDecryptor`: a sealed chunk, or a header frame with its
+/// status and headers.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+const LENGTH_PREFIX_LEN: usize = 4;
+const SALT_LEN: usize = 32;
+
+/// Error returned by `StreamDecryptor`. It does not tell why a frame failed to open, on purpose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamError {
+    /// A frame is longer than `MAX_FRAME_LEN`, too short, or follows the final chunk.
+    Malformed,
+    /// A frame was not sealed with this key, at this position, or was tampered with.
+    Forged,
+    /// The stream ended before its final chunk.
+    Truncated,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Malformed => write!(f, "malformed stream frame"),
+            StreamError::Forged => write!(f, "stream frame could not be authenticated"),
+            StreamError::Truncated => write!(f, "stream ended before its final chunk"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// A decrypted frame of a stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// The first frame, e.g. the status and headers of a response
+    Header(Vec<u8>),
+    /// A piece of the body. Nothing follows the `last` one, which may be empty.
+    Chunk { data: Vec<u8>, last: bool },
+}
+
+/// `StreamEncryptor` encrypts a message as a stream of frames, so a large body is sent while it
+/// is read instead of being buffered and sealed at once.
+///
+/// Every frame is `length (u32, big endian) || payload`:
+/// - the header frame comes first, its payload is `salt (32 bytes) || sealed header`;
+/// - each body chunk frame's payload is a sealed chunk of at most `CHUNK_SIZE` bytes.
+///
+/// Frames are sealed with ChaCha20-Poly1305 under a key derived from the nTor shared secret and
+/// the stream's random salt with HKDF-SHA256, so no two streams share a key. The nonce of a frame
+/// is its position in the stream and a final-chunk flag, as in the STREAM construction:
+/// reordered, dropped or truncated frames fail to open.
+///
+/// # Example
+/// ```rust
+/// # use utils::stream::{StreamEncryptor, CHUNK_SIZE};
+/// # let shared_secret = [7u8; 32];
+/// # let body = vec![0u8; 3 * CHUNK_SIZE];
+/// # let mut sent = Vec::new();
+/// # let mut send = |frame: Vec<u8>| sent.push(frame);
+/// let (mut encryptor, header_frame) = StreamEncryptor::new(&shared_secret, b"status and headers");
+/// send(header_frame);
+/// for chunk in body.chunks(CHUNK_SIZE) {
+///     send(encryptor.seal_chunk(chunk, false));
+/// }
+/// send(encryptor.seal_chunk(&[], true));
+/// ```
+pub struct StreamEncryptor {
+    cipher: ChaCha20Poly1305,
+    position: u64,
+}
+
+impl StreamEncryptor {
+    /// Starts a stream, returning its encryptor and its header frame, carrying `header`.
+    pub fn new(shared_secret: &[u8], header: &[u8]) -> (Self, Vec<u8>) {
+        let salt = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut encryptor = StreamEncryptor {
+            cipher: stream_cipher(shared_secret, &salt),
+            position: 0,
+        };
+
+        let sealed = encryptor.seal(header, false);
+        (encryptor, frame(&[&salt, &sealed]))
+    }
+
+    /// Returns the frame of a body chunk of at most `CHUNK_SIZE` bytes. The `last` chunk ends the
+    /// stream, it may be empty.
+    pub fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Vec<u8> {
+        debug_assert!(chunk.len() <= CHUNK_SIZE);
+        let sealed = self.seal(chunk, last);
+        frame(&[&sealed])
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool) -> Vec<u8> {
+        let nonce = frame_nonce(self.position, last);
+        self.position += 1;
+        self.cipher.encrypt(&nonce, plaintext).unwrap()
+    }
+}
+
+/// `StreamDecryptor` opens the frames of a `StreamEncryptor` stream, in order, as they are
+/// received.
+///
+/// # Example
+/// ```rust
+/// # use utils::stream::{StreamDecryptor, StreamEncryptor};
+/// # let shared_secret = [7u8; 32];
+/// # let (mut encryptor, mut received) = StreamEncryptor::new(&shared_secret, b"status and headers");
+/// # received.extend(encryptor.seal_chunk(b"body", true));
+/// let mut decryptor = StreamDecryptor::new(&shared_secret);
+/// let mut input: &[u8] = &received;
+/// while let Some(frame) = decryptor.open_frame(&mut input)? {
+///     // Frame::Header first, then Frame::Chunk until the last one
+/// }
+/// decryptor.finish()?;
+/// # Ok::<(), utils::stream::StreamError>(())
+/// ```
+pub struct StreamDecryptor {
+    shared_secret: Zeroizing<Vec<u8>>,
+    /// Set by the header frame
+    cipher: Option<ChaCha20Poly1305>,
+    position: u64,
+    finished: bool,
+}
+
+impl StreamDecryptor {
+    pub fn new(shared_secret: &[u8]) -> Self {
+        StreamDecryptor {
+            shared_secret: Zeroizing::new(shared_secret.to_vec()),
+            cipher: None,
+            position: 0,
+            finished: false,
+        }
+    }
+
+    /// Opens the frame at the start of `input` and advances `input` past it. Returns `None`, with
+    /// `input` unchanged, while `input` doesn't hold a whole frame.
+    pub fn open_frame(&mut self, input: &mut &[u8]) -> Result<Option<Frame>, StreamError> {
+        let Some(prefix) = input.get(..LENGTH_PREFIX_LEN) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        if length > MAX_FRAME_LEN || self.finished {
+            return Err(StreamError::Malformed);
+        }
+        let Some(payload) = input.get(LENGTH_PREFIX_LEN..LENGTH_PREFIX_LEN + length) else {
+            return Ok(None);
+        };
+        *input = &input[LENGTH_PREFIX_LEN + length..];
+
+        let frame = match &self.cipher {
+            None => {
+                if payload.len() < SALT_LEN {
+                    return Err(StreamError::Malformed);
+                }
+                let (salt, sealed) = payload.split_at(SALT_LEN);
+                let cipher = stream_cipher(&self.shared_secret, Key::from_slice(salt));
+                let header = open(&cipher, self.position, false, sealed)?;
+                self.cipher = Some(cipher);
+                Frame::Header(header)
+            }
+            Some(cipher) => {
+                // the final-chunk flag is bound into the nonce, so only one of them opens
+                let (data, last) = match open(cipher, self.position, false, payload) {
+                    Ok(data) => (data, false),
+                    Err(_) => (open(cipher, self.position, true, payload)?, true),
+                };
+                self.finished = last;
+                Frame::Chunk { data, last }
+            }
+        };
+
+        self.position += 1;
+        Ok(Some(frame))
+    }
+
+    /// Checks the stream ended with its final chunk.
+    pub fn finish(&self) -> Result<(), StreamError> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(StreamError::Truncated)
+        }
+    }
+}
+
+fn stream_cipher(shared_secret: &[u8], salt: &Key) -> ChaCha20Poly1305 {
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(salt), shared_secret)
+        .expand(b"layer8 body stream", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(&key)
+}
+
+/// `0 (3 bytes) || position (u64, big endian) || last (1 byte)`
+fn frame_nonce(position: u64, last: bool) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[3..11].copy_from_slice(&position.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn open(cipher: &ChaCha20Poly1305, position: u64, last: bool, sealed: &[u8]) -> Result<Vec<u8>, StreamError> {
+    cipher
+        .decrypt(&frame_nonce(position, last), sealed)
+        .map_err(|_| StreamError::Forged)
+}
+
+fn frame(parts: &[&[u8]]) -> Vec<u8> {
+    let length: usize = parts.iter().map(|part| part.len()).sum();
+
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_LEN + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    for part in parts {
+        frame.extend_from_slice(part);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"this is a 32-byte shared secret!";
+
+    /// The header frame, then a frame per chunk, the last one ending the stream.
+    fn encrypt(header: &[u8], chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let (mut encryptor, header_frame) = StreamEncryptor::new(SECRET, header);
+        let mut frames = vec![header_frame];
+        for (i, chunk) in chunks.iter().enumerate() {
+            frames.push(encryptor.seal_chunk(chunk, i == chunks.len() - 1));
+        }
+        frames
+    }
+
+    fn decrypt(secret: &[u8], frames: &[Vec<u8>]) -> Result<Vec<Frame>, StreamError> {
+        let received = frames.concat();
+        let mut input: &[u8] = &received;
+        let mut decryptor = StreamDecryptor::new(secret);
+
+        let mut opened = Vec::new();
+        while let Some(frame) = decryptor.open_frame(&mut input)? {
+            opened.push(frame);
+        }
+        assert!(input.is_empty(), "a partial frame was left");
+        decryptor.finish()?;
+        Ok(opened)
+    }
+
+    #[test]
+    fn opens_frames_in_order() {
+        let frames = encrypt(b"200", &[b"hello ", b"world", b""]);
+
+        assert_eq!(
+            decrypt(SECRET, &frames),
+            Ok(vec![
+                Frame::Header(b"200".to_vec()),
+                Frame::Chunk { data: b"hello ".to_vec(), last: false },
+                Frame::Chunk { data: b"world".to_vec(), last: false },
+                Frame::Chunk { data: Vec::new(), last: true },
+            ])
+        );
+    }
+
+    #[test]
+    fn waits_for_a_whole_frame() {
+        let frames = encrypt(b"200", &[b"hello"]);
+        let mut decryptor = StreamDecryptor::new(SECRET);
+
+        let mut input: &[u8] = &frames[0][..LENGTH_PREFIX_LEN - 1];
+        assert_eq!(decryptor.open_frame(&mut input), Ok(None));
+
+        let mut input: &[u8] = &frames[0][..frames[0].len() - 1];
+        assert_eq!(decryptor.open_frame(&mut input), Ok(None));
+        assert_eq!(input.len(), frames[0].len() - 1);
+
+        let mut input: &[u8] = &frames[0];
+        assert_eq!(decryptor.open_frame(&mut input), Ok(Some(Frame::Header(b"200".to_vec()))));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn rejects_a_stream_without_its_last_chunk() {
+        let frames = encrypt(b"200", &[b"hello", b"world"]);
+
+        assert_eq!(decrypt(SECRET, &frames[..2]), Err(StreamError::Truncated));
+        assert_eq!(decrypt(SECRET, &frames[..1]), Err(StreamError::Truncated));
+    }
+
+    #[test]
+    fn rejects_reordered_or_dropped_chunks() {
+        let frames = encrypt(b"200", &[b"one", b"two", b"three"]);
+
+        let reordered = vec![frames[0].clone(), frames[2].clone(), frames[1].clone(), frames[3].clone()];
+        assert_eq!(decrypt(SECRET, &reordered), Err(StreamError::Forged));
+
+        let dropped = vec![frames[0].clone(), frames[2].clone(), frames[3].clone()];
+        assert_eq!(decrypt(SECRET, &dropped), Err(StreamError::Forged));
+    }
+
+    #[test]
+    fn rejects_chunks_of_another_stream() {
+        let frames = encrypt(b"200", &[b"hello", b"world"]);
+        let other = encrypt(b"200", &[b"hello", b"world"]);
+
+        let spliced = vec![frames[0].clone(), other[1].clone(), frames[2].clone()];
+        assert_eq!(decrypt(SECRET, &spliced), Err(StreamError::Forged));
+    }
+
+    #[test]
+    fn rejects_tampered_frames() {
+        let frames = encrypt(b"200", &[b"hello", b"world"]);
+
+        for (i, sent) in frames.iter().enumerate() {
+            let mut tampered = frames.clone();
+            let end = tampered[i].len() - 1;
+            tampered[i][end] ^= 1;
+            assert_eq!(decrypt(SECRET, &tampered), Err(StreamError::Forged), "frame {}", i);
+
+            // a shorter length prefix cuts the frame
+            let mut cut = frames.clone();
+            cut[i] = frame(&[&sent[LENGTH_PREFIX_LEN..sent.len() - 1]]);
+            assert!(decrypt(SECRET, &cut).is_err(), "frame {}", i);
+        }
+    }
+
+    #[test]
+    fn rejects_another_key() {
+        let frames = encrypt(b"200", &[b"hello"]);
+
+        assert_eq!(decrypt(b"this is another 32-byte secret!!", &frames), Err(StreamError::Forged));
+    }
+
+    #[test]
+    fn rejects_frames_after_the_last_chunk() {
+        let mut frames = encrypt(b"200", &[b"hello"]);
+        frames.push(frames[1].clone());
+
+        assert_eq!(decrypt(SECRET, &frames), Err(StreamError::Malformed));
+    }
+
+    #[test]
+    fn rejects_oversized_and_short_frames() {
+        let oversized = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        assert_eq!(decrypt(SECRET, &[oversized]), Err(StreamError::Malformed));
+
+        let short_header = frame(&[&[0u8; SALT_LEN - 1]]);
+        assert_eq!(decrypt(SECRET, &[short_header]), Err(StreamError::Malformed));
+    }
+}